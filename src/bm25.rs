@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+
+use crate::utils::{ search_terms, BM25_B, BM25_K1 };
+
+
+// An inverted index over the memory texts for BM25 keyword scoring.
+// Embeddings are bad at exact names, ids and error codes, this finds
+// them by the words themselves. Texts are split with the same tokeniser
+// as the sentence splitter (see utils.rs)
+#[derive(Debug, Default)]
+pub struct Bm25Index {
+    // term -> memory key -> how often the term occurs in that memory
+    postings: HashMap<String, HashMap<String, u32>>,
+    // memory key -> its terms, so a memory can be removed again
+    terms: HashMap<String, Vec<String>>,
+    total_length: usize,
+}
+
+impl Bm25Index {
+    pub fn build<'a>(texts: impl Iterator<Item = (&'a String, &'a String)>) -> Self {
+        let mut index = Bm25Index::default();
+        for (key, text) in texts {
+            index.add(key, text);
+        }
+        index
+    }
+
+    pub fn len(&self) -> usize {
+        self.terms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    pub fn add(&mut self, key: &str, text: &str) {
+        self.remove(key);
+
+        let terms = search_terms(text);
+        self.total_length += terms.len();
+
+        for term in &terms {
+            *self.postings.entry(term.clone()).or_default().entry(key.to_string()).or_insert(0) += 1;
+        }
+        self.terms.insert(key.to_string(), terms);
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        let Some(terms) = self.terms.remove(key) else {
+            return;
+        };
+        self.total_length -= terms.len();
+
+        for term in terms {
+            if let Some(docs) = self.postings.get_mut(&term) {
+                docs.remove(key);
+                if docs.is_empty() {
+                    self.postings.remove(&term);
+                }
+            }
+        }
+    }
+
+    fn average_length(&self) -> f32 {
+        self.total_length as f32 / self.len().max(1) as f32
+    }
+
+    // Rare terms weigh more than common ones. This is the variant that
+    // never goes negative, even for terms found in most memories
+    fn idf(&self, term: &str) -> f32 {
+        let n = self.len() as f32;
+        let with_term = self.postings.get(term).map_or(0, |docs| docs.len()) as f32;
+        ((n - with_term + 0.5) / (with_term + 0.5) + 1.0).ln()
+    }
+
+    fn term_score(&self, term: &str, key: &str, length: usize) -> f32 {
+        let Some(frequency) = self.postings.get(term).and_then(|docs| docs.get(key)) else {
+            return 0.0;
+        };
+
+        let tf = *frequency as f32;
+        let norm = 1.0 - BM25_B + BM25_B * length as f32 / self.average_length().max(1.0);
+        self.idf(term) * tf * (BM25_K1 + 1.0) / (tf + BM25_K1 * norm)
+    }
+
+    // The BM25 score of one memory for the query terms, 0 if it has none of them
+    pub fn score(&self, terms: &[String], key: &str) -> f32 {
+        let Some(length) = self.terms.get(key).map(|t| t.len()) else {
+            return 0.0;
+        };
+        terms.iter().map(|term| self.term_score(term, key, length)).sum()
+    }
+
+    // The n best (key, score) pairs for the query, best first.
+    // Only memories containing at least one query term are scored
+    pub fn search(&self, query: &str, n: usize, filter: impl Fn(&str) -> bool) -> Vec<(String, f32)> {
+        let terms = search_terms(query);
+
+        let mut scores: HashMap<&str, f32> = HashMap::new();
+        for term in &terms {
+            let Some(docs) = self.postings.get(term) else {
+                continue;
+            };
+            for key in docs.keys() {
+                if !scores.contains_key(key.as_str()) && filter(key) {
+                    scores.insert(key, self.score(&terms, key));
+                }
+            }
+        }
+
+        let mut results: Vec<(String, f32)> = scores.into_iter()
+            .map(|(key, score)| (key.to_string(), score))
+            .collect();
+        results.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        results.truncate(n);
+        results
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(texts: &[(&str, &str)]) -> Bm25Index {
+        let owned: Vec<(String, String)> = texts.iter().map(|(k, t)| (k.to_string(), t.to_string())).collect();
+        Bm25Index::build(owned.iter().map(|(k, t)| (k, t)))
+    }
+
+    fn terms(query: &str) -> Vec<String> {
+        search_terms(query)
+    }
+
+    #[test]
+    fn score_follows_the_bm25_formula() {
+        let index = index(&[
+            ("a", "error ERR-1042 in the parser"),
+            ("b", "the parser works"),
+            ("c", "nothing to see"),
+        ]);
+
+        // "err-1042" is in one of three memories, "a" has five terms
+        // against an average of 11 / 3
+        let idf = ((3.0f32 - 1.0 + 0.5) / (1.0 + 0.5) + 1.0).ln();
+        let norm = 1.0 - BM25_B + BM25_B * 5.0 / (11.0 / 3.0);
+        let expected = idf * (BM25_K1 + 1.0) / (1.0 + BM25_K1 * norm);
+
+        assert!((index.score(&terms("err-1042"), "a") - expected).abs() < 1e-5);
+        assert_eq!(index.score(&terms("err-1042"), "b"), 0.0);
+        assert_eq!(index.score(&terms("err-1042"), "missing"), 0.0);
+    }
+
+    #[test]
+    fn rare_terms_weigh_more() {
+        let index = index(&[
+            ("a", "the cat"),
+            ("b", "the dog"),
+            ("c", "the bird"),
+        ]);
+
+        assert!(index.idf("cat") > index.idf("the"));
+        assert!(index.idf("the") > 0.0);
+    }
+
+    #[test]
+    fn search_ranks_and_filters() {
+        let index = index(&[
+            ("a", "rust rust rust"),
+            ("b", "rust and python"),
+            ("c", "python only"),
+        ]);
+
+        let results = index.search("Rust!", 10, |_| true);
+        assert_eq!(results.iter().map(|(k, _)| k.as_str()).collect::<Vec<&str>>(), vec!["a", "b"]);
+        assert!(results[0].1 > results[1].1);
+
+        let filtered = index.search("rust", 10, |key| key != "a");
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(filtered[0].0, "b");
+
+        assert_eq!(index.search("rust python", 1, |_| true).len(), 1);
+    }
+
+    #[test]
+    fn add_again_and_remove_keep_the_counts_right() {
+        let mut index = index(&[("a", "one two"), ("b", "two three")]);
+
+        index.add("a", "four");
+        assert_eq!(index.len(), 2);
+        assert!(index.search("one", 10, |_| true).is_empty());
+        assert_eq!(index.total_length, 3);
+
+        index.remove("b");
+        index.remove("b");
+        assert_eq!(index.len(), 1);
+        assert!(index.search("three", 10, |_| true).is_empty());
+        assert!(!index.postings.contains_key("two"));
+    }
+
+    #[test]
+    fn empty_index_and_query_find_nothing() {
+        let empty = Bm25Index::default();
+        assert!(empty.is_empty());
+        assert!(empty.search("anything", 10, |_| true).is_empty());
+
+        let index = index(&[("a", "some text")]);
+        assert!(index.search("", 10, |_| true).is_empty());
+        assert!(index.search("   ...  ", 10, |_| true).is_empty());
+    }
+}
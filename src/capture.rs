@@ -0,0 +1,106 @@
+use log::*;
+use openai_api_rust::Role;
+
+use crate::custom_types::MemoryRole;
+use crate::utils::load_environment;
+
+
+// Which roles of the conversation end up in memory. By default the user
+// and the assistant are remembered, so the bot can recall what it told
+// the user before. The system prompt and tool results are opt-in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CapturePolicy {
+    pub user: bool,
+    pub assistant: bool,
+    pub system: bool,
+    pub tool: bool,
+}
+
+impl Default for CapturePolicy {
+    fn default() -> Self {
+        CapturePolicy {
+            user: true,
+            assistant: true,
+            system: false,
+            tool: false,
+        }
+    }
+}
+
+impl MemoryRole {
+    pub fn parse(input: &str) -> Option<Self> {
+        match input.trim().to_lowercase().as_str() {
+            "user" => Some(MemoryRole::User),
+            "assistant" | "bot" => Some(MemoryRole::Assistant),
+            "system" => Some(MemoryRole::System),
+            "tool" => Some(MemoryRole::Tool),
+            _ => None
+        }
+    }
+
+    // How a memory of this role is introduced in the prompt, so the model
+    // can tell what the user said from what it said itself
+    pub fn prompt_prefix(&self) -> &'static str {
+        match self {
+            MemoryRole::User => "",
+            MemoryRole::Assistant => "You told them: ",
+            MemoryRole::System => "You were instructed: ",
+            MemoryRole::Tool => "A tool returned: ",
+        }
+    }
+}
+
+impl From<&Role> for MemoryRole {
+    fn from(role: &Role) -> Self {
+        match role {
+            Role::System => MemoryRole::System,
+            Role::Assistant => MemoryRole::Assistant,
+            Role::User => MemoryRole::User,
+        }
+    }
+}
+
+impl CapturePolicy {
+    // A comma separated list of roles, like "user,assistant,tool".
+    // "all" captures everything and "none" nothing at all
+    pub fn parse(input: &str) -> Option<Self> {
+        let mut policy = CapturePolicy { user: false, assistant: false, system: false, tool: false };
+
+        for name in input.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            match name.to_lowercase().as_str() {
+                "all" => policy = CapturePolicy { user: true, assistant: true, system: true, tool: true },
+                "none" => (),
+                other => match MemoryRole::parse(other)? {
+                    MemoryRole::User => policy.user = true,
+                    MemoryRole::Assistant => policy.assistant = true,
+                    MemoryRole::System => policy.system = true,
+                    MemoryRole::Tool => policy.tool = true,
+                }
+            }
+        }
+
+        Some(policy)
+    }
+
+    // Reads MEMORY_CAPTURE, without it the default roles are captured
+    pub fn from_env() -> Self {
+        let value = load_environment("MEMORY_CAPTURE");
+        if value.trim().is_empty() {
+            return CapturePolicy::default();
+        }
+
+        CapturePolicy::parse(&value).unwrap_or_else(|| {
+            warn!("Unknown role in MEMORY_CAPTURE '{}', capturing the default roles", value);
+            CapturePolicy::default()
+        })
+    }
+
+    pub fn captures(&self, role: MemoryRole) -> bool {
+        match role {
+            MemoryRole::User => self.user,
+            MemoryRole::Assistant => self.assistant,
+            MemoryRole::System => self.system,
+            MemoryRole::Tool => self.tool,
+        }
+    }
+}
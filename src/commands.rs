@@ -0,0 +1,205 @@
+use std::path::{Path, PathBuf};
+
+use log::*;
+
+use crate::consolidate::{ consolidate, ConsolidationOptions };
+use crate::custom_types::MemoryStore;
+use crate::export::{ export_csv, export_jsonl, export_npy, import_jsonl, import_npy };
+use crate::search::SearchOptions;
+use crate::snapshot::{ create_snapshot, list_snapshots, restore_snapshot };
+use crate::store::search_memories;
+use crate::utils::{ sanitize_id, EXPORT_DIR, PQ_CODEBOOK_SIZE, PQ_SUB_VECTORS };
+use crate::vectors::{ Precision, Storage };
+
+
+// Handles the REPL commands that work on the memories of the current
+// user. Returns false if the command is not one of them, so the caller
+// can try its own commands or report it as unknown
+pub fn handle_store_command(line: &str, user_id: &str, store: &mut MemoryStore) -> bool {
+    let (command, args) = line.trim().split_once(' ').unwrap_or((line.trim(), ""));
+    let args = args.trim();
+
+    match command {
+        "/snapshot" => {
+            let label = if args.is_empty() { None } else { Some(args) };
+            match create_snapshot(store, user_id, label) {
+                Ok(manifest) => println!("Created snapshot '{}' with {} memories", manifest.name, manifest.memory_count),
+                Err(e) => error!("Failed to create snapshot: {e}")
+            }
+        }
+        "/snapshots" => {
+            let snapshots = list_snapshots(user_id);
+            if snapshots.is_empty() {
+                println!("No snapshots for user '{}'", user_id);
+            }
+            for manifest in snapshots {
+                println!("{}\tcreated at {}\t{} memories", manifest.name, manifest.created_at, manifest.memory_count);
+            }
+        }
+        "/restore" => {
+            if args.is_empty() {
+                println!("Usage: /restore <snapshot name>");
+                return true;
+            }
+            match restore_snapshot(store, user_id, args) {
+                Ok(manifest) => println!("Restored snapshot '{}' with {} memories", manifest.name, manifest.memory_count),
+                Err(e) => error!("Failed to restore snapshot '{}': {e}", args)
+            }
+        }
+        "/export" => {
+            let (format, path) = args.split_once(' ').unwrap_or((args, ""));
+            let path: PathBuf = if path.trim().is_empty() {
+                Path::new(EXPORT_DIR).join(format!("{}.{}", sanitize_id(user_id), format))
+            } else {
+                PathBuf::from(path.trim())
+            };
+
+            let user = store.user_mut(user_id);
+            let result = match format {
+                "jsonl" => export_jsonl(user, &path),
+                "csv" => export_csv(user, &path),
+                "npy" => export_npy(user, &path),
+                _ => {
+                    println!("Usage: /export <jsonl|csv|npy> [path]");
+                    return true;
+                }
+            };
+
+            match result {
+                Ok(n) => println!("Exported {} memories to {}", n, path.display()),
+                Err(e) => error!("Failed to export to {}: {e}", path.display())
+            }
+        }
+        "/import" => {
+            let parts: Vec<&str> = args.split_whitespace().collect();
+            let result = match parts.as_slice() {
+                ["jsonl", path] => import_jsonl(store, user_id, Path::new(path)),
+                ["npy", npy_path, csv_path] => import_npy(store, user_id, Path::new(npy_path), Path::new(csv_path)),
+                _ => {
+                    println!("Usage: /import jsonl <path> or /import npy <npy path> <csv path>");
+                    return true;
+                }
+            };
+
+            match result {
+                Ok(n) => println!("Imported {} memories", n),
+                Err(e) => error!("Failed to import: {e}")
+            }
+        }
+        "/precision" => {
+            let (name, option) = args.split_once(' ').unwrap_or((args, ""));
+            let Some(precision) = Precision::parse(name) else {
+                println!("Usage: /precision <f32|f16|int8> [rescore]");
+                return true;
+            };
+
+            match store.set_precision(user_id, precision, option.trim() == "rescore") {
+                Ok(()) => println!("Memories of '{}' are now stored as {:?}", user_id, precision),
+                Err(e) => error!("Failed to save memories after switching precision: {e}")
+            }
+        }
+        "/search" => {
+            let (options, query) = SearchOptions::parse_args(args);
+            if query.is_empty() {
+                println!("Usage: /search [k=<n>] [vector=<weight>] [keyword=<weight>] [recency=<weight>] [half_life=<hours>] [importance=<weight>] [rrf|sum] [mmr[=<lambda>]] [rerank[=chat|endpoint]] [rerank_n=<n>] [min=<score>] [vectors] <query>");
+                return true;
+            }
+
+            store.user_mut(user_id);
+            let results = search_memories(store, user_id, &query, &options).unwrap_or_default();
+            if results.is_empty() {
+                println!("No memories found");
+            }
+            for result in results {
+                match result.rerank_score {
+                    Some(rerank_score) => println!("{:.4} (reranked from {:.4})\t{}\t{:?}\t{}", rerank_score, result.score, result.id, result.metadata.role, result.text),
+                    None => println!("{:.4}\t{}\t{:?}\t{}", result.score, result.id, result.metadata.role, result.text)
+                }
+                if let Some(vector) = &result.vector {
+                    println!("\t{} dimensions: {:?}", vector.len(), &vector[..vector.len().min(8)]);
+                }
+            }
+        }
+        "/consolidate" => {
+            let Some(options) = ConsolidationOptions::parse_args(args) else {
+                println!("Usage: /consolidate [age=<hours>] [similarity=<0..1>] [min=<n>] [max=<n>] [archive]");
+                return true;
+            };
+
+            let summaries = consolidate(store, user_id, &options);
+            if summaries.is_empty() {
+                println!("Nothing to consolidate");
+            }
+            let Some(user) = store.user(user_id) else {
+                return true;
+            };
+            for key in summaries {
+                let sources = user.metadata.get(&key).map_or(0, |meta| meta.sources.len());
+                println!("{}\tfrom {} memories\t{}", key, sources, user.memories_text.get(&key).cloned().unwrap_or_default());
+            }
+        }
+        "/storage" => {
+            let Some(storage) = Storage::parse(args) else {
+                println!("Usage: /storage <memory|mmap>");
+                return true;
+            };
+
+            match store.set_storage(user_id, storage) {
+                Ok(()) => println!("Vectors of '{}' are now kept in {:?} storage", user_id, storage),
+                Err(e) => error!("Failed to save memories after switching storage: {e}")
+            }
+        }
+        "/index" => {
+            let parts: Vec<&str> = args.split_whitespace().collect();
+            let numbers: Vec<Option<usize>> = parts.iter().skip(1).map(|p| p.parse::<usize>().ok()).collect();
+            let number = |i: usize| numbers.get(i).copied().flatten();
+            if number(0) == Some(0) {
+                println!("The number of lists must be at least 1");
+                return true;
+            }
+
+            let memories = &mut store.user_mut(user_id).memories;
+            let result = match parts.first() {
+                Some(&"ivfpq") => memories.build_pq_index(
+                    number(0),
+                    number(1).unwrap_or(PQ_SUB_VECTORS),
+                    number(2).unwrap_or(PQ_CODEBOOK_SIZE),
+                ),
+                Some(&"ivf") => memories.build_ivf_index(number(0)),
+                Some(&"off") => {
+                    memories.drop_index();
+                    Ok(())
+                }
+                _ => {
+                    println!("Usage: /index ivf [nlist], /index ivfpq [nlist] [sub-vectors] [codebook size] or /index off");
+                    return true;
+                }
+            };
+
+            match result {
+                Ok(()) => {
+                    println!("Index updated");
+                    if let Err(e) = store.checkpoint(user_id) {
+                        error!("Failed to save memories after updating the index: {e}");
+                    }
+                }
+                Err(e) => println!("Could not build index: {e}")
+            }
+        }
+        "/nprobe" => {
+            let Ok(nprobe) = args.parse::<usize>() else {
+                println!("Usage: /nprobe <number of lists>");
+                return true;
+            };
+
+            if store.user_mut(user_id).memories.set_nprobe(nprobe) {
+                println!("Searching {} lists per query", nprobe);
+            } else {
+                println!("There is no index to tune, build one with /index");
+            }
+        }
+        _ => return false
+    }
+
+    true
+}
@@ -0,0 +1,275 @@
+use std::sync::{ Arc, Mutex };
+use std::thread::{ self, JoinHandle };
+use std::time::Duration;
+
+use log::*;
+
+use crate::custom_types::{ MemoryMetadata, MemoryRole, MemoryStore, UserMemories };
+use crate::memory::cosine_similarity_slices;
+use crate::store::embed_text;
+use crate::summary::generate_summary;
+use crate::utils::{
+    load_environment, now_unix, CONSOLIDATE_MAX_CLUSTER, CONSOLIDATE_MIN_AGE_HOURS, CONSOLIDATE_MIN_CLUSTER,
+    CONSOLIDATE_SIMILARITY
+};
+
+
+// How old memories are folded into summaries.
+// Only memories older than min_age_hours are looked at, a group needs
+// at least min_cluster memories that are all at least similarity close
+// to its centre, and at most max_cluster go into one summary.
+// With archive the originals are moved out into the archive file,
+// otherwise they stay stored and point to their summary. Search leaves
+// them out for as long as the summary is there
+#[derive(Clone, Debug)]
+pub struct ConsolidationOptions {
+    pub min_age_hours: f32,
+    pub similarity: f32,
+    pub min_cluster: usize,
+    pub max_cluster: usize,
+    pub archive: bool,
+}
+
+// A group of similar memories that get summarised together
+#[derive(Clone, Debug)]
+pub struct Cluster {
+    pub keys: Vec<String>,
+    pub texts: Vec<String>,
+}
+
+impl Default for ConsolidationOptions {
+    fn default() -> Self {
+        ConsolidationOptions {
+            min_age_hours: CONSOLIDATE_MIN_AGE_HOURS,
+            similarity: CONSOLIDATE_SIMILARITY,
+            min_cluster: CONSOLIDATE_MIN_CLUSTER,
+            max_cluster: CONSOLIDATE_MAX_CLUSTER,
+            archive: false,
+        }
+    }
+}
+
+impl ConsolidationOptions {
+    // The options of the background job, CONSOLIDATE_MIN_AGE_HOURS
+    // replaces the default age and CONSOLIDATE_ARCHIVE turns on archiving
+    pub fn from_env() -> Self {
+        let mut options = ConsolidationOptions::default();
+
+        let age = load_environment("CONSOLIDATE_MIN_AGE_HOURS");
+        if !age.trim().is_empty() {
+            match age.trim().parse::<f32>() {
+                Ok(hours) => options.min_age_hours = hours.max(0.0),
+                Err(_) => warn!("CONSOLIDATE_MIN_AGE_HOURS '{}' is not a number, using {}", age, options.min_age_hours)
+            }
+        }
+
+        options.archive = matches!(load_environment("CONSOLIDATE_ARCHIVE").trim().to_lowercase().as_str(), "1" | "true" | "yes");
+        options
+    }
+
+    // Reads the arguments of "/consolidate", like "age=0 similarity=0.8 min=2 archive".
+    // Returns None if one of them is not understood
+    pub fn parse_args(args: &str) -> Option<Self> {
+        let mut options = ConsolidationOptions::default();
+
+        for word in args.split_whitespace() {
+            let parsed = match word.split_once('=') {
+                Some(("age", value)) => value.parse().map(|h: f32| options.min_age_hours = h.max(0.0)).is_ok(),
+                Some(("similarity", value)) => value.parse().map(|s: f32| options.similarity = s.clamp(-1.0, 1.0)).is_ok(),
+                Some(("min", value)) => value.parse().map(|n: usize| options.min_cluster = n.max(2)).is_ok(),
+                Some(("max", value)) => value.parse().map(|n: usize| options.max_cluster = n.max(2)).is_ok(),
+                _ => match word {
+                    "archive" => { options.archive = true; true }
+                    _ => false
+                }
+            };
+
+            if !parsed {
+                return None;
+            }
+        }
+
+        Some(options)
+    }
+}
+
+// Groups the old memories of a user by their embeddings. Memories are
+// taken oldest first and each joins the group whose centre it is closest
+// to, if that is close enough and the group is not full, or starts a new
+// one. Only memories of the same role are grouped together, so what the
+// user said and what the bot said are summarised apart. Summaries and
+// memories already summarised are left out, and groups smaller than
+// min_cluster are not worth a summary
+pub fn plan_clusters(user: &UserMemories, options: &ConsolidationOptions, now: u64) -> Vec<Cluster> {
+    let min_age_secs = (options.min_age_hours * 3600.0) as u64;
+
+    let mut eligible: Vec<(&String, u64, MemoryRole)> = user.memories.keys()
+        .filter_map(|key| {
+            let meta = user.metadata.get(key)?;
+            let old_enough = now.saturating_sub(meta.created_at) >= min_age_secs;
+            (old_enough && meta.sources.is_empty() && meta.summarized_in.is_none()).then_some((key, meta.created_at, meta.role))
+        })
+        .collect();
+    eligible.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(b.0)));
+
+    // The centre of a group is the sum of its vectors, which points the
+    // same way as their mean and is all cosine similarity cares about
+    let mut groups: Vec<(MemoryRole, Vec<f32>, Vec<&String>)> = vec![];
+
+    for (key, _, role) in eligible {
+        let Some(vector) = user.memories.get(key) else {
+            continue;
+        };
+
+        let closest = groups.iter()
+            .enumerate()
+            .filter(|(_, (group_role, _, members))| *group_role == role && members.len() < options.max_cluster)
+            .map(|(i, (_, centre, _))| (i, cosine_similarity_slices(centre, &vector)))
+            .filter(|(_, similarity)| *similarity >= options.similarity)
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+
+        match closest {
+            Some((i, _)) => {
+                let (_, centre, members) = &mut groups[i];
+                for (c, v) in centre.iter_mut().zip(&vector) {
+                    *c += v;
+                }
+                members.push(key);
+            }
+            None => groups.push((role, vector, vec![key]))
+        }
+    }
+
+    groups.into_iter()
+        .filter(|(_, _, members)| members.len() >= options.min_cluster)
+        .map(|(_, _, members)| Cluster {
+            texts: members.iter().map(|key| user.memories_text.get(*key).cloned().unwrap_or_default()).collect(),
+            keys: members.into_iter().cloned().collect(),
+        })
+        .collect()
+}
+
+// The summary of a cluster and its embedding. This talks to the chat and
+// embedding models, so it is done without holding on to the store
+fn summarise(cluster: &Cluster) -> Option<(String, Vec<f32>)> {
+    let summary = generate_summary(&cluster.texts)?;
+    let Some(vector) = embed_text(&summary) else {
+        error!("Failed to embed the summary of {} memories", cluster.keys.len());
+        return None;
+    };
+    Some((summary, vector))
+}
+
+// Stores the summary as a new memory that lists its sources, then either
+// archives the sources or marks them as summarised. The summary is as
+// recent as its newest source and as important as its most important one,
+// and it keeps the hits of all of them.
+// If any source changed since the cluster was planned, nothing is stored
+fn apply_summary(store: &mut MemoryStore, user_id: &str, cluster: &Cluster, summary: &str, vector: Vec<f32>, options: &ConsolidationOptions) -> Option<String> {
+    let user = store.user_mut(user_id);
+    let sources: Vec<MemoryMetadata> = cluster.keys.iter()
+        .filter_map(|key| user.metadata.get(key).cloned())
+        .filter(|meta| meta.summarized_in.is_none())
+        .collect();
+    if sources.len() != cluster.keys.len() {
+        warn!("Memories of user '{}' changed during consolidation, skipping a cluster of {}", user_id, cluster.keys.len());
+        return None;
+    }
+
+    let metadata = MemoryMetadata {
+        user_id: user_id.to_string(),
+        session_id: "consolidation".to_string(),
+        created_at: sources.iter().map(|meta| meta.created_at).max().unwrap_or_else(now_unix),
+        importance: sources.iter().filter_map(|meta| meta.importance).reduce(f32::max),
+        sources: cluster.keys.clone(),
+        summarized_in: None,
+        hits: sources.iter().map(|meta| meta.hits).sum(),
+        last_seen: sources.iter().map(|meta| meta.last_seen).max().unwrap_or(0),
+        role: sources.first().map(|meta| meta.role).unwrap_or_default(),
+        ..Default::default()
+    };
+
+    let summary_key = store.insert_memory(user_id, summary, vector, metadata)?;
+
+    for (key, mut meta) in cluster.keys.iter().zip(sources) {
+        if options.archive {
+            match store.archive_memory(user_id, key) {
+                Ok(true) => (),
+                Ok(false) => warn!("Memory '{}' of user '{}' was gone before it could be archived", key, user_id),
+                Err(e) => error!("Failed to archive memory '{}' of user '{}': {e}", key, user_id)
+            }
+        } else {
+            meta.summarized_in = Some(summary_key.clone());
+            store.update_metadata(user_id, key, meta);
+        }
+    }
+
+    info!("Consolidated {} memories of user '{}' into '{}'", cluster.keys.len(), user_id, summary_key);
+    Some(summary_key)
+}
+
+// Consolidates the memories of one user, returns the keys of the new
+// summaries. The store is held the whole time, see consolidate_shared
+// for the version that lets go of it while the models are working
+pub fn consolidate(store: &mut MemoryStore, user_id: &str, options: &ConsolidationOptions) -> Vec<String> {
+    let clusters = plan_clusters(store.user_mut(user_id), options, now_unix());
+
+    clusters.iter()
+        .filter_map(|cluster| {
+            let (summary, vector) = summarise(cluster)?;
+            apply_summary(store, user_id, cluster, &summary, vector, options)
+        })
+        .collect()
+}
+
+// Same as consolidate, but only locks the store to plan the clusters and
+// to store each summary, so the chat keeps working in the meantime
+pub fn consolidate_shared(store: &Mutex<MemoryStore>, user_id: &str, options: &ConsolidationOptions) -> Vec<String> {
+    let clusters = {
+        let mut locked_store = store.lock().unwrap_or_else(|e| e.into_inner());
+        plan_clusters(locked_store.user_mut(user_id), options, now_unix())
+    };
+
+    clusters.iter()
+        .filter_map(|cluster| {
+            let (summary, vector) = summarise(cluster)?;
+            let mut locked_store = store.lock().unwrap_or_else(|e| e.into_inner());
+            apply_summary(&mut locked_store, user_id, cluster, &summary, vector, options)
+        })
+        .collect()
+}
+
+// Starts the background job that consolidates the memories of every
+// loaded user every CONSOLIDATE_EVERY_MINUTES. Without that setting
+// there is no background job, "/consolidate" still works
+pub fn spawn_consolidation(store: Arc<Mutex<MemoryStore>>) -> Option<JoinHandle<()>> {
+    let value = load_environment("CONSOLIDATE_EVERY_MINUTES");
+    if value.trim().is_empty() {
+        return None;
+    }
+
+    let minutes = match value.trim().parse::<u64>() {
+        Ok(0) => return None,
+        Ok(minutes) => minutes,
+        Err(_) => {
+            warn!("CONSOLIDATE_EVERY_MINUTES '{}' is not a number, not consolidating in the background", value);
+            return None;
+        }
+    };
+    let options = ConsolidationOptions::from_env();
+    info!("Consolidating memories every {} minutes", minutes);
+
+    let handle = thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(minutes * 60));
+
+        let user_ids: Vec<String> = store.lock().unwrap_or_else(|e| e.into_inner()).users.keys().cloned().collect();
+        for user_id in user_ids {
+            let summaries = consolidate_shared(&store, &user_id, &options);
+            if !summaries.is_empty() {
+                info!("Background consolidation added {} summaries for user '{}'", summaries.len(), user_id);
+            }
+        }
+    });
+
+    Some(handle)
+}
@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{ Error, ErrorKind };
+use std::path::{ Path, PathBuf };
+
+use log::*;
+use openai_api_rust::Role;
+
+use crate::custom_types::ChatSession;
+use crate::summary::generate_summary;
+use crate::transcript::export_transcript;
+use crate::utils::{ format_unix, now_unix, sanitize_id, CONVERSATION_DIR, EXPORT_DIR };
+use crate::wal::write_atomic;
+
+static TITLE_LENGTH: usize = 60;
+
+
+fn conversation_dir(user_id: &str) -> PathBuf {
+    Path::new(CONVERSATION_DIR).join(sanitize_id(user_id))
+}
+
+fn conversation_path(user_id: &str, id: &str) -> PathBuf {
+    conversation_dir(user_id).join(format!("{}.json", sanitize_id(id)))
+}
+
+impl ChatSession {
+    // Whether anything was said yet, a session holding only the
+    // system prompt is not worth saving
+    pub fn has_turns(&self) -> bool {
+        self.messages.iter().any(|m| !matches!(m.role, Role::System))
+    }
+
+    // The title set with "/title", or the start of the first user message
+    pub fn display_title(&self) -> String {
+        if let Some(title) = &self.title {
+            return title.clone();
+        }
+
+        let first = self.messages.iter()
+            .find(|m| matches!(m.role, Role::User))
+            .map(|m| m.content.split_whitespace().collect::<Vec<&str>>().join(" "))
+            .unwrap_or_default();
+
+        if first.chars().count() > TITLE_LENGTH {
+            format!("{}...", first.chars().take(TITLE_LENGTH).collect::<String>())
+        } else if first.is_empty() {
+            "(empty)".to_string()
+        } else {
+            first
+        }
+    }
+}
+
+// Writes a session to its conversation file. Saving a conversation
+// also takes it out of the archive, it is in use again
+pub fn save_conversation(session: &mut ChatSession) -> std::io::Result<()> {
+    if !session.has_turns() {
+        return Ok(());
+    }
+
+    session.updated_at = now_unix();
+    session.archived = false;
+
+    fs::create_dir_all(conversation_dir(&session.user_id))?;
+    let contents = serde_json::to_vec(session)?;
+    write_atomic(&conversation_path(&session.user_id, &session.session_id), &contents)
+}
+
+pub fn load_conversation(user_id: &str, id: &str) -> std::io::Result<ChatSession> {
+    let contents = fs::read_to_string(conversation_path(user_id, id))?;
+    let session: ChatSession = serde_json::from_str(&contents)?;
+
+    // The file is picked by user id, but the user id inside is what the
+    // session would remember things under, so they have to agree
+    if session.user_id != user_id {
+        return Err(Error::new(ErrorKind::InvalidData, format!("conversation belongs to '{}'", session.user_id)));
+    }
+    Ok(session)
+}
+
+// The saved conversations of a user, most recently used first.
+// Archived ones are only listed if asked for
+pub fn list_conversations(user_id: &str, include_archived: bool) -> Vec<ChatSession> {
+    let Ok(entries) = fs::read_dir(conversation_dir(user_id)) else {
+        return vec![];
+    };
+
+    let mut result: Vec<ChatSession> = vec![];
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+
+        match fs::read_to_string(&path).map(|c| serde_json::from_str::<ChatSession>(&c)) {
+            Ok(Ok(session)) if include_archived || !session.archived => result.push(session),
+            Ok(Ok(_)) => (),
+            Ok(Err(e)) => warn!("Skipping unreadable conversation {}: {e}", path.display()),
+            Err(e) => warn!("Skipping unreadable conversation {}: {e}", path.display())
+        }
+    }
+
+    result.sort_by(|a, b| b.updated_at.cmp(&a.updated_at).then_with(|| b.created_at.cmp(&a.created_at)).then_with(|| b.session_id.cmp(&a.session_id)));
+    result
+}
+
+pub fn delete_conversation(user_id: &str, id: &str) -> std::io::Result<()> {
+    fs::remove_file(conversation_path(user_id, id))
+}
+
+// Hides a conversation from the list, it can still be resumed by id
+pub fn archive_conversation(user_id: &str, id: &str) -> std::io::Result<()> {
+    let mut session = load_conversation(user_id, id)?;
+    session.archived = true;
+
+    let contents = serde_json::to_vec(&session)?;
+    write_atomic(&conversation_path(user_id, id), &contents)
+}
+
+// Summarises the conversation with the chat model, unless it was
+// summarised before: the stored summary is kept, so resuming the same
+// conversation again doesn't ask the model every time.
+// Returns false if that failed
+pub fn summarise_conversation(session: &mut ChatSession) -> bool {
+    if session.summary.is_some() {
+        return true;
+    }
+
+    let lines: Vec<String> = session.messages.iter()
+        .filter_map(|m| match m.role {
+            Role::User => Some(format!("User: {}", m.content.trim())),
+            Role::Assistant => Some(format!("Assistant: {}", m.content.trim())),
+            Role::System => None
+        })
+        .collect();
+    if lines.is_empty() {
+        return false;
+    }
+
+    let Some(summary) = generate_summary(&lines) else {
+        return false;
+    };
+    session.summary = Some(summary);
+    session.summarized = session.messages.len();
+    true
+}
+
+// A conversation named by its id, or by its number in "/conversations".
+// Archived conversations keep their number, so the numbers are the same
+// with and without "all"
+fn resolve_id(user_id: &str, arg: &str) -> Option<String> {
+    match arg.parse::<usize>() {
+        Ok(n) if n >= 1 => list_conversations(user_id, true).get(n - 1).map(|s| s.session_id.clone()),
+        _ if !arg.is_empty() => Some(arg.to_string()),
+        _ => None
+    }
+}
+
+// Handles the REPL commands that work on the conversations of the current
+// user. Returns false if the command is not one of them
+pub fn handle_conversation_command(line: &str, user_id: &str, sessions: &mut HashMap<String, ChatSession>, system_prompt: &str) -> bool {
+    let (command, args) = line.trim().split_once(' ').unwrap_or((line.trim(), ""));
+    let args = args.trim();
+
+    match command {
+        "/conversations" => {
+            let show_archived = args == "all";
+            let conversations = list_conversations(user_id, true);
+            if !conversations.iter().any(|s| show_archived || !s.archived) {
+                println!("No saved conversations for user '{}'", user_id);
+            }
+            for (i, session) in conversations.iter().enumerate() {
+                if session.archived && !show_archived {
+                    continue;
+                }
+                let archived = if session.archived { "\t(archived)" } else { "" };
+                println!("{}\t{}\tupdated at {}\t{} messages\t{}{}", i + 1, session.session_id, format_unix(session.updated_at), session.messages.len(), session.display_title(), archived);
+            }
+        }
+        "/resume" => {
+            let Some(id) = resolve_id(user_id, args) else {
+                println!("Usage: /resume <conversation id or number>");
+                return true;
+            };
+
+            let mut session = match load_conversation(user_id, &id) {
+                Ok(session) => session,
+                Err(e) => {
+                    error!("Failed to load conversation '{}': {e}", id);
+                    return true;
+                }
+            };
+
+            if let Some(current) = sessions.get_mut(user_id)
+                && let Err(e) = save_conversation(current) {
+                error!("Failed to save the current conversation: {e}");
+            }
+
+            if !summarise_conversation(&mut session) {
+                warn!("Could not summarise conversation '{}'", id);
+            }
+            if let Err(e) = save_conversation(&mut session) {
+                error!("Failed to save conversation '{}': {e}", id);
+            }
+
+            println!("Resumed '{}' with {} messages", session.display_title(), session.messages.len());
+            if let Some(summary) = &session.summary {
+                println!("Summary: {}", summary);
+            }
+            sessions.insert(user_id.to_string(), session);
+        }
+        "/new" => {
+            if let Some(current) = sessions.get_mut(user_id)
+                && let Err(e) = save_conversation(current) {
+                error!("Failed to save the current conversation: {e}");
+            }
+            let session = ChatSession::new(user_id, system_prompt);
+            println!("Started conversation '{}'", session.session_id);
+            sessions.insert(user_id.to_string(), session);
+        }
+        "/title" => {
+            let Some(session) = sessions.get_mut(user_id) else {
+                println!("There is no conversation to name yet");
+                return true;
+            };
+            session.title = if args.is_empty() { None } else { Some(args.to_string()) };
+            if let Err(e) = save_conversation(session) {
+                error!("Failed to save conversation '{}': {e}", session.session_id);
+            }
+            println!("Conversation is now called '{}'", session.display_title());
+        }
+        "/transcript" => {
+            let (format, which) = args.split_once(' ').unwrap_or((args, ""));
+            let extension = match format {
+                "md" | "markdown" => "md",
+                "json" => "json",
+                _ => {
+                    println!("Usage: /transcript <md|json> [conversation id or number]");
+                    return true;
+                }
+            };
+
+            // Without an id the current conversation is exported
+            let session = match resolve_id(user_id, which.trim()) {
+                Some(id) => match load_conversation(user_id, &id) {
+                    Ok(session) => session,
+                    Err(e) => {
+                        error!("Failed to load conversation '{}': {e}", id);
+                        return true;
+                    }
+                },
+                None => match sessions.get(user_id) {
+                    Some(session) => session.clone(),
+                    None => {
+                        println!("There is no conversation to export yet");
+                        return true;
+                    }
+                }
+            };
+
+            let path = Path::new(EXPORT_DIR).join(format!("{}.{}", sanitize_id(&session.session_id), extension));
+            match export_transcript(&session, extension, &path) {
+                Ok(()) => println!("Exported '{}' to {}", session.display_title(), path.display()),
+                Err(e) => error!("Failed to export transcript to {}: {e}", path.display())
+            }
+        }
+        "/delete" | "/archive" => {
+            let Some(id) = resolve_id(user_id, args) else {
+                println!("Usage: {} <conversation id or number>", command);
+                return true;
+            };
+
+            let result = if command == "/delete" {
+                delete_conversation(user_id, &id)
+            } else {
+                archive_conversation(user_id, &id)
+            };
+
+            match result {
+                Ok(()) => println!("{} conversation '{}'", if command == "/delete" { "Deleted" } else { "Archived" }, id),
+                Err(e) => {
+                    error!("Failed to {} conversation '{}': {e}", &command[1..], id);
+                    return true;
+                }
+            }
+
+            // The current conversation starts over, otherwise the next
+            // message would save it right back
+            if sessions.get(user_id).is_some_and(|s| s.session_id == id) {
+                sessions.insert(user_id.to_string(), ChatSession::new(user_id, system_prompt));
+            }
+        }
+        _ => return false
+    }
+
+    true
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openai_api_rust::Message;
+
+    #[test]
+    fn a_stored_summary_is_kept() {
+        let mut session = ChatSession::new("tester", "");
+        session.messages.push(Message { role: Role::User, content: "My ticket is ERR-1042".to_string() });
+        session.summary = Some("The user has ticket ERR-1042.".to_string());
+        session.summarized = 1;
+
+        // Newer messages don't make it ask the chat model again
+        session.messages.push(Message { role: Role::Assistant, content: "Noted.".to_string() });
+        assert!(summarise_conversation(&mut session));
+        assert_eq!(session.summary.as_deref(), Some("The user has ticket ERR-1042."));
+        assert_eq!(session.summarized, 1);
+    }
+}
@@ -1,9 +1,20 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
 use log::{self, Level, Metadata, Record};
+use serde::{ Deserialize, Serialize };
 
 use openai_api_rust::embeddings::{ EmbeddingsBody };
 use openai_api_rust::chat::ChatBody;
 use openai_api_rust::Message;
 
+use crate::bm25::Bm25Index;
+use crate::dedup::TextHashes;
+use crate::lazy::Lazy;
+use crate::transcript::TurnRecord;
+use crate::vectors::VectorStore;
+use crate::wal::{ FsyncPolicy, WriteAheadLog };
+
 
 // Here we predefine the MyChatbot struct, and implement
 // methods elsewhere
@@ -39,9 +50,18 @@ impl log::Log for MyLogger {
 
 // This is a public struct, accessible from any other
 // source code file.
-// It creates MyChatBody, taking ownership of ChatBody to 
-// allow implementations
-pub struct MyChatBody(ChatBody);
+// It creates MyChatBody, taking ownership of ChatBody to
+// allow implementations. ChatBody has no seed, response_format or
+// tools, and its messages can't hold tool calls, so those are kept
+// next to it and merged in by to_json()
+pub struct MyChatBody {
+    body: ChatBody,
+    seed: Option<i64>,
+    response_format: Option<serde_json::Value>,
+    tools: Option<serde_json::Value>,
+    tool_choice: Option<String>,
+    raw_messages: Vec<serde_json::Value>,
+}
 
 // Implements the `::new()` constructor for MyChatBody,
 // a simplified wrapper over ChatBody for easier creation.
@@ -54,42 +74,130 @@ impl MyChatBody {
         // anywhere in the codebase, because a lot of parameters must be defined
         // when defining a ChatBody, most of which is unused, hence the None type.
         // So we only have to provide a few common parameters
-        MyChatBody(ChatBody { 
-            model: model.to_string(), 
-            messages, 
-            temperature: None,
-            top_p: None,
-            n: None,
-            stream: None,
-            stop: None,
-            max_tokens: None,
-            presence_penalty: None, 
-            frequency_penalty: None, 
-            logit_bias: None, 
-            user: None 
-        })
+        MyChatBody {
+            body: ChatBody { 
+                model: model.to_string(), 
+                messages, 
+                temperature: None,
+                top_p: None,
+                n: None,
+                stream: None,
+                stop: None,
+                max_tokens: None,
+                presence_penalty: None, 
+                frequency_penalty: None, 
+                logit_bias: None, 
+                user: None 
+            },
+            seed: None,
+            response_format: None,
+            tools: None,
+            tool_choice: None,
+            raw_messages: vec![],
+        }
     }
 
     // This function allows chaining methods to the constructor,
     // in this case changing the max_tokens value of the struct
     // MyChatBody.
     pub fn with_max_tokens(mut self, max_tokens: i32) -> Self {
-        self.0.max_tokens = Some(max_tokens);
+        self.body.max_tokens = Some(max_tokens);
         self
     }
 
     // This function does the same as the method above, but in this
     // case we are modifying the temperature value.
     pub fn with_temperature(mut self, temperature: f32) -> Self {
-        self.0.temperature = Some(temperature);
+        self.body.temperature = Some(temperature);
+        self
+    }
+
+    pub fn with_top_p(mut self, top_p: f32) -> Self {
+        self.body.top_p = Some(top_p);
+        self
+    }
+
+    // Generation ends at the first of these, they are not part of the answer
+    pub fn with_stop(mut self, stop: Vec<String>) -> Self {
+        self.body.stop = Some(stop);
+        self
+    }
+
+    pub fn with_presence_penalty(mut self, penalty: f32) -> Self {
+        self.body.presence_penalty = Some(penalty);
+        self
+    }
+
+    pub fn with_frequency_penalty(mut self, penalty: f32) -> Self {
+        self.body.frequency_penalty = Some(penalty);
+        self
+    }
+
+    // How many answers to generate for the same messages
+    pub fn with_n(mut self, n: i32) -> Self {
+        self.body.n = Some(n);
+        self
+    }
+
+    // The same seed and messages give the same answer, on servers
+    // that support it
+    pub fn with_seed(mut self, seed: i64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    // Like {"type": "json_object"}, see ResponseFormat in params.rs
+    pub fn with_response_format(mut self, response_format: serde_json::Value) -> Self {
+        self.response_format = Some(response_format);
+        self
+    }
+
+    // The tool definitions the model may call, see tools.rs
+    pub fn with_tools(mut self, tools: serde_json::Value) -> Self {
+        self.tools = Some(tools);
         self
     }
 
+    // "auto" lets the model pick, "none" makes it answer without tools
+    pub fn with_tool_choice(mut self, choice: &str) -> Self {
+        self.tool_choice = Some(choice.to_string());
+        self
+    }
+
+    // Messages sent after the regular ones, as they are. Tool calls of
+    // the assistant and tool results only fit in here
+    pub fn with_raw_messages(mut self, messages: Vec<serde_json::Value>) -> Self {
+        self.raw_messages = messages;
+        self
+    }
+
+    // The request body as it is sent, with the fields ChatBody lacks
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut json = serde_json::to_value(&self.body).unwrap_or_default();
+        if let Some(messages) = json["messages"].as_array_mut() {
+            messages.extend(self.raw_messages.iter().cloned());
+        }
+        if let Some(tools) = &self.tools {
+            json["tools"] = tools.clone();
+        }
+        if let Some(choice) = &self.tool_choice {
+            json["tool_choice"] = choice.clone().into();
+        }
+        if let Some(seed) = self.seed {
+            json["seed"] = seed.into();
+        }
+        if let Some(format) = &self.response_format {
+            json["response_format"] = format.clone();
+        }
+        json
+    }
+
     // Since MyChatBot is a wrapper for ChatBody, we can unwrap it if we want
-    // to use ChatBody instead of MyChatBody
+    // to use ChatBody instead of MyChatBody. The seed and response format
+    // are lost, ChatBody has no place for them
     // Idiomatically we call this into_inner(), but we also call it unwrap()
     pub fn into_inner(self) -> ChatBody {
-        self.0
+        self.body
     }
 }
 
@@ -102,7 +210,7 @@ impl MyChatBody {
 impl std::ops::Deref for MyChatBody {
     type Target = ChatBody;
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.body
     }
 }
 
@@ -137,4 +245,112 @@ impl std::ops::Deref for MyEmbeddingBody {
     fn deref(&self) -> &Self::Target {
         &self.0
     }
-}
\ No newline at end of file
+}
+
+
+// Who said what a memory holds. Memories from before roles were
+// recorded were all user input (see capture.rs)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MemoryRole {
+    #[default]
+    User,
+    Assistant,
+    System,
+    Tool,
+}
+
+// Every memory remembers who it belongs to and in which
+// chat session it was created, so it can never be handed
+// to a different user by accident
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct MemoryMetadata {
+    pub user_id: String,
+    pub session_id: String,
+    pub created_at: u64,
+    // 0..1, given when the memory was added (see importance.rs)
+    #[serde(default)]
+    pub importance: Option<f32>,
+    // A consolidated summary lists the memories it was made from,
+    // and those name the summary they ended up in (see consolidate.rs)
+    #[serde(default)]
+    pub sources: Vec<String>,
+    #[serde(default)]
+    pub summarized_in: Option<String>,
+    // How often the same thing was said again, and when it last was
+    // (see dedup.rs). last_seen is 0 if it never was
+    #[serde(default)]
+    pub hits: u32,
+    #[serde(default)]
+    pub last_seen: u64,
+    #[serde(default)]
+    pub role: MemoryRole,
+    // The index of the message in its session this memory was taken
+    // from, and what kind of fact it is if it was extracted from that
+    // message instead of stored as is (see extract.rs)
+    #[serde(default)]
+    pub turn: Option<usize>,
+    #[serde(default)]
+    pub category: Option<String>,
+}
+
+// This struct holds all the memories of one single user.
+// memories holds the embedding vectors (see vectors.rs), memories_text
+// the original text and metadata the MemoryMetadata, all three
+// keyed by the same memory key.
+// For memory-mapped collections the texts and metadata are kept in
+// their own files and only read when first needed (see lazy.rs).
+// keywords and text_hashes are never saved, they are rebuilt from the
+// texts on first use
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct UserMemories {
+    pub user_id: String,
+    pub memories: VectorStore,
+    #[serde(default)]
+    pub memories_text: Lazy<HashMap<String, String>>,
+    #[serde(default)]
+    pub metadata: Lazy<HashMap<String, MemoryMetadata>>,
+    pub count: usize,
+    #[serde(skip)]
+    pub keywords: OnceLock<Bm25Index>,
+    #[serde(skip)]
+    pub text_hashes: OnceLock<TextHashes>,
+}
+
+// The store itself only maps user ids to their own UserMemories,
+// next to the write-ahead log of every loaded user.
+// Methods are implemented in store.rs
+#[derive(Default)]
+pub struct MemoryStore {
+    pub users: HashMap<String, UserMemories>,
+    pub wals: HashMap<String, WriteAheadLog>,
+    pub fsync: FsyncPolicy,
+}
+
+// A chat session belongs to one user and owns its own chat history,
+// so switching users in the REPL also switches the history.
+// Sessions are saved as conversations that can be listed and resumed
+// later (see conversations.rs). summary covers the first summarized
+// messages, an archived conversation is hidden from the list.
+// turns keeps the memories and token usage of every answer, for the
+// transcripts (see transcript.rs)
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChatSession {
+    pub session_id: String,
+    pub user_id: String,
+    pub messages: Vec<Message>,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub created_at: u64,
+    #[serde(default)]
+    pub updated_at: u64,
+    #[serde(default)]
+    pub archived: bool,
+    #[serde(default)]
+    pub summary: Option<String>,
+    #[serde(default)]
+    pub summarized: usize,
+    #[serde(default)]
+    pub turns: Vec<TurnRecord>,
+}
@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+use std::hash::{ DefaultHasher, Hash, Hasher };
+
+use log::*;
+
+use crate::custom_types::{ MemoryMetadata, MemoryRole, MemoryStore, UserMemories };
+use crate::utils::{ load_environment, search_terms, DEDUP_THRESHOLD };
+
+
+// What happens when a new memory says what an existing one already says.
+// Skip drops the new one, Count bumps the hit counter of the existing one,
+// Merge also takes over the newer timestamp and the higher importance.
+// Off stores every memory, duplicate or not
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DedupPolicy {
+    Off,
+    Skip,
+    #[default]
+    Count,
+    Merge,
+}
+
+// A memory is a duplicate if its text is the same as an existing one
+// (ignoring case, spacing and punctuation around words), or if its
+// vector is at least threshold cosine similar to an existing one
+#[derive(Clone, Copy, Debug)]
+pub struct DedupOptions {
+    pub policy: DedupPolicy,
+    pub threshold: f32,
+}
+
+// Memory keys by the hash of their normalised text, for exact matches.
+// Like the keyword index it is never saved, it is built on first use
+// and kept up to date by apply_entry
+#[derive(Debug, Default)]
+pub struct TextHashes {
+    by_hash: HashMap<u64, Vec<String>>,
+    by_key: HashMap<String, u64>,
+}
+
+impl DedupPolicy {
+    pub fn parse(input: &str) -> Option<Self> {
+        match input.trim().to_lowercase().as_str() {
+            "off" | "none" => Some(DedupPolicy::Off),
+            "skip" => Some(DedupPolicy::Skip),
+            "" | "count" => Some(DedupPolicy::Count),
+            "merge" => Some(DedupPolicy::Merge),
+            _ => None
+        }
+    }
+}
+
+impl Default for DedupOptions {
+    fn default() -> Self {
+        DedupOptions {
+            policy: DedupPolicy::default(),
+            threshold: DEDUP_THRESHOLD,
+        }
+    }
+}
+
+impl DedupOptions {
+    // Reads MEMORY_DEDUP (off, skip, count or merge) and MEMORY_DEDUP_THRESHOLD
+    pub fn from_env() -> Self {
+        let mut options = DedupOptions::default();
+
+        let policy = load_environment("MEMORY_DEDUP");
+        match DedupPolicy::parse(&policy) {
+            Some(parsed) => options.policy = parsed,
+            None => warn!("Unknown MEMORY_DEDUP '{}', using {:?}", policy, options.policy)
+        }
+
+        let threshold = load_environment("MEMORY_DEDUP_THRESHOLD");
+        if !threshold.trim().is_empty() {
+            match threshold.trim().parse::<f32>() {
+                Ok(value) => options.threshold = value.clamp(-1.0, 1.0),
+                Err(_) => warn!("MEMORY_DEDUP_THRESHOLD '{}' is not a number, using {}", threshold, options.threshold)
+            }
+        }
+
+        options
+    }
+}
+
+// The words of a text, lowercased and without the punctuation around
+// them, so "Hi there!" and "hi  there" hash the same
+pub fn text_hash(text: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    search_terms(text).join(" ").hash(&mut hasher);
+    hasher.finish()
+}
+
+impl TextHashes {
+    pub fn build<'a>(texts: impl Iterator<Item = (&'a String, &'a String)>) -> Self {
+        let mut hashes = TextHashes::default();
+        for (key, text) in texts {
+            hashes.add(key, text);
+        }
+        hashes
+    }
+
+    pub fn add(&mut self, key: &str, text: &str) {
+        self.remove(key);
+
+        let hash = text_hash(text);
+        self.by_hash.entry(hash).or_default().push(key.to_string());
+        self.by_key.insert(key.to_string(), hash);
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        let Some(hash) = self.by_key.remove(key) else {
+            return;
+        };
+        if let Some(keys) = self.by_hash.get_mut(&hash) {
+            keys.retain(|k| k != key);
+            if keys.is_empty() {
+                self.by_hash.remove(&hash);
+            }
+        }
+    }
+
+    // The keys whose text may be the same as this one, a hash match
+    // still has to be confirmed against the text itself
+    pub fn candidates(&self, text: &str) -> &[String] {
+        self.by_hash.get(&text_hash(text)).map_or(&[], |keys| keys.as_slice())
+    }
+}
+
+impl UserMemories {
+    pub fn text_hashes(&self) -> &TextHashes {
+        self.text_hashes.get_or_init(|| TextHashes::build(self.memories_text.iter()))
+    }
+
+    pub fn text_hashes_mut(&mut self) -> Option<&mut TextHashes> {
+        self.text_hashes.get_mut()
+    }
+
+    fn has_role(&self, key: &str, role: MemoryRole) -> bool {
+        self.metadata.get(key).map_or(MemoryRole::default(), |meta| meta.role) == role
+    }
+
+    // The key of an existing memory of the same role with the same text.
+    // The user repeating what the bot said is not a duplicate
+    pub fn find_same_text(&self, text: &str, role: MemoryRole) -> Option<String> {
+        let normalised = search_terms(text);
+        self.text_hashes()
+            .candidates(text)
+            .iter()
+            .filter(|key| self.has_role(key, role))
+            .find(|key| self.memories_text.get(*key).is_some_and(|existing| search_terms(existing) == normalised))
+            .cloned()
+    }
+
+    // The key of the existing memory of the same role closest to the
+    // vector, if it is at least threshold similar. Keys in skip are not
+    // looked at
+    pub fn find_similar(&self, vector: &[f32], role: MemoryRole, threshold: f32, skip: &[String]) -> Option<(String, f32)> {
+        if self.memories.is_empty() {
+            return None;
+        }
+
+        self.memories
+            .search(vector, 1, |key: &str| !skip.iter().any(|s| s == key) && self.has_role(key, role))
+            .into_iter()
+            .next()
+            .filter(|(_, similarity)| *similarity >= threshold)
+    }
+}
+
+// Applies the policy to the existing memory a new one duplicates.
+// Returns false if the metadata could not be updated
+pub fn record_duplicate(store: &mut MemoryStore, user_id: &str, key: &str, new: &MemoryMetadata, policy: DedupPolicy) -> bool {
+    let Some(mut metadata) = store.user_mut(user_id).metadata.get(key).cloned() else {
+        return false;
+    };
+
+    match policy {
+        DedupPolicy::Off | DedupPolicy::Skip => return true,
+        DedupPolicy::Count => {
+            metadata.hits += 1;
+        }
+        DedupPolicy::Merge => {
+            metadata.hits += 1;
+            metadata.last_seen = metadata.last_seen.max(new.created_at);
+            metadata.importance = match (metadata.importance, new.importance) {
+                (Some(a), Some(b)) => Some(a.max(b)),
+                (a, b) => a.or(b),
+            };
+        }
+    }
+
+    store.update_metadata(user_id, key, metadata)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::remove_user_files;
+
+    // A store with one user memory, created at 100 with importance 0.4
+    fn store_with_memory(user_id: &str) -> (MemoryStore, String) {
+        remove_user_files(user_id);
+        let mut store = MemoryStore::new();
+        let metadata = MemoryMetadata { user_id: user_id.to_string(), created_at: 100, importance: Some(0.4), ..Default::default() };
+        let key = store.insert_memory(user_id, "My ticket is ERR-1042.", vec![1.0, 0.0], metadata).unwrap();
+        (store, key)
+    }
+
+    fn duplicate(user_id: &str, created_at: u64, importance: Option<f32>) -> MemoryMetadata {
+        MemoryMetadata { user_id: user_id.to_string(), created_at, importance, ..Default::default() }
+    }
+
+    fn metadata(store: &mut MemoryStore, user_id: &str, key: &str) -> MemoryMetadata {
+        store.user_mut(user_id).metadata.get(key).cloned().unwrap()
+    }
+
+    #[test]
+    fn skip_leaves_the_existing_memory_alone() {
+        let user_id = format!("dedup-skip-{}", std::process::id());
+        let (mut store, key) = store_with_memory(&user_id);
+        let before = metadata(&mut store, &user_id, &key);
+
+        assert!(record_duplicate(&mut store, &user_id, &key, &duplicate(&user_id, 200, Some(0.9)), DedupPolicy::Skip));
+        let after = metadata(&mut store, &user_id, &key);
+        assert_eq!(after.hits, before.hits);
+        assert_eq!(after.last_seen, before.last_seen);
+        assert_eq!(after.importance, Some(0.4));
+
+        drop(store);
+        remove_user_files(&user_id);
+    }
+
+    #[test]
+    fn count_only_bumps_the_hits() {
+        let user_id = format!("dedup-count-{}", std::process::id());
+        let (mut store, key) = store_with_memory(&user_id);
+        let before = metadata(&mut store, &user_id, &key);
+
+        for _ in 0..2 {
+            assert!(record_duplicate(&mut store, &user_id, &key, &duplicate(&user_id, 200, Some(0.9)), DedupPolicy::Count));
+        }
+        let after = metadata(&mut store, &user_id, &key);
+        assert_eq!(after.hits, before.hits + 2);
+        assert_eq!(after.last_seen, before.last_seen);
+        assert_eq!(after.importance, Some(0.4));
+
+        drop(store);
+        remove_user_files(&user_id);
+    }
+
+    #[test]
+    fn merge_keeps_the_latest_time_and_highest_importance() {
+        let user_id = format!("dedup-merge-{}", std::process::id());
+        let (mut store, key) = store_with_memory(&user_id);
+        let before = metadata(&mut store, &user_id, &key);
+
+        assert!(record_duplicate(&mut store, &user_id, &key, &duplicate(&user_id, 200, Some(0.9)), DedupPolicy::Merge));
+        // An older, less important duplicate changes nothing but the hits
+        assert!(record_duplicate(&mut store, &user_id, &key, &duplicate(&user_id, 150, Some(0.1)), DedupPolicy::Merge));
+        let after = metadata(&mut store, &user_id, &key);
+        assert_eq!(after.hits, before.hits + 2);
+        assert_eq!(after.last_seen, 200);
+        assert_eq!(after.importance, Some(0.9));
+
+        // Nothing to record against a memory that isn't there
+        assert!(!record_duplicate(&mut store, &user_id, "missing", &duplicate(&user_id, 300, None), DedupPolicy::Merge));
+
+        drop(store);
+        remove_user_files(&user_id);
+    }
+
+    #[test]
+    fn similar_vectors_need_to_reach_the_threshold() {
+        let user_id = format!("dedup-cosine-{}", std::process::id());
+        let (mut store, key) = store_with_memory(&user_id);
+        let user = store.user_mut(&user_id);
+
+        // cos(angle) of [1, 0] and [0.95, 0.312...] is 0.95
+        let vector = [0.95, (1.0f32 - 0.95 * 0.95).sqrt()];
+        let (found, similarity) = user.find_similar(&vector, MemoryRole::User, 0.9, &[]).unwrap();
+        assert_eq!(found, key);
+        assert!((similarity - 0.95).abs() < 1e-5);
+
+        assert!(user.find_similar(&vector, MemoryRole::User, 0.96, &[]).is_none());
+        assert!(user.find_similar(&vector, MemoryRole::Assistant, 0.9, &[]).is_none());
+        assert!(user.find_similar(&vector, MemoryRole::User, 0.9, std::slice::from_ref(&key)).is_none());
+
+        drop(store);
+        remove_user_files(&user_id);
+    }
+
+    #[test]
+    fn same_text_ignores_case_spacing_and_punctuation() {
+        let user_id = format!("dedup-text-{}", std::process::id());
+        let (mut store, key) = store_with_memory(&user_id);
+        let user = store.user_mut(&user_id);
+
+        assert_eq!(user.find_same_text("my  ticket is err-1042", MemoryRole::User), Some(key));
+        assert_eq!(user.find_same_text("my ticket is err-1043", MemoryRole::User), None);
+        assert_eq!(user.find_same_text("My ticket is ERR-1042.", MemoryRole::Assistant), None);
+
+        drop(store);
+        remove_user_files(&user_id);
+    }
+}
@@ -0,0 +1,569 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+use log::*;
+use serde::{ Deserialize, Serialize };
+use serde_json::{Map, Value};
+
+use crate::custom_types::{ MemoryMetadata, MemoryStore, UserMemories };
+
+static NPY_MAGIC: &[u8] = b"\x93NUMPY";
+
+
+// One memory as it appears in a JSONL export, one per line
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ExportRecord {
+    pub id: String,
+    pub text: String,
+    pub metadata: MemoryMetadata,
+    pub vector: Vec<f32>,
+}
+
+// The memory keys of a user in the order they were created, so that
+// every export format lists the memories in the same order
+fn ordered_keys(user: &UserMemories) -> Vec<&String> {
+    let mut keys: Vec<&String> = user.memories.keys().collect();
+    keys.sort_by_key(|k| (user.metadata.get(*k).map_or(0, |m| m.created_at), k.len(), (*k).clone()));
+    keys
+}
+
+fn create_parent(path: &Path) -> std::io::Result<()> {
+    if let Some(parent) = path.parent()
+        && !parent.as_os_str().is_empty() {
+        fs::create_dir_all(parent)?;
+    }
+    Ok(())
+}
+
+// The id list written next to a .npy file, "vectors.npy" gets "vectors.ids.txt"
+pub fn ids_sidecar_path(npy_path: &Path) -> PathBuf {
+    npy_path.with_extension("ids.txt")
+}
+
+// The vector length memories of a user have to have, None while they
+// have none and the first import decides it
+fn collection_dim(store: &mut MemoryStore, user_id: &str) -> Option<usize> {
+    let user = store.user_mut(user_id);
+    (!user.memories.is_empty()).then_some(user.memories.dim)
+}
+
+// Imports one record into the memories of a user. The metadata is always
+// re-tagged with the importing user, and a record only keeps its id if that
+// id is free or already holds the very same text. A vector of another
+// length than the collection's is skipped, it could never be searched
+fn import_record(store: &mut MemoryStore, user_id: &str, record: ExportRecord) -> bool {
+    let mut metadata = record.metadata;
+    metadata.user_id = user_id.to_string();
+
+    if record.vector.is_empty() {
+        warn!("Skipping '{}', it has no vector", record.id);
+        return false;
+    }
+    if let Some(dim) = collection_dim(store, user_id)
+        && record.vector.len() != dim {
+        warn!("Skipping '{}', its vector has length {} but the memories of user '{}' use {}", record.id, record.vector.len(), user_id, dim);
+        return false;
+    }
+
+    let user = store.user_mut(user_id);
+    let keep_id = match user.memories_text.get(&record.id) {
+        None => !record.id.is_empty(),
+        Some(text) => *text == record.text
+    };
+
+    if keep_id {
+        store.insert_memory_at(user_id, &record.id, &record.text, record.vector, metadata).is_some()
+    } else {
+        store.insert_memory(user_id, &record.text, record.vector, metadata).is_some()
+    }
+}
+
+
+pub fn export_jsonl(user: &UserMemories, path: &Path) -> std::io::Result<usize> {
+    create_parent(path)?;
+    let mut writer = BufWriter::new(File::create(path)?);
+    let mut written: usize = 0;
+
+    for key in ordered_keys(user) {
+        let record = ExportRecord {
+            id: key.clone(),
+            text: user.memories_text.get(key).cloned().unwrap_or_default(),
+            metadata: user.metadata.get(key).cloned().unwrap_or_default(),
+            vector: user.memories.get(key).unwrap_or_default(),
+        };
+
+        serde_json::to_writer(&mut writer, &record)?;
+        writer.write_all(b"\n")?;
+        written += 1;
+    }
+
+    writer.flush()?;
+    info!("Exported {} memories of user '{}' to {}", written, user.user_id, path.display());
+    Ok(written)
+}
+
+pub fn import_jsonl(store: &mut MemoryStore, user_id: &str, path: &Path) -> std::io::Result<usize> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut imported: usize = 0;
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match serde_json::from_str::<ExportRecord>(&line) {
+            Ok(record) => {
+                if import_record(store, user_id, record) { imported += 1; }
+            }
+            Err(e) => warn!("Skipping line {} of {}: {e}", i + 1, path.display())
+        }
+    }
+
+    info!("Imported {} memories into user '{}' from {}", imported, user_id, path.display());
+    Ok(imported)
+}
+
+
+// The metadata columns are taken from MemoryMetadata itself, so new
+// metadata fields show up in the CSV without touching this file.
+// The default value of each field also tells us its type when importing
+fn metadata_template() -> Map<String, Value> {
+    match serde_json::to_value(MemoryMetadata::default()) {
+        Ok(Value::Object(map)) => map,
+        _ => Map::new()
+    }
+}
+
+fn value_to_cell(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        other => other.to_string()
+    }
+}
+
+fn cell_to_value(cell: &str, template: Option<&Value>) -> Value {
+    match template {
+        Some(Value::String(_)) => Value::String(cell.to_string()),
+        _ if cell.is_empty() => Value::Null,
+        _ => serde_json::from_str(cell).unwrap_or_else(|_| Value::String(cell.to_string()))
+    }
+}
+
+fn escape_csv(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+// A small CSV reader: handles quoted fields with commas, doubled quotes
+// and line breaks inside them, which is all our own exports produce
+fn parse_csv(contents: &str) -> Vec<Vec<String>> {
+    let mut rows: Vec<Vec<String>> = vec![];
+    let mut row: Vec<String> = vec![];
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = contents.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (c, in_quotes) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            ('"', true) => in_quotes = false,
+            ('"', false) if field.is_empty() => in_quotes = true,
+            (',', false) => row.push(std::mem::take(&mut field)),
+            ('\r', false) => (),
+            ('\n', false) => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            (other, _) => field.push(other)
+        }
+    }
+
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+
+    rows
+}
+
+pub fn export_csv(user: &UserMemories, path: &Path) -> std::io::Result<usize> {
+    create_parent(path)?;
+    let mut writer = BufWriter::new(File::create(path)?);
+    let columns: Vec<String> = metadata_template().keys().cloned().collect();
+
+    let mut header: Vec<String> = vec!["id".to_string(), "text".to_string()];
+    header.extend(columns.iter().cloned());
+    writeln!(writer, "{}", header.iter().map(|h| escape_csv(h)).collect::<Vec<_>>().join(","))?;
+
+    let mut written: usize = 0;
+    for key in ordered_keys(user) {
+        let metadata = serde_json::to_value(user.metadata.get(key).cloned().unwrap_or_default())?;
+
+        let mut cells: Vec<String> = vec![
+            key.clone(),
+            user.memories_text.get(key).cloned().unwrap_or_default(),
+        ];
+        for column in &columns {
+            cells.push(metadata.get(column).map(value_to_cell).unwrap_or_default());
+        }
+
+        writeln!(writer, "{}", cells.iter().map(|c| escape_csv(c)).collect::<Vec<_>>().join(","))?;
+        written += 1;
+    }
+
+    writer.flush()?;
+    info!("Exported {} memory texts of user '{}' to {}", written, user.user_id, path.display());
+    Ok(written)
+}
+
+// Reads a CSV export back into (text, metadata) pairs keyed by id
+fn read_csv_records(path: &Path) -> std::io::Result<HashMap<String, (String, MemoryMetadata)>> {
+    let contents = fs::read_to_string(path)?;
+    let mut rows = parse_csv(&contents).into_iter();
+    let Some(header) = rows.next() else {
+        return Ok(HashMap::new());
+    };
+
+    let template = metadata_template();
+    let id_col = header.iter().position(|h| h == "id");
+    let text_col = header.iter().position(|h| h == "text");
+    let (Some(id_col), Some(text_col)) = (id_col, text_col) else {
+        return Err(Error::new(ErrorKind::InvalidData, "CSV needs an 'id' and a 'text' column"));
+    };
+
+    let mut result: HashMap<String, (String, MemoryMetadata)> = HashMap::new();
+    for (i, row) in rows.enumerate() {
+        let mut object = Map::new();
+        for (column, cell) in header.iter().zip(row.iter()) {
+            if template.contains_key(column) {
+                object.insert(column.clone(), cell_to_value(cell, template.get(column)));
+            }
+        }
+
+        let metadata = match serde_json::from_value::<MemoryMetadata>(Value::Object(object)) {
+            Ok(m) => m,
+            Err(e) => {
+                warn!("Metadata of CSV row {} could not be read ({e}), using defaults", i + 2);
+                MemoryMetadata::default()
+            }
+        };
+
+        let id = row.get(id_col).cloned().unwrap_or_default();
+        let text = row.get(text_col).cloned().unwrap_or_default();
+        result.insert(id, (text, metadata));
+    }
+
+    Ok(result)
+}
+
+
+// Writes the vectors as a 2D little-endian f32 matrix in NumPy's .npy
+// format (version 1.0), one row per memory, and the memory ids in the
+// same order into the sidecar file
+pub fn export_npy(user: &UserMemories, path: &Path) -> std::io::Result<usize> {
+    create_parent(path)?;
+    let keys = ordered_keys(user);
+    let dim = user.memories.dim;
+
+    // The header is padded with spaces so that the data starts at a
+    // multiple of 64 bytes, and always ends with a newline
+    let mut header = format!("{{'descr': '<f4', 'fortran_order': False, 'shape': ({}, {}), }}", keys.len(), dim);
+    let unpadded = NPY_MAGIC.len() + 2 + 2 + header.len() + 1;
+    header.push_str(&" ".repeat((64 - unpadded % 64) % 64));
+    header.push('\n');
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(NPY_MAGIC)?;
+    writer.write_all(&[1, 0])?;
+    writer.write_all(&(header.len() as u16).to_le_bytes())?;
+    writer.write_all(header.as_bytes())?;
+
+    for key in &keys {
+        for value in user.memories.get(key).unwrap_or_default() {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+    }
+    writer.flush()?;
+
+    let ids: String = keys.iter().map(|k| format!("{}\n", k)).collect();
+    fs::write(ids_sidecar_path(path), ids)?;
+
+    info!("Exported {}x{} vector matrix of user '{}' to {}", keys.len(), dim, user.user_id, path.display());
+    Ok(keys.len())
+}
+
+// Reads a 2D .npy matrix of '<f8' or '<f4' values in C order
+pub fn read_npy(path: &Path) -> std::io::Result<Vec<Vec<f32>>> {
+    let mut bytes: Vec<u8> = vec![];
+    File::open(path)?.read_to_end(&mut bytes)?;
+
+    let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), msg));
+
+    if bytes.len() < 10 || &bytes[..6] != NPY_MAGIC {
+        return Err(invalid("not a .npy file"));
+    }
+
+    // Version 1.x stores the header length in 2 bytes, 2.x and 3.x in 4
+    let (header_len, header_start) = match bytes[6] {
+        1 => (u16::from_le_bytes([bytes[8], bytes[9]]) as usize, 10),
+        2 | 3 if bytes.len() >= 12 => (u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize, 12),
+        _ => return Err(invalid("unsupported .npy version"))
+    };
+
+    let data_start = header_start + header_len;
+    let header = std::str::from_utf8(bytes.get(header_start..data_start).ok_or_else(|| invalid("truncated header"))?)
+        .map_err(|_| invalid("header is not text"))?;
+
+    if header.contains("'fortran_order': True") {
+        return Err(invalid("fortran ordered arrays are not supported"));
+    }
+
+    let width: usize = if header.contains("'<f8'") { 8 }
+        else if header.contains("'<f4'") { 4 }
+        else { return Err(invalid("only '<f8' and '<f4' arrays are supported")) };
+
+    let shape_start = header.find("'shape': (").ok_or_else(|| invalid("no shape in header"))? + "'shape': (".len();
+    let shape_end = header[shape_start..].find(')').ok_or_else(|| invalid("broken shape"))? + shape_start;
+    let shape: Vec<usize> = header[shape_start..shape_end]
+        .split(',')
+        .filter(|s| !s.trim().is_empty())
+        .map(|s| s.trim().parse::<usize>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| invalid("broken shape"))?;
+
+    let (rows, cols) = match shape.as_slice() {
+        [rows, cols] => (*rows, *cols),
+        [rows] => (*rows, 1),
+        _ => return Err(invalid("only 1D and 2D arrays are supported"))
+    };
+
+    let data = &bytes[data_start..];
+    if data.len() < rows * cols * width {
+        return Err(invalid("file is shorter than its shape"));
+    }
+
+    let values: Vec<f32> = data.chunks_exact(width)
+        .take(rows * cols)
+        .map(|chunk| match width {
+            8 => f64::from_le_bytes(chunk.try_into().unwrap()) as f32,
+            _ => f32::from_le_bytes(chunk.try_into().unwrap())
+        })
+        .collect();
+
+    Ok(values.chunks(cols.max(1)).map(|row| row.to_vec()).collect())
+}
+
+// Imports a .npy matrix together with its id sidecar, taking the text
+// and metadata of every row from a CSV export with the same ids
+pub fn import_npy(store: &mut MemoryStore, user_id: &str, npy_path: &Path, csv_path: &Path) -> std::io::Result<usize> {
+    let vectors = read_npy(npy_path)?;
+    let ids: Vec<String> = fs::read_to_string(ids_sidecar_path(npy_path))?
+        .lines()
+        .map(|l| l.trim().to_string())
+        .filter(|l| !l.is_empty())
+        .collect();
+
+    if ids.len() != vectors.len() {
+        return Err(Error::new(ErrorKind::InvalidData, format!("{} ids for {} vectors", ids.len(), vectors.len())));
+    }
+
+    // Every row of the matrix has the same length, so a file that doesn't
+    // fit the collection is skipped as a whole
+    if let Some(dim) = collection_dim(store, user_id)
+        && let Some(cols) = vectors.first().map(|v| v.len())
+        && cols != dim {
+        warn!("Skipping {}, its vectors have length {} but the memories of user '{}' use {}", npy_path.display(), cols, user_id, dim);
+        return Ok(0);
+    }
+
+    let mut texts = read_csv_records(csv_path)?;
+    let mut imported: usize = 0;
+
+    for (id, vector) in ids.into_iter().zip(vectors) {
+        let Some((text, metadata)) = texts.remove(&id) else {
+            warn!("No text for vector '{}' in {}, skipping it", id, csv_path.display());
+            continue;
+        };
+
+        if import_record(store, user_id, ExportRecord { id, text, metadata, vector }) {
+            imported += 1;
+        }
+    }
+
+    info!("Imported {} memories into user '{}' from {}", imported, user_id, npy_path.display());
+    Ok(imported)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::remove_user_files;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("export-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join(name)
+    }
+
+    // A store whose user has three memories, one with a text that needs
+    // every bit of CSV quoting
+    fn store_with_memories(user_id: &str) -> MemoryStore {
+        remove_user_files(user_id);
+        let mut store = MemoryStore::new();
+        let texts = ["plain", "with, a comma", "a \"quoted\" word\nand a second line"];
+        for (i, text) in texts.iter().enumerate() {
+            let metadata = MemoryMetadata { user_id: user_id.to_string(), session_id: "s1".to_string(), created_at: 100 + i as u64, ..Default::default() };
+            store.insert_memory(user_id, text, vec![i as f32, 1.0, -0.5], metadata).unwrap();
+        }
+        store
+    }
+
+    // (id, text, created_at, vector) of every memory, sorted by id
+    fn contents(store: &mut MemoryStore, user_id: &str) -> Vec<(String, String, u64, Vec<f32>)> {
+        let user = store.user_mut(user_id);
+        let mut result: Vec<(String, String, u64, Vec<f32>)> = user.memories.keys()
+            .map(|key| (
+                key.clone(),
+                user.memories_text.get(key).cloned().unwrap_or_default(),
+                user.metadata.get(key).map_or(0, |m| m.created_at),
+                user.memories.get(key).unwrap_or_default(),
+            ))
+            .collect();
+        result.sort_by(|a, b| a.0.cmp(&b.0));
+        result
+    }
+
+    #[test]
+    fn csv_fields_survive_quotes_commas_and_newlines() {
+        let fields = ["plain", "a,b", "say \"hi\"", "two\nlines", "", "\"", "crlf\r\nend"];
+        let line: String = fields.iter().map(|f| escape_csv(f)).collect::<Vec<String>>().join(",");
+
+        let rows = parse_csv(&format!("{}\nnext,row\n", line));
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0], fields.iter().map(|f| f.to_string()).collect::<Vec<String>>());
+        assert_eq!(rows[1], vec!["next", "row"]);
+    }
+
+    #[test]
+    fn csv_without_a_final_newline_keeps_its_last_row() {
+        assert_eq!(parse_csv("a,b\nc,d"), vec![vec!["a", "b"], vec!["c", "d"]]);
+        assert!(parse_csv("").is_empty());
+    }
+
+    #[test]
+    fn jsonl_round_trip() {
+        let user_id = format!("export-jsonl-{}", std::process::id());
+        let copy_id = format!("export-jsonl-copy-{}", std::process::id());
+        let path = temp_path("memories.jsonl");
+        let mut store = store_with_memories(&user_id);
+        remove_user_files(&copy_id);
+
+        assert_eq!(export_jsonl(store.user_mut(&user_id), &path).unwrap(), 3);
+        assert_eq!(import_jsonl(&mut store, &copy_id, &path).unwrap(), 3);
+        assert_eq!(contents(&mut store, &user_id), contents(&mut store, &copy_id));
+        assert!(store.user_mut(&copy_id).metadata.values().all(|m| m.user_id == copy_id));
+
+        drop(store);
+        remove_user_files(&user_id);
+        remove_user_files(&copy_id);
+    }
+
+    #[test]
+    fn csv_and_npy_round_trip() {
+        let user_id = format!("export-npy-{}", std::process::id());
+        let copy_id = format!("export-npy-copy-{}", std::process::id());
+        let npy_path = temp_path("vectors.npy");
+        let csv_path = temp_path("memories.csv");
+        let mut store = store_with_memories(&user_id);
+        remove_user_files(&copy_id);
+
+        assert_eq!(export_csv(store.user_mut(&user_id), &csv_path).unwrap(), 3);
+        assert_eq!(export_npy(store.user_mut(&user_id), &npy_path).unwrap(), 3);
+
+        // The data starts at a multiple of 64 bytes, as NumPy wants it
+        let bytes = fs::read(&npy_path).unwrap();
+        assert_eq!((bytes.len() - 3 * 3 * 4) % 64, 0);
+        assert_eq!(read_npy(&npy_path).unwrap().len(), 3);
+
+        assert_eq!(import_npy(&mut store, &copy_id, &npy_path, &csv_path).unwrap(), 3);
+        assert_eq!(contents(&mut store, &user_id), contents(&mut store, &copy_id));
+
+        drop(store);
+        remove_user_files(&user_id);
+        remove_user_files(&copy_id);
+    }
+
+    // A version 1.0 or 2.0 .npy file with the given header and values
+    fn write_npy(path: &Path, version: u8, header: &str, data: &[u8]) {
+        let mut bytes: Vec<u8> = NPY_MAGIC.to_vec();
+        bytes.extend_from_slice(&[version, 0]);
+        if version == 1 {
+            bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+        } else {
+            bytes.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        }
+        bytes.extend_from_slice(header.as_bytes());
+        bytes.extend_from_slice(data);
+        fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn npy_headers_are_read_or_refused() {
+        let path = temp_path("header.npy");
+        let f8: Vec<u8> = [1.5f64, -2.0, 0.25, 4.0].iter().flat_map(|x| x.to_le_bytes()).collect();
+
+        write_npy(&path, 1, "{'descr': '<f8', 'fortran_order': False, 'shape': (2, 2), }\n", &f8);
+        assert_eq!(read_npy(&path).unwrap(), vec![vec![1.5, -2.0], vec![0.25, 4.0]]);
+
+        write_npy(&path, 2, "{'descr': '<f8', 'fortran_order': False, 'shape': (4,), }\n", &f8);
+        assert_eq!(read_npy(&path).unwrap(), vec![vec![1.5], vec![-2.0], vec![0.25], vec![4.0]]);
+
+        write_npy(&path, 1, "{'descr': '<f8', 'fortran_order': True, 'shape': (2, 2), }\n", &f8);
+        assert!(read_npy(&path).is_err());
+
+        write_npy(&path, 1, "{'descr': '<i4', 'fortran_order': False, 'shape': (2, 2), }\n", &f8);
+        assert!(read_npy(&path).is_err());
+
+        write_npy(&path, 1, "{'descr': '<f8', 'fortran_order': False, 'shape': (3, 2), }\n", &f8);
+        assert!(read_npy(&path).is_err());
+
+        fs::write(&path, b"not numpy at all").unwrap();
+        assert!(read_npy(&path).is_err());
+    }
+
+    #[test]
+    fn vectors_of_another_length_are_skipped() {
+        let user_id = format!("export-dim-{}", std::process::id());
+        let path = temp_path("mixed.jsonl");
+        let mut store = store_with_memories(&user_id);
+
+        let record = |id: &str, vector: Vec<f32>| serde_json::to_string(&ExportRecord {
+            id: id.to_string(),
+            text: format!("text of {}", id),
+            metadata: MemoryMetadata::default(),
+            vector,
+        }).unwrap();
+        let lines = [record("fits", vec![0.0, 0.0, 1.0]), record("short", vec![1.0]), record("empty", vec![]), "not json".to_string()];
+        fs::write(&path, lines.join("\n")).unwrap();
+
+        assert_eq!(import_jsonl(&mut store, &user_id, &path).unwrap(), 1);
+        let user = store.user_mut(&user_id);
+        assert_eq!(user.memories.len(), 4);
+        assert!(user.memories.contains_key("fits"));
+        assert!(!user.memories.contains_key("short"));
+
+        drop(store);
+        remove_user_files(&user_id);
+    }
+}
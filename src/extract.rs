@@ -0,0 +1,141 @@
+use std::sync::Mutex;
+
+use log::*;
+use openai_api_rust::{ Message, Role };
+use serde::Deserialize;
+
+use crate::custom_types::{ ChatSession, MemoryRole, MemoryStore, MyChatbot };
+use crate::importance::Importance;
+use crate::params::GenerationParams;
+use crate::store::{ add_memory, store_memory, turn_metadata };
+use crate::structured::answer_json;
+use crate::utils::{ get_openai, load_environment };
+
+
+// One durable fact the chat model found in a user message.
+// importance is the model's own 0 to 10 grade, if it gave one
+#[derive(Clone, Debug, Deserialize)]
+pub struct ExtractedFact {
+    pub fact: String,
+    #[serde(default)]
+    pub category: Option<String>,
+    #[serde(default)]
+    pub importance: Option<f32>,
+}
+
+// What the model is asked to answer with. A bare list of facts is
+// accepted as well, smaller models like to leave the object out
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Extraction {
+    Object { facts: Vec<ExtractedFact> },
+    List(Vec<ExtractedFact>),
+}
+
+static EXTRACT_PROMPT: &str = "You pull durable facts about the user out of their message, for a long-term memory. \
+Only keep things worth knowing in a later conversation: who they are, their preferences, plans, people and things \
+in their life, numbers and dates that matter to them. Ignore greetings, thanks, small talk and questions. \
+Write every fact as a short sentence about \"the user\". Answer with JSON only, in this form: \
+{\"facts\": [{\"fact\": \"The user is allergic to peanuts\", \"category\": \"health\", \"importance\": 9}]} \
+where category is one word and importance goes from 0 to 10. If there is nothing worth remembering, answer {\"facts\": []}.";
+
+// Whether user messages go through extraction, from MEMORY_EXTRACT
+pub fn extraction_from_env() -> bool {
+    matches!(load_environment("MEMORY_EXTRACT").trim().to_lowercase().as_str(), "1" | "true" | "yes" | "facts")
+}
+
+// The facts in an answer, read the same way as structured output
+pub fn parse_facts(answer: &str) -> Option<Vec<ExtractedFact>> {
+    let value = match answer_json(answer) {
+        Ok(value) => value,
+        Err(e) => {
+            warn!("Fact extraction failed, {e}");
+            return None;
+        }
+    };
+    let facts = match serde_json::from_value::<Extraction>(value) {
+        Ok(Extraction::Object { facts }) | Ok(Extraction::List(facts)) => facts,
+        Err(e) => {
+            warn!("Fact extraction answer has no facts: {e}");
+            return None;
+        }
+    };
+
+    Some(facts.into_iter().filter(|fact| !fact.fact.trim().is_empty()).collect())
+}
+
+// Asks the chat model for the facts in one message. None means the
+// model could not be asked or gave no usable answer, an empty list that
+// there is nothing worth remembering
+pub fn extract_facts(message: &str) -> Option<Vec<ExtractedFact>> {
+    let oai = get_openai(&load_environment("URL"), &load_environment("LMS_API_KEY"));
+    let mut messages = vec![
+        Message { role: Role::System, content: EXTRACT_PROMPT.to_string() },
+        Message { role: Role::User, content: message.trim().to_string() },
+    ];
+
+    let answer = MyChatbot::new().generate_response(&mut messages, &oai, &GenerationParams::from_env()).0;
+    if answer.trim().is_empty() {
+        return None;
+    }
+    parse_facts(&answer)
+}
+
+// Remembers the facts of a user message instead of the message itself.
+// Every fact is linked to the message it came from through its turn.
+// If extraction fails, the message is stored as is so nothing is lost.
+// The extraction and any importance rating ask the chat model, so the
+// store is only locked to store each fact.
+// Returns how many facts were found
+pub fn remember_facts(message: &str, session: &ChatSession, store: &Mutex<MemoryStore>) -> usize {
+    let Some(facts) = extract_facts(message) else {
+        warn!("Fact extraction failed, remembering the message as is");
+        add_memory(message, MemoryRole::User, session, store);
+        return 0;
+    };
+
+    if facts.is_empty() {
+        info!("Nothing worth remembering in '{}'", message.trim());
+        return 0;
+    }
+
+    let template = turn_metadata(message, MemoryRole::User, session);
+    for fact in &facts {
+        let mut metadata = template.clone();
+        metadata.category = fact.category.as_ref().map(|c| c.trim().to_lowercase()).filter(|c| !c.is_empty());
+        metadata.importance = match fact.importance {
+            Some(grade) => Some(grade.clamp(0.0, 10.0) / 10.0),
+            None => Importance::from_env().rate(&fact.fact),
+        };
+
+        info!("Extracted fact from turn {:?}: {}", metadata.turn, fact.fact.trim());
+        store_memory(fact.fact.trim(), metadata, &mut store.lock().unwrap_or_else(|e| e.into_inner()));
+    }
+
+    facts.len()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn facts(answer: &str) -> Option<Vec<String>> {
+        parse_facts(answer).map(|facts| facts.into_iter().map(|f| f.fact).collect())
+    }
+
+    #[test]
+    fn facts_are_read_like_structured_answers() {
+        let expected = Some(vec!["The user likes tea".to_string()]);
+        assert_eq!(facts(r#"{"facts": [{"fact": "The user likes tea", "importance": 3}]}"#), expected);
+        assert_eq!(facts("```json\n{\"facts\": [{\"fact\": \"The user likes tea\"}]}\n```"), expected);
+        assert_eq!(facts(r#"[{"fact": "The user likes tea"}, {"fact": "  "}]"#), expected);
+        assert_eq!(facts(r#"{"facts": []}"#), Some(vec![]));
+    }
+
+    #[test]
+    fn an_answer_without_facts_is_not_an_empty_list() {
+        assert_eq!(facts("Nothing to remember."), None);
+        assert_eq!(facts(r#"{"memories": ["tea"]}"#), None);
+    }
+}
@@ -0,0 +1,118 @@
+use log::*;
+use openai_api_rust::{ Message, Role };
+
+use crate::custom_types::MyChatbot;
+use crate::params::GenerationParams;
+use crate::rerank::parse_grade;
+use crate::utils::{ get_openai, load_environment, search_terms, DEFAULT_IMPORTANCE };
+
+
+// How a new memory gets its importance, 0 for small talk up to 1 for
+// things that must never be forgotten. Heuristic looks at the words of
+// the memory, Chat asks the chat model, Off leaves memories unrated
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Importance {
+    #[default]
+    Heuristic,
+    Chat,
+    Off,
+}
+
+// Words that usually come with something worth keeping about the user
+static IMPORTANT_WORDS: &[&str] = &[
+    "remember", "important", "never", "always", "must", "name", "birthday",
+    "allergic", "allergy", "deadline", "appointment", "address", "password",
+    "prefer", "favorite", "favourite", "hate", "love", "married", "wife",
+    "husband", "daughter", "son", "job", "work", "live", "born",
+];
+
+// Words that usually make up small talk
+static TRIVIAL_WORDS: &[&str] = &[
+    "hi", "hello", "hey", "thanks", "thank", "ok", "okay", "bye", "lol", "yes", "no", "sure",
+];
+
+static CHAT_IMPORTANCE_PROMPT: &str = "You rate how important a message is to remember about the user who wrote it. \
+On a scale from 0 (mundane small talk, like a greeting) to 10 (life-changing or critical, like an allergy, \
+a death in the family or a hard deadline), answer with a single number, nothing else.";
+
+impl Importance {
+    pub fn parse(input: &str) -> Option<Self> {
+        match input.trim().to_lowercase().as_str() {
+            "" | "heuristic" => Some(Importance::Heuristic),
+            "chat" | "llm" => Some(Importance::Chat),
+            "off" | "none" => Some(Importance::Off),
+            _ => None
+        }
+    }
+
+    // The rating used for new memories, from MEMORY_IMPORTANCE
+    pub fn from_env() -> Self {
+        let value = load_environment("MEMORY_IMPORTANCE");
+        Importance::parse(&value).unwrap_or_else(|| {
+            warn!("Unknown MEMORY_IMPORTANCE '{}', rating memories by heuristic", value);
+            Importance::Heuristic
+        })
+    }
+
+    // The importance of a memory in 0..=1, None if memories are not rated.
+    // If the chat model gives no usable answer, the heuristic is used
+    pub fn rate(&self, text: &str) -> Option<f32> {
+        match self {
+            Importance::Heuristic => Some(rate_by_heuristic(text)),
+            Importance::Chat => Some(rate_with_chat(text).unwrap_or_else(|| {
+                warn!("Chat model gave no importance for '{}', using the heuristic", text.trim());
+                rate_by_heuristic(text)
+            })),
+            Importance::Off => None,
+        }
+    }
+}
+
+// Starts at DEFAULT_IMPORTANCE and moves up for words that hint at facts
+// about the user, numbers and longer statements, and down for small talk
+// and questions, which ask for something rather than tell it
+fn rate_by_heuristic(text: &str) -> f32 {
+    let terms = search_terms(text);
+    if terms.is_empty() {
+        return 0.0;
+    }
+
+    let mut importance = DEFAULT_IMPORTANCE;
+
+    let important = terms.iter().filter(|term| IMPORTANT_WORDS.contains(&term.as_str())).count();
+    importance += 0.15 * important.min(3) as f32;
+
+    let personal = terms.iter().any(|term| matches!(term.as_str(), "i" | "i'm" | "my" | "me" | "we" | "our"));
+    if personal {
+        importance += 0.1;
+    }
+
+    if terms.iter().any(|term| term.chars().any(|c| c.is_ascii_digit())) {
+        importance += 0.1;
+    }
+
+    if terms.len() >= 12 {
+        importance += 0.05;
+    }
+
+    if terms.iter().all(|term| TRIVIAL_WORDS.contains(&term.as_str())) {
+        importance = 0.05;
+    }
+
+    if text.trim_end().ends_with('?') {
+        importance -= 0.1;
+    }
+
+    importance.clamp(0.0, 1.0)
+}
+
+fn rate_with_chat(text: &str) -> Option<f32> {
+    let oai = get_openai(&load_environment("URL"), &load_environment("LMS_API_KEY"));
+    let mut messages = vec![
+        Message { role: Role::System, content: CHAT_IMPORTANCE_PROMPT.to_string() },
+        Message { role: Role::User, content: format!("Message: {}\nImportance:", text.trim()) },
+    ];
+
+    let answer = MyChatbot::new().generate_response(&mut messages, &oai, &GenerationParams::from_env()).0;
+    parse_grade(&answer)
+}
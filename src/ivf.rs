@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+
+use log::*;
+use serde::{ Deserialize, Serialize };
+
+use crate::kmeans::{ kmeans, nearest_centroid, nearest_centroids, sample_rows, SimpleRng };
+use crate::memory::normalized;
+use crate::utils::{ INDEX_TRAIN_SAMPLE, IVF_MAX_LIST_FACTOR, KMEANS_ITERATIONS };
+
+
+// An IVF-Flat index: k-means centroids split the collection into lists,
+// and a search only looks at the lists whose centroids are closest to
+// the query. The lists only hold keys, the vectors themselves stay in
+// the VectorStore and are scored exactly from there
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IvfFlatIndex {
+    pub dim: usize,
+    pub nprobe: usize,
+    pub centroids: Vec<Vec<f32>>,
+    pub lists: Vec<Vec<String>>,
+    pub assignments: HashMap<String, usize>,
+    pub trained_on: usize,
+}
+
+impl IvfFlatIndex {
+    pub fn train(vectors: &[Vec<f32>], nlist: usize, nprobe: usize) -> Result<Self, String> {
+        let Some(dim) = vectors.first().map(|v| v.len()) else {
+            return Err("there are no vectors to train on".to_string());
+        };
+        if nlist == 0 {
+            return Err("the number of lists must be at least 1".to_string());
+        }
+
+        let mut rng = SimpleRng::new(dim as u64);
+        let normalised: Vec<Vec<f32>> = vectors.iter().map(|v| normalized(v)).collect();
+        let sample = sample_rows(&normalised, INDEX_TRAIN_SAMPLE, &mut rng);
+        info!("Training IVF-Flat index with {} lists on {} of {} vectors", nlist, sample.len(), vectors.len());
+
+        let centroids = kmeans(&sample, nlist, KMEANS_ITERATIONS, &mut rng);
+        if centroids.is_empty() {
+            return Err("k-means found no centroids".to_string());
+        }
+
+        Ok(IvfFlatIndex {
+            dim,
+            nprobe: nprobe.clamp(1, centroids.len()),
+            lists: vec![vec![]; centroids.len()],
+            centroids,
+            assignments: HashMap::new(),
+            trained_on: vectors.len(),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.assignments.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.assignments.is_empty()
+    }
+
+    pub fn add(&mut self, key: &str, vector: &[f32]) {
+        if vector.len() != self.dim {
+            warn!("Not indexing '{}', its length {} doesn't match the index", key, vector.len());
+            return;
+        }
+        self.remove(key);
+
+        let (list, _) = nearest_centroid(&normalized(vector), &self.centroids);
+        self.lists[list].push(key.to_string());
+        self.assignments.insert(key.to_string(), list);
+    }
+
+    pub fn remove(&mut self, key: &str) -> bool {
+        let Some(list) = self.assignments.remove(key) else {
+            return false;
+        };
+
+        if let Some(position) = self.lists[list].iter().position(|k| k == key) {
+            self.lists[list].swap_remove(position);
+        }
+        true
+    }
+
+    // Every key in the nprobe lists closest to the query
+    pub fn candidates(&self, query: &[f32], nprobe: Option<usize>) -> Vec<String> {
+        if query.len() != self.dim {
+            return vec![];
+        }
+
+        let probes = nearest_centroids(&normalized(query), &self.centroids, nprobe.unwrap_or(self.nprobe).max(1));
+        probes.into_iter()
+            .flat_map(|list| self.lists[list].iter().cloned())
+            .collect()
+    }
+
+    // The centroids were trained on what the collection looked like back
+    // then. Once it doubled in size, or one list grew far bigger than the
+    // average, searches get slow or miss things, and it is time to retrain
+    pub fn needs_rebalance(&self) -> bool {
+        if self.len() >= self.trained_on.max(1) * 2 {
+            return true;
+        }
+
+        let average = self.len() as f32 / self.lists.len().max(1) as f32;
+        let largest = self.lists.iter().map(|l| l.len()).max().unwrap_or(0);
+        self.len() >= self.lists.len() * 4 && largest as f32 > average * IVF_MAX_LIST_FACTOR
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Points around four directions, so four lists split them cleanly
+    fn clustered() -> Vec<Vec<f32>> {
+        let directions = [[1.0, 0.0], [0.0, 1.0], [-1.0, 0.0], [0.0, -1.0]];
+        let mut vectors: Vec<Vec<f32>> = vec![];
+        for d in directions {
+            for i in 0..5 {
+                let jitter = i as f32 * 0.01;
+                vectors.push(vec![d[0] + jitter, d[1] + jitter]);
+            }
+        }
+        vectors
+    }
+
+    fn built(data: &[Vec<f32>], nlist: usize, nprobe: usize) -> IvfFlatIndex {
+        let mut index = IvfFlatIndex::train(data, nlist, nprobe).unwrap();
+        for (i, v) in data.iter().enumerate() {
+            index.add(&format!("k{}", i), v);
+        }
+        index
+    }
+
+    #[test]
+    fn train_rejects_empty_input_and_zero_lists() {
+        assert!(IvfFlatIndex::train(&[], 4, 1).is_err());
+        assert!(IvfFlatIndex::train(&clustered(), 0, 1).is_err());
+    }
+
+    #[test]
+    fn train_clamps_lists_and_nprobe() {
+        let data = vec![vec![1.0, 0.0], vec![0.0, 1.0]];
+        let index = IvfFlatIndex::train(&data, 10, 0).unwrap();
+        assert_eq!(index.centroids.len(), 2);
+        assert_eq!(index.nprobe, 1);
+    }
+
+    #[test]
+    fn add_puts_every_key_in_one_list() {
+        let data = clustered();
+        let mut index = built(&data, 4, 1);
+
+        assert_eq!(index.len(), 20);
+        assert_eq!(index.lists.iter().map(|l| l.len()).sum::<usize>(), 20);
+
+        // Adding a key again moves it instead of listing it twice
+        index.add("k0", &[-1.0, 0.0]);
+        assert_eq!(index.len(), 20);
+        assert_eq!(index.lists.iter().map(|l| l.len()).sum::<usize>(), 20);
+        assert_eq!(index.assignments["k0"], index.assignments["k10"]);
+
+        index.add("short", &[1.0]);
+        assert_eq!(index.len(), 20);
+    }
+
+    #[test]
+    fn candidates_come_from_the_closest_lists() {
+        let data = clustered();
+        let index = built(&data, 4, 1);
+
+        let mut candidates = index.candidates(&[0.0, 1.0], None);
+        candidates.sort();
+        let mut expected: Vec<String> = (5..10).map(|i| format!("k{}", i)).collect();
+        expected.sort();
+        assert_eq!(candidates, expected);
+
+        assert_eq!(index.candidates(&[0.0, 1.0], Some(4)).len(), 20);
+        assert!(index.candidates(&[0.0, 1.0, 0.0], None).is_empty());
+    }
+
+    #[test]
+    fn remove_takes_the_key_out_of_its_list() {
+        let data = clustered();
+        let mut index = built(&data, 4, 1);
+
+        assert!(index.remove("k6"));
+        assert!(!index.remove("k6"));
+        assert_eq!(index.len(), 19);
+        assert!(!index.candidates(&[0.0, 1.0], Some(4)).contains(&"k6".to_string()));
+    }
+
+    #[test]
+    fn rebalance_once_the_collection_doubled() {
+        let data = clustered();
+        let mut index = built(&data, 4, 1);
+        assert!(!index.needs_rebalance());
+
+        for (i, v) in data.iter().enumerate() {
+            index.add(&format!("extra{}", i), v);
+        }
+        assert!(index.needs_rebalance());
+    }
+}
@@ -0,0 +1,240 @@
+use log::*;
+
+
+// A tiny deterministic random number generator (splitmix64). Training the
+// same data twice gives the same centroids, which makes indexes reproducible
+pub struct SimpleRng(u64);
+
+impl SimpleRng {
+    pub fn new(seed: u64) -> Self {
+        SimpleRng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // A number in 0..n
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n.max(1) as u64) as usize
+    }
+
+    // A number in 0.0..1.0
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+pub fn squared_l2(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b.iter()).map(|(x, y)| (x - y) * (x - y)).sum()
+}
+
+// Index and squared distance of the centroid closest to v
+pub fn nearest_centroid(v: &[f32], centroids: &[Vec<f32>]) -> (usize, f32) {
+    let mut best = (0, f32::INFINITY);
+    for (i, c) in centroids.iter().enumerate() {
+        let d = squared_l2(v, c);
+        if d < best.1 {
+            best = (i, d);
+        }
+    }
+    best
+}
+
+// Indexes of the n centroids closest to v, closest first
+pub fn nearest_centroids(v: &[f32], centroids: &[Vec<f32>], n: usize) -> Vec<usize> {
+    let mut distances: Vec<(usize, f32)> = centroids.iter()
+        .enumerate()
+        .map(|(i, c)| (i, squared_l2(v, c)))
+        .collect();
+
+    distances.sort_by(|a, b| a.1.total_cmp(&b.1));
+    distances.into_iter().take(n).map(|(i, _)| i).collect()
+}
+
+// Picks up to n of the rows at random, used to train on a sample
+// instead of the whole collection
+pub fn sample_rows<'a>(rows: &'a [Vec<f32>], n: usize, rng: &mut SimpleRng) -> Vec<&'a Vec<f32>> {
+    if rows.len() <= n {
+        return rows.iter().collect();
+    }
+
+    // Partial Fisher-Yates shuffle over the row indexes
+    let mut indexes: Vec<usize> = (0..rows.len()).collect();
+    for i in 0..n {
+        let j = i + rng.below(rows.len() - i);
+        indexes.swap(i, j);
+    }
+
+    indexes[..n].iter().map(|i| &rows[*i]).collect()
+}
+
+// k-means++ seeding: the first centroid is a random point, every next one
+// is picked with a probability proportional to its squared distance from
+// the closest centroid chosen so far
+fn kmeans_plus_plus(data: &[&Vec<f32>], k: usize, rng: &mut SimpleRng) -> Vec<Vec<f32>> {
+    let mut centroids: Vec<Vec<f32>> = vec![data[rng.below(data.len())].clone()];
+    let mut distances: Vec<f32> = data.iter().map(|v| squared_l2(v, &centroids[0])).collect();
+
+    while centroids.len() < k {
+        let total: f32 = distances.iter().sum();
+        let next = if total <= 0.0 {
+            rng.below(data.len())
+        } else {
+            let mut target = rng.next_f32() * total;
+            let mut chosen = data.len() - 1;
+            for (i, d) in distances.iter().enumerate() {
+                target -= d;
+                if target <= 0.0 {
+                    chosen = i;
+                    break;
+                }
+            }
+            chosen
+        };
+
+        centroids.push(data[next].clone());
+        let newest = centroids.last().unwrap();
+        for (i, v) in data.iter().enumerate() {
+            distances[i] = distances[i].min(squared_l2(v, newest));
+        }
+    }
+
+    centroids
+}
+
+// Lloyd's k-means. Returns at most k centroids (fewer if there are fewer
+// points than k), and stops early once no point changes its cluster.
+// A cluster that ends up empty is moved onto a random point
+pub fn kmeans(data: &[&Vec<f32>], k: usize, iterations: usize, rng: &mut SimpleRng) -> Vec<Vec<f32>> {
+    let k = k.min(data.len());
+    if k == 0 {
+        return vec![];
+    }
+
+    let dim = data[0].len();
+    let mut centroids = kmeans_plus_plus(data, k, rng);
+    let mut assignment: Vec<usize> = vec![usize::MAX; data.len()];
+
+    for iteration in 0..iterations {
+        let mut changed: usize = 0;
+        for (i, v) in data.iter().enumerate() {
+            let (nearest, _) = nearest_centroid(v, &centroids);
+            if assignment[i] != nearest {
+                assignment[i] = nearest;
+                changed += 1;
+            }
+        }
+
+        if changed == 0 {
+            debug!("k-means converged after {} iterations", iteration);
+            break;
+        }
+
+        let mut sums: Vec<Vec<f32>> = vec![vec![0.0; dim]; k];
+        let mut counts: Vec<usize> = vec![0; k];
+        for (i, v) in data.iter().enumerate() {
+            counts[assignment[i]] += 1;
+            for (s, x) in sums[assignment[i]].iter_mut().zip(v.iter()) {
+                *s += x;
+            }
+        }
+
+        for (c, (sum, count)) in sums.into_iter().zip(counts).enumerate() {
+            centroids[c] = if count == 0 {
+                data[rng.below(data.len())].clone()
+            } else {
+                sum.into_iter().map(|s| s / count as f32).collect()
+            };
+        }
+    }
+
+    centroids
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two tight groups of points, around (0, 0) and around (10, 10)
+    fn two_clusters() -> Vec<Vec<f32>> {
+        vec![
+            vec![0.0, 0.0], vec![0.1, 0.0], vec![0.0, 0.1], vec![0.1, 0.1],
+            vec![10.0, 10.0], vec![10.1, 10.0], vec![10.0, 10.1], vec![10.1, 10.1],
+        ]
+    }
+
+    #[test]
+    fn kmeans_plus_plus_seeds_each_cluster() {
+        let data = two_clusters();
+        let refs: Vec<&Vec<f32>> = data.iter().collect();
+
+        for seed in 0..20 {
+            let centroids = kmeans_plus_plus(&refs, 2, &mut SimpleRng::new(seed));
+            assert_eq!(centroids.len(), 2);
+            // The second seed is far more likely in the other cluster, and
+            // with a zero distance it can never be the same point again
+            assert!(squared_l2(&centroids[0], &centroids[1]) > 0.0);
+        }
+    }
+
+    #[test]
+    fn kmeans_finds_cluster_centres() {
+        let data = two_clusters();
+        let refs: Vec<&Vec<f32>> = data.iter().collect();
+
+        let mut centroids = kmeans(&refs, 2, 20, &mut SimpleRng::new(7));
+        centroids.sort_by(|a, b| a[0].total_cmp(&b[0]));
+
+        assert!(squared_l2(&centroids[0], &[0.05, 0.05]) < 1e-6);
+        assert!(squared_l2(&centroids[1], &[10.05, 10.05]) < 1e-6);
+    }
+
+    #[test]
+    fn kmeans_is_deterministic() {
+        let data = two_clusters();
+        let refs: Vec<&Vec<f32>> = data.iter().collect();
+
+        let a = kmeans(&refs, 3, 10, &mut SimpleRng::new(42));
+        let b = kmeans(&refs, 3, 10, &mut SimpleRng::new(42));
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn kmeans_handles_empty_input_and_small_k() {
+        let empty: Vec<&Vec<f32>> = vec![];
+        assert!(kmeans(&empty, 4, 10, &mut SimpleRng::new(1)).is_empty());
+
+        let data = two_clusters();
+        let refs: Vec<&Vec<f32>> = data.iter().collect();
+        assert!(kmeans(&refs, 0, 10, &mut SimpleRng::new(1)).is_empty());
+        assert_eq!(kmeans(&refs, 100, 10, &mut SimpleRng::new(1)).len(), data.len());
+    }
+
+    #[test]
+    fn nearest_centroids_are_sorted() {
+        let centroids = vec![vec![5.0], vec![1.0], vec![3.0]];
+        assert_eq!(nearest_centroid(&[2.9], &centroids).0, 2);
+        assert_eq!(nearest_centroids(&[0.0], &centroids, 2), vec![1, 2]);
+        assert_eq!(nearest_centroids(&[0.0], &centroids, 10), vec![1, 2, 0]);
+    }
+
+    #[test]
+    fn sample_rows_picks_distinct_rows() {
+        let data: Vec<Vec<f32>> = (0..50).map(|i| vec![i as f32]).collect();
+        let sample = sample_rows(&data, 10, &mut SimpleRng::new(3));
+        assert_eq!(sample.len(), 10);
+
+        let mut values: Vec<i32> = sample.iter().map(|v| v[0] as i32).collect();
+        values.sort();
+        values.dedup();
+        assert_eq!(values.len(), 10);
+
+        assert_eq!(sample_rows(&data, 100, &mut SimpleRng::new(3)).len(), 50);
+    }
+}
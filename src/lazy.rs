@@ -0,0 +1,146 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use log::*;
+use serde::de::DeserializeOwned;
+use serde::{ Deserialize, Deserializer, Serialize, Serializer };
+
+use crate::wal::write_atomic;
+
+
+// Decides whether a value read from its file can be used, the error
+// says why not
+type Check<T> = Box<dyn Fn(&T) -> Result<(), String> + Send + Sync>;
+
+// A value that is read from its JSON file the first time it is used.
+// Memory-mapped collections keep their texts, metadata, keys and indexes
+// in files of their own, so loading a user only reads a small memory file
+// and maps the vector segments. Whatever a search or insert needs is
+// read when it first needs it.
+// Anything that isn't backed by a file is simply already loaded
+pub struct Lazy<T> {
+    value: OnceLock<T>,
+    source: Option<PathBuf>,
+    check: Option<Check<T>>,
+}
+
+impl<T: DeserializeOwned + Default> Lazy<T> {
+    pub fn from_file(path: &Path) -> Self {
+        Lazy { value: OnceLock::new(), source: Some(path.to_path_buf()), check: None }
+    }
+
+    pub fn with_check(mut self, check: impl Fn(&T) -> Result<(), String> + Send + Sync + 'static) -> Self {
+        self.check = Some(Box::new(check));
+        self
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.value.get().is_some()
+    }
+
+    // The file this value came from, if it hasn't been loaded yet
+    pub fn pending_source(&self) -> Option<&Path> {
+        if self.is_loaded() { None } else { self.source.as_deref() }
+    }
+
+    // A missing file means nothing was saved yet, a broken one is
+    // reported and treated the same, like a broken memory file
+    fn load(&self) -> &T {
+        self.value.get_or_init(|| {
+            let Some(path) = &self.source else {
+                return T::default();
+            };
+
+            let value: T = match fs::read_to_string(path) {
+                Ok(contents) => match serde_json::from_str(&contents) {
+                    Ok(value) => value,
+                    Err(e) => {
+                        error!("Failed to parse {}: {e}", path.display());
+                        return T::default();
+                    }
+                },
+                Err(e) => {
+                    info!("Nothing to load from {} ({})", path.display(), e);
+                    return T::default();
+                }
+            };
+
+            if let Some(check) = &self.check
+                && let Err(e) = check(&value) {
+                error!("Ignoring {}, {e}", path.display());
+                return T::default();
+            }
+            value
+        })
+    }
+}
+
+impl<T: Serialize + DeserializeOwned + Default> Lazy<T> {
+    // Writes the value to path. One that was never loaded is still
+    // exactly what its file holds, so the file is copied instead, and
+    // the value is read from the copy from then on
+    pub fn save_to(&mut self, path: &Path) -> std::io::Result<()> {
+        if let Some(source) = self.pending_source() {
+            if source == path {
+                return Ok(());
+            }
+            if fs::copy(source, path).is_ok() {
+                self.source = Some(path.to_path_buf());
+                return Ok(());
+            }
+        }
+
+        let json = serde_json::to_string(self)?;
+        write_atomic(path, json.as_bytes())
+    }
+}
+
+impl<T: DeserializeOwned + Default> std::ops::Deref for Lazy<T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        self.load()
+    }
+}
+
+impl<T: DeserializeOwned + Default> std::ops::DerefMut for Lazy<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.load();
+        self.value.get_mut().unwrap()
+    }
+}
+
+impl<T> From<T> for Lazy<T> {
+    fn from(value: T) -> Self {
+        Lazy { value: OnceLock::from(value), source: None, check: None }
+    }
+}
+
+impl<T: Default> Default for Lazy<T> {
+    fn default() -> Self {
+        Lazy::from(T::default())
+    }
+}
+
+impl<T: std::fmt::Debug> std::fmt::Debug for Lazy<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.value.get(), &self.source) {
+            (Some(value), _) => value.fmt(f),
+            (None, Some(path)) => write!(f, "<not loaded from {}>", path.display()),
+            (None, None) => write!(f, "<empty>"),
+        }
+    }
+}
+
+// Serialising always writes the full value, loading it first if needed
+impl<T: Serialize + DeserializeOwned + Default> Serialize for Lazy<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.load().serialize(serializer)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Lazy<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(Lazy::from)
+    }
+}
@@ -2,13 +2,14 @@ use log::{self, info};
 
 use openai_api_rust::completions::Completion;
 use openai_api_rust::*;
-use openai_api_rust::chat::*;
 
 use crate::custom_types::{ MyChatBody, MyChatbot };
-use crate::utils::{ CHAT_MODEL };
+use crate::params::GenerationParams;
+use crate::usage::record_usage;
 
 static MY_ZERO: Option<u32> = Some(0);
 static MY_STR: &str = "";
+static CHAT_COMPLETIONS: &str = "chat/completions";
 
 
 impl MyChatbot {
@@ -16,19 +17,27 @@ impl MyChatbot {
         MyChatbot
     }
 
-    pub fn generate_response(self, messages: &mut Vec<Message>, openai: &OpenAI) -> (String, Option<u32>, Option<u32>) {
+    // Sends the messages with the given model and sampling, and returns
+    // the first answer with its prompt and completion tokens
+    pub fn generate_response(self, messages: &mut Vec<Message>, openai: &OpenAI, params: &GenerationParams) -> (String, Option<u32>, Option<u32>) {
+        let (choices, user_usage, agent_usage) = self.generate_choices(messages, openai, params);
+        match choices.into_iter().next() {
+            Some(first) => (first, user_usage, agent_usage),
+            None => (MY_STR.to_string(), MY_ZERO, MY_ZERO)
+        }
+    }
+
+    // Like generate_response, but returns every answer when params.n
+    // asks for more than one
+    pub fn generate_choices(self, messages: &[Message], openai: &OpenAI, params: &GenerationParams) -> (Vec<String>, Option<u32>, Option<u32>) {
         let oai = openai.clone();
-        let max_tokens: i32 = 512;
-        let temperature: f32 = 0.3;
 
         info!("Creating ChatBdy...");
-        let body = MyChatBody::new(CHAT_MODEL, messages.to_vec())
-            .with_max_tokens(max_tokens)
-            .with_temperature(temperature);
+        let body = params.chat_body(messages.to_vec());
         info!("Created ChatBody");
             
         info!("Trying to get result");
-        let result = match oai.chat_completion_create(&body) {
+        let result = match post_chat(&oai, &body) {
             Ok(res) => {
                 info!("Completion successfully generate");
                 res
@@ -50,19 +59,36 @@ impl MyChatbot {
             }
         };
 
-        if result.choices.len() == 0 { return (MY_STR.to_string(), MY_ZERO, MY_ZERO); }
+        if result.choices.is_empty() { return (vec![], MY_ZERO, MY_ZERO); }
 
-        let choices = &result.choices;
-        let message = choices[0].message.as_ref().unwrap();
-        let true_msg = message.content.clone();
+        let answers: Vec<String> = result.choices.iter()
+            .filter_map(|choice| choice.message.as_ref().map(|m| m.content.clone()))
+            .collect();
 
         let usage = &result.usage;
         let user_usage: Option<u32> = usage.prompt_tokens;
         let agent_usage: Option<u32> = usage.completion_tokens;
+        record_usage(&params.model, user_usage, agent_usage);
 
-        //println!("\n\nMessage: {}", true_msg);
         //println!("User Usage: {:#?}\nAgent Usage: {:#?}", user_usage, agent_usage);
-        return (true_msg, user_usage, agent_usage);
-
+        (answers, user_usage, agent_usage)
     }
-}
\ No newline at end of file
+}
+
+// The library only sends the fields ChatBody has, so the request is
+// posted here to include the seed and response format as well
+fn post_chat(oai: &OpenAI, body: &MyChatBody) -> Result<Completion, String> {
+    serde_json::from_value(post_chat_json(oai, body)?).map_err(|e| e.to_string())
+}
+
+// The completion as the server sent it. Answers with tool calls have
+// no content, Completion can't read them
+pub fn post_chat_json(oai: &OpenAI, body: &MyChatBody) -> Result<serde_json::Value, String> {
+    let url = format!("{}{}", oai.api_url, CHAT_COMPLETIONS);
+    ureq::post(&url)
+        .set("Authorization", &format!("Bearer {}", oai.auth.api_key))
+        .send_json(body.to_json())
+        .map_err(|e| e.to_string())?
+        .into_json::<serde_json::Value>()
+        .map_err(|e| e.to_string())
+}
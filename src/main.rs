@@ -1,73 +1,165 @@
+pub mod bm25;
+pub mod capture;
+pub mod commands;
+pub mod consolidate;
+pub mod conversations;
 pub mod custom_types;
+pub mod dedup;
+pub mod export;
+pub mod extract;
+pub mod importance;
+pub mod ivf;
+pub mod kmeans;
+pub mod lazy;
 pub mod llm;
 pub mod memory;
+pub mod params;
+pub mod pq;
+pub mod prompt;
+pub mod rerank;
+pub mod scan;
+pub mod search;
+pub mod segments;
+pub mod session;
+pub mod snapshot;
 pub mod store;
+pub mod structured;
 pub mod summary;
+pub mod tools;
+pub mod transcript;
+pub mod usage;
 pub mod utils;
+pub mod vectors;
+pub mod wal;
 
 use std::collections::{HashMap};
 use std::io::Write;
 use std::sync::atomic::{ AtomicBool, Ordering };
-use std::sync::Arc;
+use std::sync::{ Arc, Mutex };
 
 use dotenv::dotenv;
 use log::{self, info};
 use openai_api_rust::{Message, OpenAI, Role};
 
-use crate::custom_types::{ MyChatbot };
-use crate::utils::{ get_openai, init_logger, load_environment, load_sysprompt };
-use crate::store::add_memory;
+use crate::capture::CapturePolicy;
+use crate::commands::handle_store_command;
+use crate::consolidate::spawn_consolidation;
+use crate::conversations::{ handle_conversation_command, save_conversation };
+use crate::custom_types::{ ChatSession, MemoryRole, MemoryStore, MyChatbot };
+use crate::extract::{ extraction_from_env, remember_facts };
+use crate::params::{ handle_params_command, GenerationParams };
+use crate::prompt::{ handle_profile_command, PromptVars, SystemPrompts };
+use crate::search::{ SearchOptions, SearchResult };
+use crate::usage::{ handle_usage_command, save_usage, take_turn_usage, total_usage, Budget };
+use crate::utils::{ get_openai, init_logger, load_environment, load_sysprompt, now_unix };
+use crate::store::{ add_memory, retrieve_memory };
+use crate::structured::handle_structured_command;
+use crate::tools::{ tools_from_env, ToolContext, ToolRegistry };
 
 
+// Asks who is chatting, unless USER_ID is already set in the environment
+fn read_user_id() -> String {
+    let from_env = load_environment("USER_ID");
+    if !from_env.trim().is_empty() {
+        return from_env.trim().to_string();
+    }
+
+    print!("Enter your user name: ");
+    let _ = std::io::stdout().flush();
+
+    let mut name = String::new();
+    if let Err(e) = std::io::stdin().read_line(&mut name) {
+        log::error!("Failed to read user name due to error: {e}");
+    }
+
+    match name.trim() {
+        "" => "default".to_string(),
+        n => n.to_string()
+    }
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    dotenv().ok();
+    match init_logger() {
+        Ok(_) => (),
+        Err(e) => println!("AN ERROR PREVENTED LOG INITIALIZATION: {e}")
+    }
+
+    // The store is shared with the Ctrl+C handler, which takes the lock
+    // before flushing. If the main thread is in the middle of writing a
+    // memory, the handler waits until that write is finished
+    let store: Arc<Mutex<MemoryStore>> = Arc::new(Mutex::new(MemoryStore::new()));
     let running = Arc::new(AtomicBool::new(true));
     let r = running.clone();
+    let s = store.clone();
 
     ctrlc::set_handler(move || {
         log::error!("\nReceived Ctrl+C, exiting...");
         r.store(false, Ordering::SeqCst);
-        std::process::exit(1);
+        s.lock().unwrap_or_else(|e| e.into_inner()).shutdown();
+        save_usage();
+        std::process::exit(0);
     }).expect("Error setting Ctrl+C handler");
 
-    dotenv().ok();
-    match init_logger() {
-        Ok(_) => (),
-        Err(e) => println!("AN ERROR PREVENTED LOG INITIALIZATION: {e}")
-    }
-
     let url = load_environment("URL");
     let api_key = load_environment("LMS_API_KEY");
     let oai: OpenAI = get_openai(&url, &api_key);
 
     let cb = MyChatbot::new();
-    let system_prompt = load_sysprompt();
-    let mut messages: Vec<Message> = vec![
-        Message {
-            role: Role::System,
-            content: match system_prompt {
-                Ok(p) if !p.is_empty() => p,
-                Ok(_) => {
-                    info!("System prompt was successfully loaded, but empty");
-                    "".to_string()
-                }
-                Err(_) => {
-                    log::warn!("System Prompt was empty, resuming with an empty one");
-                    "".to_string()
-                }
-            }
+    let system_prompt: String = match load_sysprompt() {
+        Ok(p) if !p.is_empty() => p,
+        Ok(_) => {
+            info!("System prompt was successfully loaded, but empty");
+            "".to_string()
+        }
+        Err(_) => {
+            log::warn!("System Prompt was empty, resuming with an empty one");
+            "".to_string()
         }
-    ];
+    };
+
+    // The system prompt is a template, filled in for every message. It
+    // comes from the profile in use, and is read again when its file changes
+    let mut prompts = SystemPrompts::from_env(&system_prompt);
+
+    // Old memories are folded into summaries in the background, if
+    // CONSOLIDATE_EVERY_MINUTES asks for it
+    let _consolidation = spawn_consolidation(store.clone());
+
+    // How memories are searched for every chat message, and which
+    // parts of the conversation are remembered
+    let search_options = SearchOptions::from_env();
+    let capture = CapturePolicy::from_env();
+    let extract_facts = extraction_from_env();
+    let budget = Budget::from_env();
+
+    // The model and sampling of every answer, "/model" and "/params"
+    // change them for the rest of the run
+    let mut params = GenerationParams::from_env();
+
+    // With MEMORY_TOOLS set, the model can search, save and forget
+    // memories itself while it answers
+    let tools: Option<ToolRegistry> = tools_from_env().then(ToolRegistry::builtin);
+
+    // Every user gets their own memories and their own chat session,
+    // "/user <name>" switches between them
+    let mut sessions: HashMap<String, ChatSession> = HashMap::new();
+    let mut current_user: String = read_user_id();
+    store.lock().unwrap_or_else(|e| e.into_inner()).user_mut(&current_user);
+
     let mut input: String = String::new();
-    let mut memories: HashMap<String, Vec<f64>> = HashMap::new();
-    let mut memories_text: HashMap<String, String> = HashMap::new();
-    let mut count: usize = 0;
 
     // Start of chatbot operations
     while running.load(Ordering::SeqCst) {
-        print!("Enter your message here: ");
+        input.clear();
+        print!("[{}] Enter your message here: ", current_user);
         let _ = std::io::stdout().flush();
 
         match std::io::stdin().read_line(&mut input) {
+            Ok(0) => {
+                log::info!("Reached end of input, exiting...");
+                break;
+            }
             Ok(_) => {
                 log::info!("Line read was successful");
             }
@@ -76,33 +168,142 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
         }
 
-        if !input.is_empty() {
-            messages.push(
-                Message { 
-                    role: Role::User, 
-                    content: input.to_string()
+        if input.trim().is_empty() {
+            continue;
+        }
+
+        if prompts.reload_if_changed() {
+            info!("System prompt profile '{}' changed, reloaded it", prompts.profile());
+        }
+
+        if let Some(name) = input.trim().strip_prefix("/user ") {
+            current_user = name.trim().to_string();
+            store.lock().unwrap_or_else(|e| e.into_inner()).user_mut(&current_user);
+            info!("Switched to user '{}'", current_user);
+            continue;
+        }
+
+        if handle_conversation_command(&input, &current_user, &mut sessions, prompts.template()) {
+            continue;
+        }
+
+        if handle_usage_command(&input, sessions.get(&current_user), &budget) {
+            continue;
+        }
+
+        if handle_params_command(&input, &mut params) {
+            continue;
+        }
+
+        if handle_profile_command(&input, &mut prompts) {
+            continue;
+        }
+
+        let vars = PromptVars { user_name: &current_user, memories: &[], summary: None, model: &params.model, now: now_unix() };
+        if handle_structured_command(&input, &prompts, &vars, &oai, &params) {
+            continue;
+        }
+
+        if input.trim().starts_with('/') {
+            let mut locked_store = store.lock().unwrap_or_else(|e| e.into_inner());
+            if !handle_store_command(&input, &current_user, &mut locked_store) {
+                println!("Unknown command: {}", input.trim());
+            }
+            continue;
+        }
+
+        let session = sessions
+            .entry(current_user.clone())
+            .or_insert_with(|| {
+                let session = ChatSession::new(&current_user, prompts.template());
+                // The system prompt is the same every time, dedup keeps
+                // it from being stored more than once. A templated one is
+                // not, its placeholders are filled in anew for every turn
+                if capture.captures(MemoryRole::System) && !prompts.template().trim().is_empty() && !prompts.is_templated() {
+                    add_memory(prompts.template(), MemoryRole::System, &session, &store);
                 }
-            );
-            add_memory(&input, &mut memories, &mut count, &mut memories_text);
-        }
-        
-        let resp: (String, Option<u32>, Option<u32>) = cb.clone().generate_response(&mut messages.clone(), &oai);
-        messages.push(
-            Message { 
-                role: Role::Assistant, 
-                content: resp.0 
+                session
+            });
+
+        // Budgets are checked before anything is sent. Whatever commands
+        // used since the last turn is in the totals, but not in this turn
+        let reached = budget.exceeded(&session.usage(), &total_usage());
+        if !reached.is_empty() {
+            log::warn!("Token budget reached: {}", reached.join(", "));
+            if budget.stop {
+                println!("Budget reached ({}), not sending the message", reached.join(", "));
+                continue;
+            }
+        }
+        take_turn_usage();
+
+        // Memories are retrieved before the new input is stored, otherwise
+        // the best match would always be the message we just typed
+        let retrieved: Vec<SearchResult> = retrieve_memory(&store, &session.user_id, &input, &search_options)
+            .unwrap_or_default();
+
+        session.messages.push(
+            Message {
+                role: Role::User,
+                content: input.to_string()
             }
         );
-        println!("{:#?}", messages);
-        
+        if capture.captures(MemoryRole::User) {
+            if extract_facts {
+                remember_facts(&input, session, &store);
+            } else {
+                add_memory(&input, MemoryRole::User, session, &store);
+            }
+        }
+
+        session.set_system_prompt(prompts.template());
+        let mut prompt: Vec<Message> = session.prompt_with_template(&prompts, &retrieved, &params.model);
+        let resp: (String, Option<u32>, Option<u32>) = match &tools {
+            Some(tools) => {
+                let ctx = ToolContext { store: &store, session, search_options: &search_options, capture };
+                cb.clone().generate_with_tools(&prompt, &oai, &params, tools, &ctx)
+            }
+            None => cb.clone().generate_response(&mut prompt, &oai, &params)
+        };
+        if capture.captures(MemoryRole::Assistant) && !resp.0.trim().is_empty() {
+            add_memory(&resp.0, MemoryRole::Assistant, session, &store);
+        }
+        let memory_count: usize = store.lock().unwrap_or_else(|e| e.into_inner())
+            .user(&current_user)
+            .map_or(0, |u| u.memories.len());
+        println!("\n{}\n", resp.0.trim());
+        session.messages.push(
+            Message {
+                role: Role::Assistant,
+                content: resp.0
+            }
+        );
+        session.record_turn(&retrieved, resp.1, resp.2, take_turn_usage(), now_unix());
+        if let Err(e) = save_conversation(session) {
+            log::error!("Failed to save conversation '{}': {e}", session.session_id);
+        }
+
+        // The memories the answer was given, so it can be traced back
+        for result in &retrieved {
+            println!("Remembered [{}] ({:.4}): {}", result.id, result.final_score(), result.text);
+        }
+
         /*for item in text_to_vec(&input) {
             println!("{}", item);
             std::io::stdout().flush().unwrap();
         }*/
-        println!("Length of messages: {}", messages.len());
-        println!("Length of memories: {}", memories.len());
+        println!("Length of messages: {}", session.messages.len());
+        println!("Length of memories: {}", memory_count);
+    }
+
+    for session in sessions.values_mut() {
+        if let Err(e) = save_conversation(session) {
+            log::error!("Failed to save conversation '{}': {e}", session.session_id);
+        }
     }
+    store.lock().unwrap_or_else(|e| e.into_inner()).shutdown();
+    save_usage();
 
     Ok(())
     // End of Chatbot operations
-}
\ No newline at end of file
+}
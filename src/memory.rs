@@ -2,8 +2,9 @@ use openai_api_rust::embeddings::{Embeddings, EmbeddingsApi};
 use openai_api_rust::OpenAI;
 
 use ndarray::{self, Array1, ArrayD, Axis};
-use ndarray_linalg::{self, Norm};
 
+use crate::scan::{ dot, norm };
+use crate::usage::record_usage;
 use crate::utils::{ load_environment, get_openai, EMBED_MODEL };
 use crate::custom_types::MyEmbeddingBody;
 
@@ -22,10 +23,12 @@ pub fn make_vector(input: &ArrayD<f64>) -> Option<Array1<f64>> {
     }
 }
 
-pub fn cosine_similarity(a: &Array1<f64>, b: &Array1<f64>) -> f64 {   
-    let dot_product: f64 = a.dot(b);
-    let norm_a: f64 = a.norm_l2();
-    let norm_b: f64 = b.norm_l2();
+// The dot product and the norms are summed up by hand instead of using
+// ndarray's dot(), which would need a BLAS library to link against
+pub fn cosine_similarity(a: &Array1<f64>, b: &Array1<f64>) -> f64 {
+    let dot_product: f64 = a.iter().zip(b.iter()).map(|(x, y)| x * y).sum();
+    let norm_a: f64 = a.iter().map(|x| x * x).sum::<f64>().sqrt();
+    let norm_b: f64 = b.iter().map(|x| x * x).sum::<f64>().sqrt();
 
     if norm_a == 0.0 || norm_b == 0.0 {
         0.0
@@ -34,6 +37,29 @@ pub fn cosine_similarity(a: &Array1<f64>, b: &Array1<f64>) -> f64 {
     }
 }
 
+// The same as cosine_similarity, for plain f32 slices as they are
+// stored in a VectorStore
+pub fn cosine_similarity_slices(a: &[f32], b: &[f32]) -> f32 {
+    let dot_product = dot(a, b);
+    let norm_a = norm(a);
+    let norm_b = norm(b);
+
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot_product / (norm_a * norm_b)
+    }
+}
+
+// Returns v scaled to length 1, a zero vector is returned as it is
+pub fn normalized(v: &[f32]) -> Vec<f32> {
+    let norm: f32 = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm == 0.0 {
+        return v.to_vec();
+    }
+    v.iter().map(|x| x / norm).collect()
+}
+
 pub fn generate_embeddings(input: Vec<String>) -> Option<Embeddings> {
     let oai: OpenAI = get_openai(&load_environment("URL"), &load_environment("LMS_API_KEY"));
     let model = EMBED_MODEL;
@@ -43,6 +69,7 @@ pub fn generate_embeddings(input: Vec<String>) -> Option<Embeddings> {
     
     let result: Option<Embeddings> = match emb {
         Ok(emb) => {
+            record_usage(model, emb.usage.prompt_tokens, None);
             Some(emb)
         }
         Err(e) => {
@@ -0,0 +1,390 @@
+use std::fs;
+
+use log::*;
+use serde_json::json;
+
+use crate::custom_types::MyChatBody;
+use crate::utils::{ load_environment, CHAT_MODEL };
+use openai_api_rust::{ Message, Role };
+
+static DEFAULT_MAX_TOKENS: i32 = 512;
+static DEFAULT_TEMPERATURE: f32 = 0.3;
+
+
+// What the answer has to look like. JsonSchema asks servers that support
+// it to keep to the schema, JsonObject only for any valid JSON. Either
+// is also spelled out in the prompt, for servers that don't
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum ResponseFormat {
+    #[default]
+    Text,
+    JsonObject,
+    JsonSchema { name: String, schema: serde_json::Value },
+}
+
+// How an answer is generated: the model and its sampling. The chat loop
+// keeps one of these, "/model" and "/params" change it between messages.
+// None leaves the server's default
+#[derive(Clone, Debug, PartialEq)]
+pub struct GenerationParams {
+    pub model: String,
+    pub max_tokens: Option<i32>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub stop: Vec<String>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    pub seed: Option<i64>,
+    pub n: Option<i32>,
+    pub response_format: ResponseFormat,
+}
+
+impl Default for GenerationParams {
+    fn default() -> Self {
+        GenerationParams {
+            model: CHAT_MODEL.to_string(),
+            max_tokens: Some(DEFAULT_MAX_TOKENS),
+            temperature: Some(DEFAULT_TEMPERATURE),
+            top_p: None,
+            stop: vec![],
+            presence_penalty: None,
+            frequency_penalty: None,
+            seed: None,
+            n: None,
+            response_format: ResponseFormat::Text,
+        }
+    }
+}
+
+impl ResponseFormat {
+    // "text", "json", or "schema:<file>" for the JSON Schema in that file
+    pub fn parse(input: &str) -> Option<Self> {
+        if let Some(path) = input.trim().strip_prefix("schema:") {
+            return read_schema(path)
+                .inspect_err(|e| warn!("{e}"))
+                .ok()
+                .map(|schema| ResponseFormat::JsonSchema { name: "answer".to_string(), schema });
+        }
+
+        match input.trim().to_lowercase().as_str() {
+            "text" => Some(ResponseFormat::Text),
+            "json" | "json_object" => Some(ResponseFormat::JsonObject),
+            _ => None
+        }
+    }
+
+    // The message that asks for this format in the prompt, None for plain text
+    pub fn instruction(&self) -> Option<Message> {
+        let content = match self {
+            ResponseFormat::Text => return None,
+            ResponseFormat::JsonObject => "Answer with JSON only, no other text.".to_string(),
+            ResponseFormat::JsonSchema { schema, .. } => format!("Answer with JSON only, no other text. It has to match this JSON Schema:\n{}", schema),
+        };
+        Some(Message { role: Role::System, content })
+    }
+
+    // The response_format field of the request, None for plain text
+    pub fn to_json(&self) -> Option<serde_json::Value> {
+        match self {
+            ResponseFormat::Text => None,
+            ResponseFormat::JsonObject => Some(json!({ "type": "json_object" })),
+            ResponseFormat::JsonSchema { name, schema } => Some(json!({
+                "type": "json_schema",
+                "json_schema": { "name": name, "schema": schema }
+            })),
+        }
+    }
+}
+
+// The JSON Schema in a file, or what went wrong reading it
+pub fn read_schema(path: &str) -> Result<serde_json::Value, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("Failed to read schema {}: {e}", path))?;
+    serde_json::from_str(&contents).map_err(|e| format!("Schema {} is not valid JSON: {e}", path))
+}
+
+fn env_value<T: std::str::FromStr>(name: &str) -> Option<T> {
+    let value = load_environment(name);
+    if value.trim().is_empty() {
+        return None;
+    }
+
+    value.trim().parse::<T>()
+        .inspect_err(|_| warn!("{} '{}' is not valid, ignoring it", name, value))
+        .ok()
+}
+
+impl GenerationParams {
+    pub fn with_model(mut self, model: &str) -> Self {
+        self.model = model.to_string();
+        self
+    }
+
+    pub fn with_max_tokens(mut self, max_tokens: i32) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    pub fn with_temperature(mut self, temperature: f32) -> Self {
+        self.temperature = Some(temperature);
+        self
+    }
+
+    pub fn with_response_format(mut self, response_format: ResponseFormat) -> Self {
+        self.response_format = response_format;
+        self
+    }
+
+    // The defaults, changed by CHAT_MODEL_NAME, CHAT_MAX_TOKENS,
+    // CHAT_TEMPERATURE, CHAT_TOP_P and CHAT_SEED where they are set
+    pub fn from_env() -> Self {
+        let mut params = GenerationParams::default();
+
+        let model = load_environment("CHAT_MODEL_NAME");
+        if !model.trim().is_empty() {
+            params.model = model.trim().to_string();
+        }
+        if let Some(max_tokens) = env_value("CHAT_MAX_TOKENS") {
+            params.max_tokens = Some(max_tokens);
+        }
+        if let Some(temperature) = env_value("CHAT_TEMPERATURE") {
+            params.temperature = Some(temperature);
+        }
+        params.top_p = env_value("CHAT_TOP_P");
+        params.seed = env_value("CHAT_SEED");
+
+        params
+    }
+
+    // Changes the parameters named in "key=value" words, like
+    // "temperature=0.7 top_p=0.9 stop=END|###". "default" clears a value
+    // back to the server's default. Returns the first word that was not
+    // understood, nothing is changed then
+    pub fn apply_args(&mut self, args: &str) -> Result<(), String> {
+        let mut params = self.clone();
+
+        for word in args.split_whitespace() {
+            let Some((key, value)) = word.split_once('=') else {
+                return Err(word.to_string());
+            };
+            let clear = value == "default";
+
+            let parsed = match key {
+                "model" => { params.model = value.to_string(); true }
+                "max_tokens" => clear_or(clear, value, &mut params.max_tokens),
+                "temperature" => clear_or(clear, value, &mut params.temperature),
+                "top_p" => clear_or(clear, value, &mut params.top_p),
+                "presence" | "presence_penalty" => clear_or(clear, value, &mut params.presence_penalty),
+                "frequency" | "frequency_penalty" => clear_or(clear, value, &mut params.frequency_penalty),
+                "seed" => clear_or(clear, value, &mut params.seed),
+                "n" => clear_or(clear, value, &mut params.n),
+                "stop" => {
+                    params.stop = if clear { vec![] } else { value.split('|').filter(|s| !s.is_empty()).map(String::from).collect() };
+                    true
+                }
+                "format" => ResponseFormat::parse(value).map(|f| params.response_format = f).is_some(),
+                _ => false
+            };
+
+            if !parsed {
+                return Err(word.to_string());
+            }
+        }
+
+        *self = params;
+        Ok(())
+    }
+
+    // The request body for these parameters. A response format adds its
+    // instruction after the messages
+    pub fn chat_body(&self, mut messages: Vec<Message>) -> MyChatBody {
+        messages.extend(self.response_format.instruction());
+        let mut body = MyChatBody::new(&self.model, messages);
+
+        if let Some(max_tokens) = self.max_tokens {
+            body = body.with_max_tokens(max_tokens);
+        }
+        if let Some(temperature) = self.temperature {
+            body = body.with_temperature(temperature);
+        }
+        if let Some(top_p) = self.top_p {
+            body = body.with_top_p(top_p);
+        }
+        if !self.stop.is_empty() {
+            body = body.with_stop(self.stop.clone());
+        }
+        if let Some(penalty) = self.presence_penalty {
+            body = body.with_presence_penalty(penalty);
+        }
+        if let Some(penalty) = self.frequency_penalty {
+            body = body.with_frequency_penalty(penalty);
+        }
+        if let Some(seed) = self.seed {
+            body = body.with_seed(seed);
+        }
+        if let Some(n) = self.n {
+            body = body.with_n(n);
+        }
+        if let Some(format) = self.response_format.to_json() {
+            body = body.with_response_format(format);
+        }
+
+        body
+    }
+}
+
+fn clear_or<T: std::str::FromStr>(clear: bool, value: &str, field: &mut Option<T>) -> bool {
+    if clear {
+        *field = None;
+        return true;
+    }
+    value.parse().map(|v| *field = Some(v)).is_ok()
+}
+
+fn show(value: Option<impl std::fmt::Display>) -> String {
+    value.map_or("default".to_string(), |v| v.to_string())
+}
+
+// "/model [name]" and "/params [key=value ...|reset]" show or change how
+// the chat loop generates answers. Returns false for other commands
+pub fn handle_params_command(line: &str, params: &mut GenerationParams) -> bool {
+    let (command, args) = line.trim().split_once(' ').unwrap_or((line.trim(), ""));
+    let args = args.trim();
+
+    match command {
+        "/model" => {
+            if !args.is_empty() {
+                params.model = args.to_string();
+                info!("Switched to model '{}'", params.model);
+            }
+            println!("Model: {}", params.model);
+        }
+        "/params" => {
+            if args == "reset" {
+                *params = GenerationParams::from_env();
+            } else if let Err(word) = params.apply_args(args) {
+                println!("Unknown parameter '{}'", word);
+                println!("Usage: /params [model=<name>] [max_tokens=<n>] [temperature=<t>] [top_p=<p>] [stop=<a|b>] [presence=<p>] [frequency=<p>] [seed=<n>] [n=<n>] [format=<text|json|schema:file>] or /params reset, \"default\" clears a value");
+                return true;
+            }
+
+            println!("Model: {}", params.model);
+            println!("max_tokens={} temperature={} top_p={} presence={} frequency={} seed={} n={}",
+                show(params.max_tokens), show(params.temperature), show(params.top_p),
+                show(params.presence_penalty), show(params.frequency_penalty), show(params.seed), show(params.n));
+            println!("stop={:?} format={:?}", params.stop, params.response_format);
+        }
+        _ => return false
+    }
+
+    true
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply_args_changes_every_named_value() {
+        let mut params = GenerationParams::default();
+        params.apply_args("model=small temperature=0.7 top_p=0.9 max_tokens=64 stop=END|### presence=0.5 frequency=-0.5 seed=42 n=2 format=json").unwrap();
+
+        assert_eq!(params.model, "small");
+        assert_eq!(params.temperature, Some(0.7));
+        assert_eq!(params.top_p, Some(0.9));
+        assert_eq!(params.max_tokens, Some(64));
+        assert_eq!(params.stop, vec!["END", "###"]);
+        assert_eq!(params.presence_penalty, Some(0.5));
+        assert_eq!(params.frequency_penalty, Some(-0.5));
+        assert_eq!(params.seed, Some(42));
+        assert_eq!(params.n, Some(2));
+        assert_eq!(params.response_format, ResponseFormat::JsonObject);
+    }
+
+    #[test]
+    fn default_clears_a_value() {
+        let mut params = GenerationParams::default().with_temperature(0.9);
+        params.apply_args("temperature=default max_tokens=default stop=a|b").unwrap();
+        params.apply_args("stop=default").unwrap();
+
+        assert_eq!(params.temperature, None);
+        assert_eq!(params.max_tokens, None);
+        assert!(params.stop.is_empty());
+    }
+
+    #[test]
+    fn a_bad_word_changes_nothing() {
+        let before = GenerationParams::default();
+
+        for args in ["temperature=hot", "max_tokens=1.5", "unknown=1", "temperature", "format=xml", "seed=-"] {
+            let mut params = before.clone();
+            let word = args.split_whitespace().next().unwrap();
+            assert_eq!(params.apply_args(&format!("top_p=0.5 {}", args)), Err(word.to_string()));
+            assert_eq!(params, before, "{} changed the parameters", args);
+        }
+    }
+
+    #[test]
+    fn params_command_applies_or_refuses() {
+        let mut params = GenerationParams::default();
+
+        assert!(handle_params_command("/params temperature=0.1 n=3", &mut params));
+        assert_eq!(params.temperature, Some(0.1));
+        assert_eq!(params.n, Some(3));
+
+        // An unknown parameter is reported, but the command is still handled
+        assert!(handle_params_command("/params temperature=0.5 colour=blue", &mut params));
+        assert_eq!(params.temperature, Some(0.1));
+
+        assert!(handle_params_command("/model other-model", &mut params));
+        assert_eq!(params.model, "other-model");
+        assert!(handle_params_command("/model", &mut params));
+        assert_eq!(params.model, "other-model");
+
+        assert!(!handle_params_command("/paramsx", &mut params));
+        assert!(!handle_params_command("hello", &mut params));
+    }
+
+    #[test]
+    fn the_request_body_leaves_out_unset_values() {
+        let params = GenerationParams { temperature: None, ..Default::default() }
+            .with_response_format(ResponseFormat::JsonObject);
+        let body = params.chat_body(vec![]).to_json();
+
+        assert_eq!(body["max_tokens"], DEFAULT_MAX_TOKENS);
+        assert!(body.get("temperature").is_none_or(|t| t.is_null()));
+        assert!(body.get("seed").is_none_or(|s| s.is_null()));
+        assert_eq!(body["response_format"]["type"], "json_object");
+    }
+
+    #[test]
+    fn a_schema_format_is_in_the_request_and_the_prompt() {
+        let path = std::env::temp_dir().join(format!("params-schema-{}.json", std::process::id()));
+        fs::write(&path, r#"{ "type": "object", "required": ["answer"] }"#).unwrap();
+
+        let mut params = GenerationParams::default();
+        params.apply_args(&format!("format=schema:{}", path.display())).unwrap();
+        let ResponseFormat::JsonSchema { schema, .. } = &params.response_format else {
+            panic!("expected a schema format, got {:?}", params.response_format);
+        };
+        assert_eq!(schema["required"][0], "answer");
+
+        let body = params.chat_body(vec![Message { role: Role::User, content: "hi".to_string() }]).to_json();
+        assert_eq!(body["response_format"]["type"], "json_schema");
+        assert_eq!(body["response_format"]["json_schema"]["schema"]["required"][0], "answer");
+        let messages = body["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 2);
+        assert!(messages[1]["content"].as_str().unwrap().contains("\"required\":[\"answer\"]"));
+
+        // A missing file is refused like any other bad value
+        fs::remove_file(&path).unwrap();
+        assert!(params.apply_args(&format!("format=schema:{}", path.display())).is_err());
+    }
+
+    #[test]
+    fn plain_text_adds_no_instruction() {
+        let body = GenerationParams::default().chat_body(vec![Message { role: Role::User, content: "hi".to_string() }]).to_json();
+        assert_eq!(body["messages"].as_array().unwrap().len(), 1);
+        assert!(body.get("response_format").is_none_or(|f| f.is_null()));
+    }
+}
@@ -0,0 +1,310 @@
+use std::collections::HashMap;
+
+use log::*;
+use serde::{ Deserialize, Serialize };
+
+use crate::kmeans::{ kmeans, nearest_centroid, nearest_centroids, sample_rows, squared_l2, SimpleRng };
+use crate::memory::normalized;
+use crate::utils::{ INDEX_TRAIN_SAMPLE, KMEANS_ITERATIONS };
+
+
+// An IVF-PQ index. A coarse k-means quantiser splits the vectors into
+// lists, and inside a list every vector is stored only as the product
+// quantised residual to its list centroid: the residual is cut into
+// sub_vectors pieces, and each piece is replaced by the index of the
+// closest entry of that piece's codebook, one byte each.
+// Vectors are normalised first, so the squared distance d between two of
+// them turns into the cosine similarity 1 - d / 2
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IvfPqIndex {
+    pub dim: usize,
+    pub sub_vectors: usize,
+    pub codebook_size: usize,
+    pub nprobe: usize,
+    pub centroids: Vec<Vec<f32>>,
+    pub codebooks: Vec<Vec<Vec<f32>>>,
+    pub lists: Vec<Vec<String>>,
+    pub codes: Vec<Vec<u8>>,
+    pub assignments: HashMap<String, usize>,
+}
+
+impl IvfPqIndex {
+    // Trains the coarse quantiser and the codebooks on a random sample of
+    // the given vectors. The index starts out empty, vectors are added
+    // with add() afterwards
+    pub fn train(vectors: &[Vec<f32>], nlist: usize, sub_vectors: usize, codebook_size: usize, nprobe: usize) -> Result<Self, String> {
+        let Some(dim) = vectors.first().map(|v| v.len()) else {
+            return Err("there are no vectors to train on".to_string());
+        };
+        if sub_vectors == 0 || dim % sub_vectors != 0 {
+            return Err(format!("{} sub-vectors don't evenly divide {} dimensions", sub_vectors, dim));
+        }
+        if !(1..=256).contains(&codebook_size) {
+            return Err("the codebook size must be between 1 and 256".to_string());
+        }
+        if nlist == 0 {
+            return Err("the number of lists must be at least 1".to_string());
+        }
+
+        let mut rng = SimpleRng::new(dim as u64);
+        let normalised: Vec<Vec<f32>> = vectors.iter().map(|v| normalized(v)).collect();
+        let sample = sample_rows(&normalised, INDEX_TRAIN_SAMPLE, &mut rng);
+        info!("Training IVF-PQ index on {} of {} vectors", sample.len(), vectors.len());
+
+        let centroids = kmeans(&sample, nlist, KMEANS_ITERATIONS, &mut rng);
+        if centroids.is_empty() {
+            return Err("k-means found no centroids".to_string());
+        }
+
+        let residuals: Vec<Vec<f32>> = sample.iter()
+            .map(|v| {
+                let (list, _) = nearest_centroid(v, &centroids);
+                v.iter().zip(centroids[list].iter()).map(|(x, c)| x - c).collect()
+            })
+            .collect();
+
+        let sub_dim = dim / sub_vectors;
+        let mut codebooks: Vec<Vec<Vec<f32>>> = Vec::with_capacity(sub_vectors);
+        for j in 0..sub_vectors {
+            let pieces: Vec<Vec<f32>> = residuals.iter()
+                .map(|r| r[j * sub_dim..(j + 1) * sub_dim].to_vec())
+                .collect();
+            let piece_refs: Vec<&Vec<f32>> = pieces.iter().collect();
+            codebooks.push(kmeans(&piece_refs, codebook_size, KMEANS_ITERATIONS, &mut rng));
+        }
+
+        Ok(IvfPqIndex {
+            dim,
+            sub_vectors,
+            codebook_size,
+            nprobe: nprobe.clamp(1, centroids.len()),
+            lists: vec![vec![]; centroids.len()],
+            codes: vec![vec![]; centroids.len()],
+            centroids,
+            codebooks,
+            assignments: HashMap::new(),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.assignments.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.assignments.is_empty()
+    }
+
+    fn sub_dim(&self) -> usize {
+        self.dim / self.sub_vectors
+    }
+
+    pub fn add(&mut self, key: &str, vector: &[f32]) {
+        if vector.len() != self.dim {
+            warn!("Not indexing '{}', its length {} doesn't match the index", key, vector.len());
+            return;
+        }
+        self.remove(key);
+
+        let v = normalized(vector);
+        let (list, _) = nearest_centroid(&v, &self.centroids);
+        let sub_dim = self.sub_dim();
+
+        for (j, codebook) in self.codebooks.iter().enumerate() {
+            let piece: Vec<f32> = (j * sub_dim..(j + 1) * sub_dim)
+                .map(|i| v[i] - self.centroids[list][i])
+                .collect();
+            let (code, _) = nearest_centroid(&piece, codebook);
+            self.codes[list].push(code as u8);
+        }
+
+        self.lists[list].push(key.to_string());
+        self.assignments.insert(key.to_string(), list);
+    }
+
+    pub fn remove(&mut self, key: &str) -> bool {
+        let Some(list) = self.assignments.remove(key) else {
+            return false;
+        };
+        let Some(position) = self.lists[list].iter().position(|k| k == key) else {
+            return false;
+        };
+
+        // Same trick as in VectorStore: the last entry takes its place
+        let m = self.sub_vectors;
+        let last = self.lists[list].len() - 1;
+        self.lists[list].swap_remove(position);
+        self.codes[list].copy_within(last * m..(last + 1) * m, position * m);
+        self.codes[list].truncate(last * m);
+        true
+    }
+
+    // Asymmetric distance computation: the query itself is not quantised.
+    // For every probed list, a table holds the distance of each piece of the
+    // query residual to every codebook entry, so the distance to a stored
+    // vector is just sub_vectors table lookups.
+    // Returns up to n (key, approximate cosine similarity) pairs, best first
+    pub fn search(&self, query: &[f32], n: usize, nprobe: Option<usize>) -> Vec<(String, f32)> {
+        if query.len() != self.dim || n == 0 {
+            return vec![];
+        }
+
+        let q = normalized(query);
+        let sub_dim = self.sub_dim();
+        let m = self.sub_vectors;
+        let probes = nearest_centroids(&q, &self.centroids, nprobe.unwrap_or(self.nprobe).max(1));
+
+        let mut results: Vec<(String, f32)> = vec![];
+        let mut table: Vec<f32> = vec![0.0; m * self.codebook_size];
+
+        for list in probes {
+            let residual: Vec<f32> = q.iter().zip(self.centroids[list].iter()).map(|(x, c)| x - c).collect();
+
+            for (j, codebook) in self.codebooks.iter().enumerate() {
+                let piece = &residual[j * sub_dim..(j + 1) * sub_dim];
+                for (c, entry) in codebook.iter().enumerate() {
+                    table[j * self.codebook_size + c] = squared_l2(piece, entry);
+                }
+            }
+
+            for (i, key) in self.lists[list].iter().enumerate() {
+                let codes = &self.codes[list][i * m..(i + 1) * m];
+                let distance: f32 = codes.iter()
+                    .enumerate()
+                    .map(|(j, code)| table[j * self.codebook_size + *code as usize])
+                    .sum();
+                results.push((key.clone(), 1.0 - distance / 2.0));
+            }
+        }
+
+        results.sort_by(|a, b| b.1.total_cmp(&a.1));
+        results.truncate(n);
+        results
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vectors(n: usize, dim: usize) -> Vec<Vec<f32>> {
+        let mut rng = SimpleRng::new(11);
+        (0..n).map(|_| (0..dim).map(|_| rng.next_f32() - 0.5).collect()).collect()
+    }
+
+    fn built(data: &[Vec<f32>], nlist: usize, sub_vectors: usize, codebook_size: usize) -> IvfPqIndex {
+        let mut index = IvfPqIndex::train(data, nlist, sub_vectors, codebook_size, nlist).unwrap();
+        for (i, v) in data.iter().enumerate() {
+            index.add(&format!("k{}", i), v);
+        }
+        index
+    }
+
+    // The vector a stored entry stands for: its list centroid plus the
+    // codebook entry of every piece
+    fn reconstruct(index: &IvfPqIndex, key: &str) -> Vec<f32> {
+        let list = index.assignments[key];
+        let position = index.lists[list].iter().position(|k| k == key).unwrap();
+        let m = index.sub_vectors;
+        let codes = &index.codes[list][position * m..(position + 1) * m];
+
+        let mut v = index.centroids[list].clone();
+        for (j, code) in codes.iter().enumerate() {
+            for (i, x) in index.codebooks[j][*code as usize].iter().enumerate() {
+                v[j * index.sub_dim() + i] += x;
+            }
+        }
+        v
+    }
+
+    #[test]
+    fn train_rejects_bad_settings() {
+        let data = vectors(20, 8);
+        assert!(IvfPqIndex::train(&[], 2, 2, 16, 1).is_err());
+        assert!(IvfPqIndex::train(&data, 0, 2, 16, 1).is_err());
+        assert!(IvfPqIndex::train(&data, 2, 3, 16, 1).is_err());
+        assert!(IvfPqIndex::train(&data, 2, 0, 16, 1).is_err());
+        assert!(IvfPqIndex::train(&data, 2, 2, 0, 1).is_err());
+        assert!(IvfPqIndex::train(&data, 2, 2, 257, 1).is_err());
+    }
+
+    #[test]
+    fn train_clamps_nprobe_to_the_lists() {
+        let data = vectors(20, 8);
+        let index = IvfPqIndex::train(&data, 4, 2, 16, 100).unwrap();
+        assert_eq!(index.centroids.len(), 4);
+        assert_eq!(index.nprobe, 4);
+        assert!(index.is_empty());
+    }
+
+    #[test]
+    fn add_encodes_one_byte_per_sub_vector() {
+        let data = vectors(30, 8);
+        let index = built(&data, 3, 4, 16);
+
+        assert_eq!(index.len(), 30);
+        for (list, keys) in index.lists.iter().enumerate() {
+            assert_eq!(index.codes[list].len(), keys.len() * 4);
+            assert!(index.codes[list].iter().all(|code| (*code as usize) < index.codebooks[0].len()));
+        }
+    }
+
+    #[test]
+    fn adc_scores_match_the_reconstructed_vectors() {
+        let data = vectors(40, 8);
+        let index = built(&data, 2, 2, 8);
+        let query = normalized(&data[5]);
+
+        for (key, score) in index.search(&data[5], 40, Some(2)) {
+            let expected = 1.0 - squared_l2(&query, &reconstruct(&index, &key)) / 2.0;
+            assert!((score - expected).abs() < 1e-4, "{} scored {} instead of {}", key, score, expected);
+        }
+    }
+
+    #[test]
+    fn exact_codebooks_find_the_vector_itself() {
+        // With at least as many codebook entries as vectors, every residual
+        // is its own entry and the search is exact
+        let data = vectors(16, 4);
+        let index = built(&data, 1, 2, 16);
+
+        for (i, v) in data.iter().enumerate() {
+            let results = index.search(v, 1, None);
+            assert_eq!(results[0].0, format!("k{}", i));
+            assert!((results[0].1 - 1.0).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn remove_drops_the_entry_and_its_codes() {
+        let data = vectors(20, 8);
+        let mut index = built(&data, 2, 2, 8);
+
+        assert!(index.remove("k3"));
+        assert!(!index.remove("k3"));
+        assert_eq!(index.len(), 19);
+        for (list, keys) in index.lists.iter().enumerate() {
+            assert_eq!(index.codes[list].len(), keys.len() * 2);
+        }
+        assert!(index.search(&data[3], 20, Some(2)).iter().all(|(key, _)| key != "k3"));
+
+        // The entry that took its place still decodes to the same scores
+        let query = normalized(&data[0]);
+        for (key, score) in index.search(&data[0], 20, Some(2)) {
+            let expected = 1.0 - squared_l2(&query, &reconstruct(&index, &key)) / 2.0;
+            assert!((score - expected).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn search_ignores_wrong_lengths_and_zero_results() {
+        let data = vectors(20, 8);
+        let mut index = built(&data, 2, 2, 8);
+
+        assert!(index.search(&data[0], 0, None).is_empty());
+        assert!(index.search(&[1.0, 0.0], 5, None).is_empty());
+
+        index.add("short", &[1.0, 0.0]);
+        assert_eq!(index.len(), 20);
+    }
+}
@@ -0,0 +1,336 @@
+use std::fs;
+use std::io::{ Error, ErrorKind };
+use std::path::{ Path, PathBuf };
+use std::time::SystemTime;
+
+use log::*;
+use openai_api_rust::{ Message, Role };
+
+use crate::custom_types::ChatSession;
+use crate::search::SearchResult;
+use crate::session::memory_lines;
+use crate::utils::{ format_unix, load_environment, now_unix, PROMPT_DIR, SYSPROMPT_FILE };
+
+static DEFAULT_PROFILE: &str = "default";
+
+
+// What the placeholders of a system prompt are filled with
+pub struct PromptVars<'a> {
+    pub user_name: &'a str,
+    pub memories: &'a [SearchResult],
+    pub summary: Option<&'a str>,
+    pub model: &'a str,
+    pub now: u64,
+}
+
+// The system prompt in use: the template of the selected profile, read
+// again whenever its file changes. "default" is data/system_pormpt.txt,
+// every other profile is data/prompts/<name>.txt
+pub struct SystemPrompts {
+    profile: String,
+    template: String,
+    modified: Option<SystemTime>,
+}
+
+fn profile_path(profile: &str) -> PathBuf {
+    if profile == DEFAULT_PROFILE {
+        PathBuf::from(SYSPROMPT_FILE)
+    } else {
+        Path::new(PROMPT_DIR).join(format!("{}.txt", profile))
+    }
+}
+
+// Profiles are the file stems list_profiles shows, so a name is used as it
+// is. Only names that would reach outside PROMPT_DIR are turned down
+fn check_profile_name(profile: &str) -> std::io::Result<()> {
+    if profile.contains('/') || profile.contains('\\') || profile.contains("..") {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("'{}' is not a profile name", profile)));
+    }
+    Ok(())
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// The value of one placeholder, None for names that are not known.
+// Those are left in the text as they are
+fn placeholder(name: &str, vars: &PromptVars) -> Option<String> {
+    let now = format_unix(vars.now);
+    match name {
+        "date" => Some(now.split(' ').next().unwrap_or_default().to_string()),
+        "time" => Some(now.split(' ').skip(1).collect::<Vec<&str>>().join(" ")),
+        "datetime" => Some(now),
+        "user_name" | "user" => Some(vars.user_name.to_string()),
+        "memories" => Some(memory_lines(vars.memories).trim_end().to_string()),
+        "summary" => Some(vars.summary.unwrap_or_default().trim().to_string()),
+        "model" => Some(vars.model.to_string()),
+        _ => None
+    }
+}
+
+// The names of the placeholders in a template, in order
+fn placeholders(template: &str) -> Vec<&str> {
+    let mut names: Vec<&str> = vec![];
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start + 2..].find("}}") else {
+            break;
+        };
+        names.push(rest[start + 2..start + 2 + end].trim());
+        rest = &rest[start + 4 + end..];
+    }
+    names
+}
+
+// Fills in the {{name}} placeholders of a template. Spaces inside the
+// braces are allowed, like {{ date }}
+pub fn render_template(template: &str, vars: &PromptVars) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start + 2..].find("}}") else {
+            break;
+        };
+        let name = rest[start + 2..start + 2 + end].trim();
+
+        out.push_str(&rest[..start]);
+        match placeholder(name, vars) {
+            Some(value) => out.push_str(&value),
+            None => out.push_str(&rest[start..start + 4 + end])
+        }
+        rest = &rest[start + 4 + end..];
+    }
+
+    out.push_str(rest);
+    out
+}
+
+impl SystemPrompts {
+    // Starts with the default profile, whose text was already read with
+    // load_sysprompt, then switches to SYSTEM_PROFILE if it names another
+    pub fn from_env(default_template: &str) -> Self {
+        let mut prompts = SystemPrompts {
+            profile: DEFAULT_PROFILE.to_string(),
+            template: default_template.to_string(),
+            modified: modified(Path::new(SYSPROMPT_FILE)),
+        };
+
+        let profile = load_environment("SYSTEM_PROFILE");
+        if !profile.trim().is_empty()
+            && let Err(e) = prompts.switch(profile.trim()) {
+            error!("Failed to load system prompt profile '{}', using the default: {e}", profile.trim());
+        }
+        prompts
+    }
+
+    pub fn profile(&self) -> &str {
+        &self.profile
+    }
+
+    // The text of the profile, placeholders and all
+    pub fn template(&self) -> &str {
+        &self.template
+    }
+
+    // Whether the template has a {{name}} placeholder
+    pub fn uses(&self, name: &str) -> bool {
+        placeholders(&self.template).contains(&name)
+    }
+
+    // Whether the template has any placeholder at all, its text is then
+    // different for every turn
+    pub fn is_templated(&self) -> bool {
+        !placeholders(&self.template).is_empty()
+    }
+
+    pub fn render(&self, vars: &PromptVars) -> String {
+        render_template(&self.template, vars)
+    }
+
+    pub fn switch(&mut self, profile: &str) -> std::io::Result<()> {
+        check_profile_name(profile)?;
+        let path = profile_path(profile);
+        if !path.exists() {
+            return Err(Error::new(ErrorKind::NotFound, format!("{} does not exist", path.display())));
+        }
+
+        self.template = fs::read_to_string(&path)?;
+        self.modified = modified(&path);
+        self.profile = profile.to_string();
+        Ok(())
+    }
+
+    // Reads the template again if its file changed since it was read.
+    // Returns whether it did
+    pub fn reload_if_changed(&mut self) -> bool {
+        let path = profile_path(&self.profile);
+        let current = modified(&path);
+        if current.is_none() || current == self.modified {
+            return false;
+        }
+
+        match fs::read_to_string(&path) {
+            Ok(template) => {
+                self.template = template;
+                self.modified = current;
+                true
+            }
+            Err(e) => {
+                error!("Failed to reload system prompt {}: {e}", path.display());
+                false
+            }
+        }
+    }
+}
+
+// The names of the profiles there are, default first
+pub fn list_profiles() -> Vec<String> {
+    let mut profiles: Vec<String> = fs::read_dir(PROMPT_DIR)
+        .map(|entries| entries.flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "txt"))
+            .filter_map(|path| path.file_stem().map(|stem| stem.to_string_lossy().to_string()))
+            .filter(|name| name != DEFAULT_PROFILE)
+            .collect())
+        .unwrap_or_default();
+
+    profiles.sort();
+    profiles.insert(0, DEFAULT_PROFILE.to_string());
+    profiles
+}
+
+impl ChatSession {
+    // Keeps the first message of the session on the template in use, so a
+    // saved conversation shows which prompt it was held with
+    pub fn set_system_prompt(&mut self, template: &str) {
+        if let Some(first) = self.messages.first_mut()
+            && matches!(first.role, Role::System) {
+            first.content = template.to_string();
+        }
+    }
+
+    // The messages to send, with the system prompt filled in for this
+    // turn. If the template places the memories itself, they are not
+    // added again as a message of their own
+    pub fn prompt_with_template(&self, prompts: &SystemPrompts, memories: &[SearchResult], model: &str) -> Vec<Message> {
+        let mut prompt = if prompts.uses("memories") {
+            self.messages.clone()
+        } else {
+            self.prompt_with_memories(memories)
+        };
+
+        let vars = PromptVars {
+            user_name: &self.user_id,
+            memories,
+            summary: self.summary.as_deref(),
+            model,
+            now: now_unix(),
+        };
+        if let Some(first) = prompt.first_mut()
+            && matches!(first.role, Role::System) {
+            first.content = prompts.render(&vars);
+        }
+        prompt
+    }
+}
+
+// "/profile" lists the system prompt profiles, "/profile <name>" switches
+// to one. Returns false for other commands
+pub fn handle_profile_command(line: &str, prompts: &mut SystemPrompts) -> bool {
+    let (command, args) = line.trim().split_once(' ').unwrap_or((line.trim(), ""));
+    if command != "/profile" {
+        return false;
+    }
+
+    let name = args.trim();
+    if name.is_empty() {
+        for profile in list_profiles() {
+            let current = if profile == prompts.profile() { "\t(in use)" } else { "" };
+            println!("{}{}", profile, current);
+        }
+        return true;
+    }
+
+    match prompts.switch(name) {
+        Ok(()) => println!("Using system prompt profile '{}'", name),
+        Err(e) => error!("Failed to load system prompt profile '{}': {e}", name)
+    }
+    true
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> PromptVars<'static> {
+        PromptVars { user_name: "alice", memories: &[], summary: Some(" Talked about tea. "), model: "gpt-test", now: 0 }
+    }
+
+    #[test]
+    fn known_placeholders_are_filled_in() {
+        let text = render_template("{{user_name}} on {{model}}, {{date}}: {{summary}}", &vars());
+        assert_eq!(text, format!("alice on gpt-test, {}: Talked about tea.", format_unix(0).split(' ').next().unwrap()));
+    }
+
+    #[test]
+    fn unknown_placeholders_are_left_as_they_are() {
+        let template = "Hi {{user}}, {{ nickname }} and {{}} stay";
+        assert_eq!(render_template(template, &vars()), "Hi alice, {{ nickname }} and {{}} stay");
+        assert_eq!(placeholders(template), vec!["user", "nickname", ""]);
+    }
+
+    #[test]
+    fn spaces_inside_the_braces_are_trimmed() {
+        assert_eq!(render_template("{{ user_name }}|{{model  }}|{{\tuser}}", &vars()), "alice|gpt-test|alice");
+        assert_eq!(placeholders("{{ user_name }} {{model  }}"), vec!["user_name", "model"]);
+    }
+
+    #[test]
+    fn an_unterminated_placeholder_is_kept_as_text() {
+        let template = "{{user}} wrote {{model and {{ user";
+        assert_eq!(render_template(template, &vars()), "alice wrote {{model and {{ user");
+        assert_eq!(placeholders(template), vec!["user"]);
+        assert_eq!(render_template("ends with {{", &vars()), "ends with {{");
+        assert!(placeholders("}} {{").is_empty());
+    }
+
+    #[test]
+    fn every_listed_profile_can_be_switched_to() {
+        let name = format!("code_review-{}", std::process::id());
+        let path = Path::new(PROMPT_DIR).join(format!("{}.txt", name));
+        fs::create_dir_all(PROMPT_DIR).unwrap();
+        fs::write(&path, "Review the code of {{user_name}}").unwrap();
+
+        // Creates the default prompt file if it is missing, as at startup
+        let default = crate::utils::load_sysprompt().unwrap_or_default();
+        let profiles = list_profiles();
+        let mut prompts = SystemPrompts::from_env(&default);
+        let mut failed = vec![];
+        for profile in &profiles {
+            if let Err(e) = prompts.switch(profile) {
+                failed.push(format!("{profile}: {e}"));
+            }
+        }
+        prompts.switch(&name).unwrap();
+        let template = prompts.template().to_string();
+        fs::remove_file(&path).unwrap();
+
+        assert!(profiles.contains(&name));
+        assert_eq!(failed, Vec::<String>::new());
+        assert_eq!(template, "Review the code of {{user_name}}");
+    }
+
+    #[test]
+    fn profile_names_cannot_leave_the_prompt_dir() {
+        let mut prompts = SystemPrompts::from_env("Default prompt");
+        for name in ["../system_pormpt", "a/b", "a\\b", ".."] {
+            let e = prompts.switch(name).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::InvalidInput, "{name}");
+        }
+        assert_eq!(prompts.profile(), DEFAULT_PROFILE);
+    }
+}
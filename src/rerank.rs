@@ -0,0 +1,177 @@
+use log::*;
+use openai_api_rust::{ Message, Role };
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::custom_types::MyChatbot;
+use crate::params::GenerationParams;
+use crate::search::SearchResult;
+use crate::utils::{ get_openai, load_environment };
+
+
+// What rescores the candidates of a search after retrieval.
+// Endpoint is a dedicated reranker model (a cross-encoder) behind a
+// /rerank style API, Chat asks the chat model to grade each candidate
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reranker {
+    Endpoint { url: String, model: String },
+    Chat,
+}
+
+// The answer of a /rerank endpoint, as served by llama.cpp, Jina,
+// Cohere and friends
+#[derive(Deserialize)]
+struct RerankResponse {
+    results: Vec<RerankScore>,
+}
+
+#[derive(Deserialize)]
+struct RerankScore {
+    index: usize,
+    relevance_score: f32,
+}
+
+static CHAT_RERANK_PROMPT: &str = "You grade how relevant a remembered fact is to a user's message. \
+Answer with a single number from 0 (unrelated) to 10 (exactly what the message needs), nothing else.";
+
+impl Reranker {
+    // The endpoint set in RERANK_URL and RERANK_MODEL, if there is one
+    fn endpoint_from_env() -> Option<Self> {
+        let url = load_environment("RERANK_URL");
+        if url.trim().is_empty() {
+            return None;
+        }
+        Some(Reranker::Endpoint { url: url.trim().to_string(), model: load_environment("RERANK_MODEL").trim().to_string() })
+    }
+
+    // "chat" or "endpoint". Without a name, the endpoint is used
+    // if one is set and the chat model otherwise
+    pub fn parse(input: &str) -> Option<Self> {
+        match input.trim().to_lowercase().as_str() {
+            "chat" | "llm" => Some(Reranker::Chat),
+            "endpoint" => Reranker::endpoint_from_env().or_else(|| {
+                warn!("RERANK_URL is not set, there is no reranker endpoint to use");
+                None
+            }),
+            "" => Some(Reranker::endpoint_from_env().unwrap_or(Reranker::Chat)),
+            _ => None
+        }
+    }
+
+    // The reranker the chat uses for every message, from MEMORY_RERANK
+    pub fn from_env() -> Option<Self> {
+        let value = load_environment("MEMORY_RERANK");
+        match value.trim().to_lowercase().as_str() {
+            "" | "off" | "none" => None,
+            other => Reranker::parse(other).or_else(|| {
+                warn!("Unknown MEMORY_RERANK '{}', not reranking", value);
+                None
+            })
+        }
+    }
+
+    // One relevance score per document, in the order they were given
+    fn score(&self, query: &str, documents: &[String]) -> Option<Vec<f32>> {
+        match self {
+            Reranker::Endpoint { url, model } => score_with_endpoint(url, model, query, documents)
+                .inspect_err(|e| error!("Reranker endpoint {} failed: {e}", url))
+                .ok(),
+            Reranker::Chat => score_with_chat(query, documents),
+        }
+    }
+}
+
+fn score_with_endpoint(url: &str, model: &str, query: &str, documents: &[String]) -> Result<Vec<f32>, String> {
+    let mut body = json!({
+        "query": query,
+        "documents": documents,
+        "top_n": documents.len(),
+    });
+    if !model.is_empty() {
+        body["model"] = json!(model);
+    }
+
+    let response: RerankResponse = ureq::post(url)
+        .set("Authorization", &format!("Bearer {}", load_environment("LMS_API_KEY")))
+        .send_json(body)
+        .map_err(|e| e.to_string())?
+        .into_json()
+        .map_err(|e| e.to_string())?;
+
+    let mut scores: Vec<f32> = vec![f32::NEG_INFINITY; documents.len()];
+    for result in response.results {
+        if let Some(score) = scores.get_mut(result.index) {
+            *score = result.relevance_score;
+        }
+    }
+    Ok(scores)
+}
+
+// Grades every document on its own with the chat model, and scales the
+// 0 to 10 answer to 0..1. An answer without a number counts as 0, but if
+// no answer came back at all the chat model is probably not reachable
+fn score_with_chat(query: &str, documents: &[String]) -> Option<Vec<f32>> {
+    let oai = get_openai(&load_environment("URL"), &load_environment("LMS_API_KEY"));
+    let chatbot = MyChatbot::new();
+
+    let answers: Vec<String> = documents.iter()
+        .map(|document| {
+            let mut messages = vec![
+                Message { role: Role::System, content: CHAT_RERANK_PROMPT.to_string() },
+                Message { role: Role::User, content: format!("Message: {}\nFact: {}\nRelevance:", query, document) },
+            ];
+            chatbot.clone().generate_response(&mut messages, &oai, &GenerationParams::from_env()).0
+        })
+        .collect();
+
+    if answers.iter().all(|answer| answer.trim().is_empty()) {
+        return None;
+    }
+
+    let scores: Vec<f32> = answers.iter()
+        .zip(documents)
+        .map(|(answer, document)| parse_grade(answer).unwrap_or_else(|| {
+            warn!("Chat reranker gave no grade for '{}': {:?}", document, answer);
+            0.0
+        }))
+        .collect();
+
+    Some(scores)
+}
+
+// The first number in the answer, clamped to 0..=10 and scaled to 0..1
+pub fn parse_grade(answer: &str) -> Option<f32> {
+    let start = answer.find(|c: char| c.is_ascii_digit())?;
+    let number: String = answer[start..].chars()
+        .take_while(|c| c.is_ascii_digit() || *c == '.')
+        .collect();
+    number.trim_end_matches('.').parse::<f32>().ok().map(|grade| grade.clamp(0.0, 10.0) / 10.0)
+}
+
+// Rescores the results with the reranker and returns the best top_k of
+// them by rerank score. The original scores are kept next to the rerank
+// scores. If the reranker fails, the results keep their original order
+pub fn rerank_results(reranker: &Reranker, query: &str, mut results: Vec<SearchResult>, top_k: usize) -> Vec<SearchResult> {
+    if results.is_empty() {
+        return results;
+    }
+
+    let documents: Vec<String> = results.iter().map(|result| result.text.clone()).collect();
+    let Some(scores) = reranker.score(query, &documents) else {
+        warn!("Reranking failed, keeping the original order");
+        results.truncate(top_k);
+        return results;
+    };
+
+    for (result, score) in results.iter_mut().zip(scores) {
+        result.rerank_score = Some(score);
+    }
+
+    results.sort_by(|a, b| {
+        let a_score = a.rerank_score.unwrap_or(f32::NEG_INFINITY);
+        let b_score = b.rerank_score.unwrap_or(f32::NEG_INFINITY);
+        b_score.total_cmp(&a_score).then(b.score.total_cmp(&a.score))
+    });
+    results.truncate(top_k);
+    results
+}
@@ -0,0 +1,244 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+
+
+// Dot product of two f32 slices. On x86_64 CPUs with AVX2 and FMA this
+// runs 16 multiply-adds per loop, everywhere else a plain loop with eight
+// separate sums, which the compiler can vectorise on its own
+pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+    let n = a.len().min(b.len());
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        if n >= 16 && std::is_x86_feature_detected!("avx2") && std::is_x86_feature_detected!("fma") {
+            // Safe because we just checked that the CPU supports both features
+            return unsafe { dot_avx2(&a[..n], &b[..n]) };
+        }
+    }
+
+    dot_fallback(&a[..n], &b[..n])
+}
+
+pub fn norm(a: &[f32]) -> f32 {
+    dot(a, a).sqrt()
+}
+
+fn dot_fallback(a: &[f32], b: &[f32]) -> f32 {
+    let mut sums = [0.0f32; 8];
+    let chunks_a = a.chunks_exact(8);
+    let chunks_b = b.chunks_exact(8);
+    let rest: f32 = chunks_a.remainder().iter().zip(chunks_b.remainder()).map(|(x, y)| x * y).sum();
+
+    for (ca, cb) in chunks_a.zip(chunks_b) {
+        for i in 0..8 {
+            sums[i] += ca[i] * cb[i];
+        }
+    }
+
+    sums.iter().sum::<f32>() + rest
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2,fma")]
+unsafe fn dot_avx2(a: &[f32], b: &[f32]) -> f32 {
+    use std::arch::x86_64::*;
+
+    let n = a.len();
+    let mut i = 0;
+    let mut acc0 = _mm256_setzero_ps();
+    let mut acc1 = _mm256_setzero_ps();
+
+    // The loads are unaligned, which AVX handles at no real cost
+    unsafe {
+        while i + 16 <= n {
+            acc0 = _mm256_fmadd_ps(_mm256_loadu_ps(a.as_ptr().add(i)), _mm256_loadu_ps(b.as_ptr().add(i)), acc0);
+            acc1 = _mm256_fmadd_ps(_mm256_loadu_ps(a.as_ptr().add(i + 8)), _mm256_loadu_ps(b.as_ptr().add(i + 8)), acc1);
+            i += 16;
+        }
+        if i + 8 <= n {
+            acc0 = _mm256_fmadd_ps(_mm256_loadu_ps(a.as_ptr().add(i)), _mm256_loadu_ps(b.as_ptr().add(i)), acc0);
+            i += 8;
+        }
+    }
+
+    let mut lanes = [0.0f32; 8];
+    unsafe { _mm256_storeu_ps(lanes.as_mut_ptr(), _mm256_add_ps(acc0, acc1)) };
+
+    let mut sum: f32 = lanes.iter().sum();
+    while i < n {
+        sum += a[i] * b[i];
+        i += 1;
+    }
+    sum
+}
+
+
+// A score for a row, ordered by the score alone
+#[derive(Clone, Copy, Debug)]
+struct Scored(f32, usize);
+
+impl PartialEq for Scored {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Scored {}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0).then(other.1.cmp(&self.1))
+    }
+}
+
+// Keeps only the k best scores seen so far. The heap is a min-heap, so
+// the worst of the k is always on top and easy to throw out, and nothing
+// ever has to sort every score of the collection
+pub struct TopK {
+    k: usize,
+    heap: BinaryHeap<Reverse<Scored>>,
+}
+
+impl TopK {
+    pub fn new(k: usize) -> Self {
+        TopK { k, heap: BinaryHeap::with_capacity(k + 1) }
+    }
+
+    pub fn push(&mut self, row: usize, score: f32) {
+        if self.k == 0 || score.is_nan() {
+            return;
+        }
+
+        let scored = Scored(score, row);
+        if self.heap.len() < self.k {
+            self.heap.push(Reverse(scored));
+        } else if let Some(Reverse(worst)) = self.heap.peek()
+            && scored > *worst {
+            self.heap.pop();
+            self.heap.push(Reverse(scored));
+        }
+    }
+
+    // Combines the results of two threads
+    pub fn merge(mut self, other: TopK) -> TopK {
+        for Reverse(Scored(score, row)) in other.heap {
+            self.push(row, score);
+        }
+        self
+    }
+
+    // (row, score) pairs, best first
+    pub fn into_sorted(self) -> Vec<(usize, f32)> {
+        self.heap.into_sorted_vec()
+            .into_iter()
+            .map(|Reverse(Scored(score, row))| (row, score))
+            .collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kmeans::SimpleRng;
+
+    fn random(n: usize, rng: &mut SimpleRng) -> Vec<f32> {
+        (0..n).map(|_| rng.next_f32() * 2.0 - 1.0).collect()
+    }
+
+    fn naive_dot(a: &[f32], b: &[f32]) -> f32 {
+        a.iter().zip(b.iter()).map(|(x, y)| *x as f64 * *y as f64).sum::<f64>() as f32
+    }
+
+    #[test]
+    fn dot_matches_a_plain_loop() {
+        let mut rng = SimpleRng::new(5);
+        for n in 0..70 {
+            let a = random(n, &mut rng);
+            let b = random(n, &mut rng);
+            assert!((dot(&a, &b) - naive_dot(&a, &b)).abs() < 1e-4, "length {}", n);
+            assert!((dot_fallback(&a, &b) - naive_dot(&a, &b)).abs() < 1e-4, "length {}", n);
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn avx2_dot_matches_the_fallback() {
+        if !std::is_x86_feature_detected!("avx2") || !std::is_x86_feature_detected!("fma") {
+            return;
+        }
+
+        let mut rng = SimpleRng::new(9);
+        for n in [0, 1, 7, 8, 15, 16, 17, 24, 31, 32, 33, 100, 1536] {
+            let a = random(n, &mut rng);
+            let b = random(n, &mut rng);
+            let simd = unsafe { dot_avx2(&a, &b) };
+            assert!((simd - dot_fallback(&a, &b)).abs() < 1e-3, "length {}", n);
+        }
+    }
+
+    #[test]
+    fn dot_uses_the_shorter_slice() {
+        assert_eq!(dot(&[], &[]), 0.0);
+        assert_eq!(dot(&[1.0, 2.0, 3.0], &[2.0, 2.0]), 6.0);
+        assert_eq!(norm(&[3.0, 4.0]), 5.0);
+    }
+
+    #[test]
+    fn top_k_keeps_the_best_in_order() {
+        let mut rng = SimpleRng::new(3);
+        let scores = random(200, &mut rng);
+
+        let mut top = TopK::new(10);
+        for (row, score) in scores.iter().enumerate() {
+            top.push(row, *score);
+        }
+
+        let mut expected: Vec<(usize, f32)> = scores.iter().copied().enumerate().collect();
+        expected.sort_by(|a, b| b.1.total_cmp(&a.1));
+        expected.truncate(10);
+        assert_eq!(top.into_sorted(), expected);
+    }
+
+    #[test]
+    fn top_k_breaks_ties_by_row_and_skips_nan() {
+        let mut top = TopK::new(3);
+        top.push(4, 0.5);
+        top.push(1, 0.5);
+        top.push(2, f32::NAN);
+        top.push(3, 0.5);
+        top.push(0, 0.5);
+
+        assert_eq!(top.into_sorted(), vec![(0, 0.5), (1, 0.5), (3, 0.5)]);
+    }
+
+    #[test]
+    fn top_k_handles_zero_and_empty() {
+        let mut top = TopK::new(0);
+        top.push(0, 1.0);
+        assert!(top.into_sorted().is_empty());
+        assert!(TopK::new(5).into_sorted().is_empty());
+    }
+
+    #[test]
+    fn merged_top_k_equals_one_pass() {
+        let mut rng = SimpleRng::new(8);
+        let scores = random(100, &mut rng);
+
+        let mut whole = TopK::new(7);
+        let mut left = TopK::new(7);
+        let mut right = TopK::new(7);
+        for (row, score) in scores.iter().enumerate() {
+            whole.push(row, *score);
+            if row < 50 { left.push(row, *score) } else { right.push(row, *score) }
+        }
+
+        assert_eq!(left.merge(right).into_sorted(), whole.into_sorted());
+    }
+}
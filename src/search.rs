@@ -0,0 +1,547 @@
+use std::collections::HashMap;
+
+use log::*;
+
+use crate::bm25::Bm25Index;
+use crate::custom_types::{ MemoryMetadata, UserMemories };
+use crate::memory::cosine_similarity_slices;
+use crate::rerank::Reranker;
+use crate::utils::{
+    load_environment, now_unix, search_terms, DEFAULT_IMPORTANCE, HYBRID_KEYWORD_WEIGHT, HYBRID_OVERSAMPLE,
+    HYBRID_VECTOR_WEIGHT, IMPORTANCE_WEIGHT, MEMORY_TOP_K, MMR_DEFAULT_LAMBDA, RECENCY_HALF_LIFE_HOURS,
+    RECENCY_WEIGHT, RERANK_TOP_N, RRF_K
+};
+
+
+// How the vector and keyword results of a hybrid search are combined.
+// WeightedSum adds up both scores (keyword scores scaled to 0..1 first),
+// Rrf only looks at the rank each result has in either list
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Fusion {
+    #[default]
+    WeightedSum,
+    Rrf,
+}
+
+// Everything a single memory search can be tuned with.
+// A weight of 0 leaves that side of the search out entirely.
+// Recency and importance are added on top of the relevance, like the
+// memory stream of generative agents, a weight of 0 turns them off.
+// With mmr_lambda set, the results are picked by maximal marginal
+// relevance instead of by score alone.
+// With a reranker, the best rerank_top_n results are scored again by it
+// and the top_k of those by rerank score are returned.
+// Results below min_score are dropped, and with_vectors hands back the
+// vector of every result as well
+#[derive(Clone, Debug)]
+pub struct SearchOptions {
+    pub top_k: usize,
+    pub vector_weight: f32,
+    pub keyword_weight: f32,
+    pub fusion: Fusion,
+    pub recency_weight: f32,
+    pub importance_weight: f32,
+    pub half_life_hours: f32,
+    pub mmr_lambda: Option<f32>,
+    pub reranker: Option<Reranker>,
+    pub rerank_top_n: usize,
+    pub min_score: Option<f32>,
+    pub with_vectors: bool,
+}
+
+// One memory found by a search. score is what the hybrid search gave
+// it, rerank_score what the reranker gave it, if one was used
+#[derive(Clone, Debug)]
+pub struct SearchResult {
+    pub id: String,
+    pub score: f32,
+    pub rerank_score: Option<f32>,
+    pub text: String,
+    pub metadata: MemoryMetadata,
+    pub vector: Option<Vec<f32>>,
+}
+
+impl SearchResult {
+    pub fn new(user: &UserMemories, id: String, score: f32, with_vector: bool) -> Self {
+        SearchResult {
+            text: user.memories_text.get(&id).cloned().unwrap_or_default(),
+            metadata: user.metadata.get(&id).cloned().unwrap_or_default(),
+            vector: if with_vector { user.memories.get(&id) } else { None },
+            rerank_score: None,
+            score,
+            id,
+        }
+    }
+
+    // The score the result was ranked by in the end
+    pub fn final_score(&self) -> f32 {
+        self.rerank_score.unwrap_or(self.score)
+    }
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        SearchOptions {
+            top_k: MEMORY_TOP_K,
+            vector_weight: HYBRID_VECTOR_WEIGHT,
+            keyword_weight: HYBRID_KEYWORD_WEIGHT,
+            fusion: Fusion::default(),
+            recency_weight: RECENCY_WEIGHT,
+            importance_weight: IMPORTANCE_WEIGHT,
+            half_life_hours: RECENCY_HALF_LIFE_HOURS,
+            mmr_lambda: None,
+            reranker: None,
+            rerank_top_n: RERANK_TOP_N,
+            min_score: None,
+            with_vectors: false,
+        }
+    }
+}
+
+impl SearchOptions {
+    pub fn with_top_k(mut self, top_k: usize) -> Self {
+        self.top_k = top_k;
+        self
+    }
+
+    pub fn with_weights(mut self, vector_weight: f32, keyword_weight: f32) -> Self {
+        self.vector_weight = vector_weight.max(0.0);
+        self.keyword_weight = keyword_weight.max(0.0);
+        self
+    }
+
+    pub fn with_fusion(mut self, fusion: Fusion) -> Self {
+        self.fusion = fusion;
+        self
+    }
+
+    pub fn with_recency(mut self, weight: f32, half_life_hours: f32) -> Self {
+        self.recency_weight = weight.max(0.0);
+        self.half_life_hours = half_life_hours;
+        self
+    }
+
+    pub fn with_importance(mut self, weight: f32) -> Self {
+        self.importance_weight = weight.max(0.0);
+        self
+    }
+
+    pub fn with_mmr(mut self, lambda: f32) -> Self {
+        self.mmr_lambda = Some(lambda.clamp(0.0, 1.0));
+        self
+    }
+
+    pub fn with_reranker(mut self, reranker: Reranker, top_n: usize) -> Self {
+        self.reranker = Some(reranker);
+        self.rerank_top_n = top_n;
+        self
+    }
+
+    pub fn with_min_score(mut self, min_score: f32) -> Self {
+        self.min_score = Some(min_score);
+        self
+    }
+
+    pub fn with_vectors(mut self, with_vectors: bool) -> Self {
+        self.with_vectors = with_vectors;
+        self
+    }
+
+    // The options the chat uses for every message. MMR is turned on by
+    // setting MEMORY_MMR_LAMBDA, reranking by MEMORY_RERANK, and
+    // MEMORY_MIN_SCORE keeps weak matches out of the prompt.
+    // MEMORY_RECENCY_WEIGHT, MEMORY_IMPORTANCE_WEIGHT and
+    // MEMORY_HALF_LIFE_HOURS replace the defaults from utils.rs
+    pub fn from_env() -> Self {
+        SearchOptions {
+            recency_weight: number_from_env("MEMORY_RECENCY_WEIGHT").map_or(RECENCY_WEIGHT, |w| w.max(0.0)),
+            importance_weight: number_from_env("MEMORY_IMPORTANCE_WEIGHT").map_or(IMPORTANCE_WEIGHT, |w| w.max(0.0)),
+            half_life_hours: number_from_env("MEMORY_HALF_LIFE_HOURS").unwrap_or(RECENCY_HALF_LIFE_HOURS),
+            reranker: Reranker::from_env(),
+            mmr_lambda: number_from_env("MEMORY_MMR_LAMBDA").map(|lambda| lambda.clamp(0.0, 1.0)),
+            min_score: number_from_env("MEMORY_MIN_SCORE"),
+            ..Default::default()
+        }
+    }
+
+    // Reads options given in front of a query in the REPL, like
+    // "k=5 vector=0.5 keyword=0.5 recency=0.3 half_life=24 rrf mmr=0.7 rerank=chat min=0.4 what was my ticket number".
+    // Returns the options and whatever is left as the query
+    pub fn parse_args(args: &str) -> (Self, String) {
+        let mut options = SearchOptions::default();
+        let mut words = args.split_whitespace().peekable();
+
+        while let Some(word) = words.peek() {
+            let parsed = match word.split_once('=') {
+                Some(("k", value)) => value.parse().map(|k| options.top_k = k).is_ok(),
+                Some(("vector", value)) => value.parse().map(|w: f32| options.vector_weight = w.max(0.0)).is_ok(),
+                Some(("keyword", value)) => value.parse().map(|w: f32| options.keyword_weight = w.max(0.0)).is_ok(),
+                Some(("recency", value)) => value.parse().map(|w: f32| options.recency_weight = w.max(0.0)).is_ok(),
+                Some(("importance", value)) => value.parse().map(|w: f32| options.importance_weight = w.max(0.0)).is_ok(),
+                Some(("half_life", value)) => value.parse().map(|h| options.half_life_hours = h).is_ok(),
+                Some(("mmr", value)) => value.parse().map(|l: f32| options.mmr_lambda = Some(l.clamp(0.0, 1.0))).is_ok(),
+                Some(("rerank", value)) => Reranker::parse(value).map(|r| options.reranker = Some(r)).is_some(),
+                Some(("rerank_n", value)) => value.parse().map(|n| options.rerank_top_n = n).is_ok(),
+                Some(("min", value)) => value.parse().map(|m| options.min_score = Some(m)).is_ok(),
+                _ => match *word {
+                    "rrf" => { options.fusion = Fusion::Rrf; true }
+                    "sum" => { options.fusion = Fusion::WeightedSum; true }
+                    "mmr" => { options.mmr_lambda = Some(MMR_DEFAULT_LAMBDA); true }
+                    "rerank" => { options.reranker = Reranker::parse(""); true }
+                    "vectors" => { options.with_vectors = true; true }
+                    _ => false
+                }
+            };
+
+            if !parsed {
+                break;
+            }
+            words.next();
+        }
+
+        (options, words.collect::<Vec<&str>>().join(" "))
+    }
+}
+
+fn number_from_env(name: &str) -> Option<f32> {
+    let value = load_environment(name);
+    if value.trim().is_empty() {
+        return None;
+    }
+
+    value.trim().parse::<f32>()
+        .inspect_err(|_| warn!("{} '{}' is not a number, ignoring it", name, value))
+        .ok()
+}
+
+impl UserMemories {
+    // The keyword index is built from the texts the first time it is
+    // needed, and kept up to date by apply_entry after that
+    pub fn keywords(&self) -> &Bm25Index {
+        self.keywords.get_or_init(|| {
+            let index = Bm25Index::build(self.memories_text.iter());
+            debug!("Built keyword index over {} memories of user '{}'", index.len(), self.user_id);
+            index
+        })
+    }
+
+    pub fn keywords_mut(&mut self) -> Option<&mut Bm25Index> {
+        self.keywords.get_mut()
+    }
+}
+
+// Combines a vector search and a BM25 keyword search over the memories
+// of one user, and returns the best top_k (key, score) pairs.
+// Without a query vector, or with a vector weight of 0, only the
+// keywords count, and the other way round.
+// With MMR, a bigger pool of candidates is fetched and top_k of them
+// are picked to be relevant but not repeat each other
+pub fn hybrid_search(user: &UserMemories, user_id: &str, query: &str, query_vector: Option<&[f32]>, options: &SearchOptions) -> Vec<(String, f32)> {
+    // Recency, importance and MMR can all pull results up from below
+    // the top_k by relevance, so they get a bigger pool to pick from
+    let memory_stream = options.recency_weight > 0.0 || options.importance_weight > 0.0;
+    let pool_size = if memory_stream || options.mmr_lambda.is_some() {
+        options.top_k * HYBRID_OVERSAMPLE
+    } else {
+        options.top_k
+    };
+
+    let mut pool = candidates(user, user_id, query, query_vector, options, pool_size);
+    if memory_stream {
+        pool = memory_stream_scores(user, pool, options, now_unix());
+    }
+
+    match options.mmr_lambda {
+        Some(lambda) => maximal_marginal_relevance(user, pool, options.top_k, lambda),
+        None => {
+            pool.truncate(options.top_k);
+            pool
+        }
+    }
+}
+
+// How recent a memory is, 1 when it was just added, halving every
+// half_life_hours. Memories without a timestamp are not recent at all
+pub fn recency(created_at: u64, now: u64, half_life_hours: f32) -> f32 {
+    if created_at == 0 || half_life_hours <= 0.0 {
+        return 0.0;
+    }
+    let age_hours = now.saturating_sub(created_at) as f32 / 3600.0;
+    0.5f32.powf(age_hours / half_life_hours)
+}
+
+// Rescores the candidates as relevance + recency_weight * recency +
+// importance_weight * importance and sorts them by that. Relevance is
+// the score scaled to 0..1 by the best one, so it is the same for both
+// fusions, and every part of the sum is in 0..1. A memory said again
+// later (see dedup.rs) counts as recent as the last time it was said
+fn memory_stream_scores(user: &UserMemories, candidates: Vec<(String, f32)>, options: &SearchOptions, now: u64) -> Vec<(String, f32)> {
+    let highest = candidates.iter().map(|(_, s)| *s).fold(0.0f32, f32::max);
+
+    let mut scored: Vec<(String, f32)> = candidates.into_iter()
+        .map(|(key, score)| {
+            let relevance = if highest > 0.0 { score.max(0.0) / highest } else { 0.0 };
+            let metadata = user.metadata.get(&key);
+            let recency = metadata.map_or(0.0, |meta| recency(meta.created_at.max(meta.last_seen), now, options.half_life_hours));
+            let importance = metadata.and_then(|meta| meta.importance).unwrap_or(DEFAULT_IMPORTANCE);
+
+            let score = relevance + options.recency_weight * recency + options.importance_weight * importance;
+            (key, score)
+        })
+        .collect();
+
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    scored
+}
+
+// The best n (key, score) pairs of the hybrid search.
+// Memories whose metadata names another user are skipped, just in case
+// a broken file ever mixed them up. Checking that loads the metadata of
+// a memory-mapped collection (see lazy.rs).
+// Memories that were consolidated into a summary which still exists are
+// skipped as well, the summary stands in for them (see consolidate.rs)
+fn candidates(user: &UserMemories, user_id: &str, query: &str, query_vector: Option<&[f32]>, options: &SearchOptions, n: usize) -> Vec<(String, f32)> {
+    let filter = |key: &str| {
+        let Some(meta) = user.metadata.get(key) else {
+            return true;
+        };
+        if meta.user_id != user_id {
+            warn!("Memory '{}' is tagged with another user, skipping it", key);
+            return false;
+        }
+        meta.summarized_in.as_ref().is_none_or(|summary| !user.memories.contains_key(summary))
+    };
+
+    let query_vector = query_vector.filter(|_| options.vector_weight > 0.0);
+    let use_keywords = options.keyword_weight > 0.0;
+    let pool = n * HYBRID_OVERSAMPLE;
+
+    let by_vector: Vec<(String, f32)> = match query_vector {
+        Some(q) => user.memories.search(q, if use_keywords { pool } else { n }, filter),
+        None => vec![]
+    };
+    if !use_keywords {
+        return by_vector;
+    }
+
+    let by_keyword = user.keywords().search(query, pool, filter);
+    if query_vector.is_none() {
+        return by_keyword.into_iter().take(n).collect();
+    }
+
+    let mut fused: Vec<(String, f32)> = match options.fusion {
+        Fusion::Rrf => reciprocal_rank_fusion(&by_vector, &by_keyword, options),
+        Fusion::WeightedSum => {
+            // A result found by only one side still gets its score from
+            // the other, so both lists are compared on the same footing
+            let terms = search_terms(query);
+            let mut candidates: Vec<&String> = by_vector.iter().chain(by_keyword.iter()).map(|(key, _)| key).collect();
+            candidates.sort();
+            candidates.dedup();
+
+            let vector_scores: HashMap<&String, f32> = by_vector.iter().map(|(k, s)| (k, *s)).collect();
+            let keyword_scores: HashMap<&String, f32> = by_keyword.iter().map(|(k, s)| (k, *s)).collect();
+
+            let scored: Vec<(String, f32, f32)> = candidates.into_iter()
+                .map(|key| {
+                    let vector_score = vector_scores.get(key).copied().unwrap_or_else(|| {
+                        user.memories.get(key).map_or(0.0, |v| cosine_similarity_slices(query_vector.unwrap_or_default(), &v))
+                    });
+                    let keyword_score = keyword_scores.get(key).copied()
+                        .unwrap_or_else(|| user.keywords().score(&terms, key));
+                    (key.clone(), vector_score, keyword_score)
+                })
+                .collect();
+
+            let max_keyword = scored.iter().map(|(_, _, k)| *k).fold(0.0f32, f32::max);
+            scored.into_iter()
+                .map(|(key, vector_score, keyword_score)| {
+                    let keyword_score = if max_keyword > 0.0 { keyword_score / max_keyword } else { 0.0 };
+                    let score = options.vector_weight * vector_score.max(0.0) + options.keyword_weight * keyword_score;
+                    (key, score)
+                })
+                .collect()
+        }
+    };
+
+    fused.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    fused.truncate(n);
+    fused
+}
+
+// Picks top_k of the candidates one by one, each time taking the one with
+// the best lambda * relevance - (1 - lambda) * similarity to the closest
+// one picked so far. Relevance is the candidate's score scaled to 0..1,
+// whichever way it was computed. The scores handed back are the original
+// ones, only the order and the selection change
+pub fn maximal_marginal_relevance(user: &UserMemories, candidates: Vec<(String, f32)>, top_k: usize, lambda: f32) -> Vec<(String, f32)> {
+    if candidates.len() <= 1 {
+        return candidates;
+    }
+
+    // Scaling by the best score keeps cosine similarities as they are,
+    // and brings BM25 and RRF scores into the same range
+    let highest = candidates.iter().map(|(_, s)| *s).fold(0.0f32, f32::max);
+    let relevance = |score: f32| if highest > 0.0 { score.max(0.0) / highest } else { 0.0 };
+
+    let vectors: Vec<Option<Vec<f32>>> = candidates.iter().map(|(key, _)| user.memories.get(key)).collect();
+    let mut remaining: Vec<usize> = (0..candidates.len()).collect();
+    // How similar each candidate is to the closest one already picked
+    let mut redundancy: Vec<f32> = vec![0.0; candidates.len()];
+    let mut picked: Vec<usize> = vec![];
+
+    while picked.len() < top_k && !remaining.is_empty() {
+        let (position, _) = remaining.iter()
+            .enumerate()
+            .map(|(position, i)| (position, lambda * relevance(candidates[*i].1) - (1.0 - lambda) * redundancy[*i]))
+            .fold((0, f32::NEG_INFINITY), |best, current| if current.1 > best.1 { current } else { best });
+
+        let chosen = remaining.remove(position);
+        picked.push(chosen);
+
+        if let Some(chosen_vector) = &vectors[chosen] {
+            for i in &remaining {
+                if let Some(vector) = &vectors[*i] {
+                    redundancy[*i] = redundancy[*i].max(cosine_similarity_slices(chosen_vector, vector));
+                }
+            }
+        }
+    }
+
+    picked.into_iter().map(|i| candidates[i].clone()).collect()
+}
+
+// Every list adds weight / (RRF_K + rank) for each result in it
+fn reciprocal_rank_fusion(by_vector: &[(String, f32)], by_keyword: &[(String, f32)], options: &SearchOptions) -> Vec<(String, f32)> {
+    let mut scores: HashMap<&String, f32> = HashMap::new();
+
+    for (list, weight) in [(by_vector, options.vector_weight), (by_keyword, options.keyword_weight)] {
+        for (rank, (key, _)) in list.iter().enumerate() {
+            *scores.entry(key).or_insert(0.0) += weight / (RRF_K + rank as f32 + 1.0);
+        }
+    }
+
+    scores.into_iter().map(|(key, score)| (key.clone(), score)).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lazy::Lazy;
+    use crate::vectors::{ Precision, VectorStore };
+
+    // A user with the given (key, text, vector) memories, all tagged with them
+    fn user(memories: &[(&str, &str, [f32; 2])]) -> UserMemories {
+        let mut vectors = VectorStore::new(Precision::F32, false);
+        let mut texts: HashMap<String, String> = HashMap::new();
+        let mut metadata: HashMap<String, MemoryMetadata> = HashMap::new();
+        for (key, text, vector) in memories {
+            vectors.insert(key, vector);
+            texts.insert(key.to_string(), text.to_string());
+            metadata.insert(key.to_string(), MemoryMetadata { user_id: "tester".to_string(), ..Default::default() });
+        }
+
+        UserMemories {
+            user_id: "tester".to_string(),
+            memories: vectors,
+            memories_text: Lazy::from(texts),
+            metadata: Lazy::from(metadata),
+            ..Default::default()
+        }
+    }
+
+    // Relevance only, nothing on top of it
+    fn options(vector_weight: f32, keyword_weight: f32) -> SearchOptions {
+        SearchOptions::default()
+            .with_top_k(3)
+            .with_weights(vector_weight, keyword_weight)
+            .with_recency(0.0, RECENCY_HALF_LIFE_HOURS)
+            .with_importance(0.0)
+    }
+
+    fn keys(results: &[(String, f32)]) -> Vec<&str> {
+        results.iter().map(|(key, _)| key.as_str()).collect()
+    }
+
+    // "vector" only matches the query vector, "keyword" only the query
+    // text, "both" a bit of each
+    fn mixed() -> UserMemories {
+        user(&[
+            ("vector", "an apple pie recipe", [1.0, 0.0]),
+            ("keyword", "error code E42", [0.0, 1.0]),
+            ("both", "error code E42 while baking an apple pie", [0.8, 0.6]),
+        ])
+    }
+
+    #[test]
+    fn weighted_sum_adds_up_both_sides() {
+        let user = mixed();
+        let query = [1.0, 0.0];
+
+        let by_vector = hybrid_search(&user, "tester", "E42", Some(&query), &options(1.0, 0.0));
+        assert_eq!(keys(&by_vector), vec!["vector", "both", "keyword"]);
+
+        let by_keyword = hybrid_search(&user, "tester", "E42", Some(&query), &options(0.0, 1.0));
+        assert_eq!(keys(&by_keyword)[..2].to_vec(), vec!["keyword", "both"]);
+
+        // Keyword scores are scaled by the best one before they are added
+        let terms = search_terms("E42");
+        let best_keyword = user.keywords().score(&terms, "keyword");
+        let ratio = user.keywords().score(&terms, "both") / best_keyword;
+
+        // "vector" and "keyword" both get half, ties go by key
+        let fused = hybrid_search(&user, "tester", "E42", Some(&query), &options(0.5, 0.5));
+        assert_eq!(keys(&fused), vec!["both", "keyword", "vector"]);
+        assert!((fused[0].1 - (0.5 * 0.8 + 0.5 * ratio)).abs() < 1e-5);
+        assert!((fused[1].1 - 0.5).abs() < 1e-5);
+        assert!((fused[2].1 - 0.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn rrf_only_looks_at_ranks() {
+        let list = |keys: &[&str]| -> Vec<(String, f32)> {
+            keys.iter().enumerate().map(|(i, k)| (k.to_string(), 100.0 - i as f32)).collect()
+        };
+        let by_vector = list(&["x", "y"]);
+        let by_keyword = list(&["y", "z"]);
+
+        let mut fused = reciprocal_rank_fusion(&by_vector, &by_keyword, &options(1.0, 1.0));
+        fused.sort_by(|a, b| b.1.total_cmp(&a.1));
+        assert_eq!(keys(&fused), vec!["y", "x", "z"]);
+        assert!((fused[0].1 - (1.0 / (RRF_K + 2.0) + 1.0 / (RRF_K + 1.0))).abs() < 1e-6);
+        assert!((fused[1].1 - 1.0 / (RRF_K + 1.0)).abs() < 1e-6);
+        assert!((fused[2].1 - 1.0 / (RRF_K + 2.0)).abs() < 1e-6);
+
+        // Weights scale what each list adds
+        let weighted = reciprocal_rank_fusion(&by_vector, &by_keyword, &options(0.0, 1.0));
+        let x = weighted.iter().find(|(key, _)| key == "x").unwrap();
+        assert_eq!(x.1, 0.0);
+    }
+
+    #[test]
+    fn mmr_skips_near_duplicates() {
+        let user = user(&[
+            ("first", "my ticket is 1042", [1.0, 0.0]),
+            ("again", "my ticket is 1042 again", [1.0, 0.01]),
+            ("other", "I live in Berlin", [0.0, 1.0]),
+        ]);
+        let candidates = vec![("first".to_string(), 1.0), ("again".to_string(), 0.99), ("other".to_string(), 0.5)];
+
+        let relevant = maximal_marginal_relevance(&user, candidates.clone(), 2, 1.0);
+        assert_eq!(keys(&relevant), vec!["first", "again"]);
+
+        // The scores handed back are the ones the candidates came with
+        let diverse = maximal_marginal_relevance(&user, candidates, 2, 0.5);
+        assert_eq!(diverse, vec![("first".to_string(), 1.0), ("other".to_string(), 0.5)]);
+    }
+
+    #[test]
+    fn foreign_memories_are_left_out() {
+        let mut user = mixed();
+        user.metadata.get_mut("vector").unwrap().user_id = "someone else".to_string();
+
+        let results = hybrid_search(&user, "tester", "E42", Some(&[1.0, 0.0]), &options(1.0, 0.0));
+        assert_eq!(keys(&results), vec!["both", "keyword"]);
+    }
+}
@@ -0,0 +1,249 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use log::*;
+use memmap2::MmapMut;
+use serde::{ Deserialize, Serialize };
+
+use crate::utils::SEGMENT_BYTES;
+
+
+const SEGMENT_MAGIC: &[u8; 4] = b"VSEG";
+const SEGMENT_VERSION: u32 = 1;
+const PAGE_SIZE: usize = 4096;
+// Every record starts with the norm of its vector, padded to 8 bytes
+const RECORD_HEADER: usize = 8;
+
+// Where the rows of a memory-mapped collection live on disk, written into
+// the memory file of the user. Records past len are left over from before
+// a crash and get overwritten when the write-ahead log is replayed
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SegmentManifest {
+    pub dir: PathBuf,
+    pub generation: u64,
+    pub row_bytes: usize,
+    pub per_segment: usize,
+    pub len: usize,
+}
+
+// Append-only storage of vector rows in memory-mapped segment files.
+// Each generation of a collection is a directory of equally sized
+// segment files: one header page, then fixed size record slots.
+// A slot is a power of two bytes big (or whole pages for huge rows), so
+// records never straddle a page and the operating system only has to
+// page in what a search actually reads.
+// Records are never changed once written. Replacing a vector appends a
+// new record, and a compaction copies the live ones into a new generation
+pub struct SegmentStore {
+    pub dir: PathBuf,
+    pub generation: u64,
+    pub row_bytes: usize,
+    pub stride: usize,
+    pub per_segment: usize,
+    pub len: usize,
+    maps: Vec<MmapMut>,
+    // Segments from this one on were written to since the last flush
+    dirty_from: usize,
+}
+
+impl std::fmt::Debug for SegmentStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SegmentStore")
+            .field("dir", &self.dir)
+            .field("generation", &self.generation)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+fn record_stride(row_bytes: usize) -> usize {
+    let record = RECORD_HEADER + row_bytes;
+    if record <= PAGE_SIZE {
+        record.next_power_of_two().max(64)
+    } else {
+        record.div_ceil(PAGE_SIZE) * PAGE_SIZE
+    }
+}
+
+fn generation_dir(base: &Path, generation: u64) -> PathBuf {
+    base.join(format!("{:06}", generation))
+}
+
+fn segment_path(dir: &Path, segment: usize) -> PathBuf {
+    dir.join(format!("{:05}.seg", segment))
+}
+
+// The generations that exist below base, oldest first
+fn list_generations(base: &Path) -> Vec<u64> {
+    let Ok(entries) = fs::read_dir(base) else {
+        return vec![];
+    };
+
+    let mut generations: Vec<u64> = entries
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u64>().ok())
+        .collect();
+    generations.sort();
+    generations
+}
+
+// Deletes every generation below base except the one in use
+pub fn remove_stale_generations(base: &Path, keep: u64) {
+    for generation in list_generations(base) {
+        if generation == keep {
+            continue;
+        }
+
+        let dir = generation_dir(base, generation);
+        match fs::remove_dir_all(&dir) {
+            Ok(()) => debug!("Removed old vector segments in {}", dir.display()),
+            Err(e) => warn!("Failed to remove old vector segments in {}: {e}", dir.display())
+        }
+    }
+}
+
+impl SegmentStore {
+    // Starts an empty generation below base, one newer than any there
+    pub fn create(base: &Path, row_bytes: usize) -> std::io::Result<Self> {
+        let generation = list_generations(base).last().map_or(0, |g| g + 1);
+        let dir = generation_dir(base, generation);
+        fs::create_dir_all(&dir)?;
+
+        let stride = record_stride(row_bytes);
+        Ok(SegmentStore {
+            dir,
+            generation,
+            row_bytes,
+            stride,
+            per_segment: (SEGMENT_BYTES / stride).max(1),
+            len: 0,
+            maps: vec![],
+            dirty_from: 0,
+        })
+    }
+
+    // Maps the segment files a manifest points to. Only the records up to
+    // the manifest's length count, the files themselves are not read
+    pub fn open(manifest: &SegmentManifest) -> std::io::Result<Self> {
+        let stride = record_stride(manifest.row_bytes);
+        let mut store = SegmentStore {
+            dir: manifest.dir.clone(),
+            generation: manifest.generation,
+            row_bytes: manifest.row_bytes,
+            stride,
+            per_segment: manifest.per_segment.max(1),
+            len: manifest.len,
+            maps: vec![],
+            dirty_from: 0,
+        };
+
+        let segments = manifest.len.div_ceil(store.per_segment);
+        for segment in 0..segments {
+            store.map_segment(segment, false)?;
+        }
+        store.dirty_from = store.maps.len();
+
+        debug!("Mapped {} vector records from {}", store.len, store.dir.display());
+        Ok(store)
+    }
+
+    pub fn manifest(&self) -> SegmentManifest {
+        SegmentManifest {
+            dir: self.dir.clone(),
+            generation: self.generation,
+            row_bytes: self.row_bytes,
+            per_segment: self.per_segment,
+            len: self.len,
+        }
+    }
+
+    fn segment_bytes(&self) -> usize {
+        PAGE_SIZE + self.per_segment * self.stride
+    }
+
+    // Maps one segment file, creating it first if asked to. A new file
+    // is sized up front, which costs nothing on disk until it is written
+    fn map_segment(&mut self, segment: usize, create: bool) -> std::io::Result<()> {
+        let path = segment_path(&self.dir, segment);
+        let file = OpenOptions::new().read(true).write(true).create(create).truncate(false).open(&path)?;
+        let size = self.segment_bytes() as u64;
+
+        if create {
+            file.set_len(size)?;
+        } else if file.metadata()?.len() != size {
+            return Err(Error::new(ErrorKind::InvalidData, format!("{} has the wrong size", path.display())));
+        }
+
+        // Safe as long as nothing else changes the file while it is mapped,
+        // the segment directories belong to this process alone
+        let mut map = unsafe { MmapMut::map_mut(&file)? };
+
+        if create {
+            map[0..4].copy_from_slice(SEGMENT_MAGIC);
+            map[4..8].copy_from_slice(&SEGMENT_VERSION.to_le_bytes());
+            map[8..16].copy_from_slice(&(self.row_bytes as u64).to_le_bytes());
+            map[16..24].copy_from_slice(&(self.stride as u64).to_le_bytes());
+        } else {
+            let version = u32::from_le_bytes([map[4], map[5], map[6], map[7]]);
+            let row_bytes = u64::from_le_bytes(map[8..16].try_into().unwrap_or_default());
+            if &map[0..4] != SEGMENT_MAGIC || version != SEGMENT_VERSION || row_bytes != self.row_bytes as u64 {
+                return Err(Error::new(ErrorKind::InvalidData, format!("{} is not a matching vector segment", path.display())));
+            }
+        }
+
+        self.maps.push(map);
+        Ok(())
+    }
+
+    fn locate(&self, slot: usize) -> (usize, usize) {
+        let segment = slot / self.per_segment;
+        let offset = PAGE_SIZE + (slot % self.per_segment) * self.stride;
+        (segment, offset)
+    }
+
+    // Writes a new record and returns its slot
+    pub fn append(&mut self, norm: f32, row: &[u8]) -> std::io::Result<usize> {
+        if row.len() != self.row_bytes {
+            return Err(Error::new(ErrorKind::InvalidInput, "row has the wrong length for this segment"));
+        }
+
+        let slot = self.len;
+        let (segment, offset) = self.locate(slot);
+        if segment == self.maps.len() {
+            self.map_segment(segment, true)?;
+        }
+
+        let map = &mut self.maps[segment];
+        map[offset..offset + 4].copy_from_slice(&norm.to_le_bytes());
+        map[offset + RECORD_HEADER..offset + RECORD_HEADER + row.len()].copy_from_slice(row);
+
+        self.dirty_from = self.dirty_from.min(segment);
+        self.len += 1;
+        Ok(slot)
+    }
+
+    pub fn row(&self, slot: usize) -> &[u8] {
+        let (segment, offset) = self.locate(slot);
+        &self.maps[segment][offset + RECORD_HEADER..offset + RECORD_HEADER + self.row_bytes]
+    }
+
+    pub fn norm(&self, slot: usize) -> f32 {
+        let (segment, offset) = self.locate(slot);
+        let bytes = &self.maps[segment][offset..offset + 4];
+        f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    // Writes every changed segment back to the disk, this has to happen
+    // before a manifest pointing at the new records is saved
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        for map in self.maps.iter().skip(self.dirty_from) {
+            map.flush()?;
+        }
+        self.dirty_from = self.maps.len();
+
+        if let Ok(dir) = File::open(&self.dir) {
+            let _ = dir.sync_all();
+        }
+        Ok(())
+    }
+}
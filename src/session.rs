@@ -0,0 +1,61 @@
+use openai_api_rust::{Message, Role};
+
+use crate::custom_types::ChatSession;
+use crate::search::SearchResult;
+use crate::utils::{ now_unix, now_unix_millis, sanitize_id };
+
+
+impl ChatSession {
+    // Every new session starts with the system prompt, and gets an id
+    // made from the user id and the current time
+    pub fn new(user_id: &str, system_prompt: &str) -> Self {
+        ChatSession {
+            session_id: format!("{}-{}", sanitize_id(user_id), now_unix_millis()),
+            user_id: user_id.to_string(),
+            messages: vec![
+                Message {
+                    role: Role::System,
+                    content: system_prompt.to_string()
+                }
+            ],
+            title: None,
+            created_at: now_unix(),
+            updated_at: now_unix(),
+            archived: false,
+            summary: None,
+            summarized: 0,
+            turns: vec![],
+        }
+    }
+
+    // Here we build the messages we send to the model: the chat history,
+    // with the retrieved memories of this session's user added as an extra
+    // system message right before the latest user message. Memories of
+    // what the bot itself said are marked as such.
+    // The history itself is left untouched.
+    pub fn prompt_with_memories(&self, memories: &[SearchResult]) -> Vec<Message> {
+        let mut prompt: Vec<Message> = self.messages.clone();
+        if memories.is_empty() {
+            return prompt;
+        }
+
+        let content = format!("Things you remember about this user:\n{}", memory_lines(memories));
+
+        let position = prompt.len().saturating_sub(1);
+        prompt.insert(position, Message { role: Role::System, content });
+        prompt
+    }
+}
+
+// The memories as a list, one line each. Memories of what the bot
+// itself said are marked as such
+pub fn memory_lines(memories: &[SearchResult]) -> String {
+    let mut content = String::new();
+    for memory in memories {
+        content.push_str("- ");
+        content.push_str(memory.metadata.role.prompt_prefix());
+        content.push_str(memory.text.trim());
+        content.push('\n');
+    }
+    content
+}
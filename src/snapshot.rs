@@ -0,0 +1,276 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use log::*;
+use serde::{ Deserialize, Serialize };
+
+use crate::custom_types::{ MemoryStore, UserMemories };
+use crate::utils::{ now_unix, sanitize_id, SNAPSHOT_DIR };
+use crate::wal::write_atomic;
+
+static SNAPSHOT_VERSION: u32 = 1;
+static SNAPSHOT_EXTENSION: &str = "snap";
+
+
+// The first line of every snapshot file. It describes the snapshot and
+// carries the checksum of everything that follows it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SnapshotManifest {
+    pub version: u32,
+    pub name: String,
+    pub user_id: String,
+    pub created_at: u64,
+    pub memory_count: usize,
+    pub checksum: u32,
+}
+
+fn snapshot_dir(user_id: &str) -> PathBuf {
+    Path::new(SNAPSHOT_DIR).join(sanitize_id(user_id))
+}
+
+fn snapshot_path(user_id: &str, name: &str) -> PathBuf {
+    snapshot_dir(user_id).join(format!("{}.{}", sanitize_id(name), SNAPSHOT_EXTENSION))
+}
+
+// Writes the whole collection of a user (vectors, texts, metadata and
+// everything else stored in UserMemories) into a single file:
+// a manifest line followed by the collection as JSON.
+// Without a label the snapshot is named after the current time, with a
+// number added if there already is one from the same second. A label
+// that is already taken is refused rather than overwritten
+pub fn create_snapshot(store: &mut MemoryStore, user_id: &str, label: Option<&str>) -> std::io::Result<SnapshotManifest> {
+    // Everything in the write-ahead log is already applied in memory,
+    // flushing just makes sure the log and the snapshot agree on disk
+    store.flush();
+
+    let user: &UserMemories = store.user_mut(user_id);
+    let payload: String = serde_json::to_string(user)?;
+    let created_at = now_unix();
+
+    let name = match label {
+        Some(l) if !l.trim().is_empty() => {
+            if snapshot_path(user_id, l.trim()).exists() {
+                return Err(Error::new(ErrorKind::AlreadyExists, format!("there already is a snapshot named '{}'", l.trim())));
+            }
+            l.trim().to_string()
+        }
+        _ => {
+            let mut name = format!("{}", created_at);
+            let mut n = 2;
+            while snapshot_path(user_id, &name).exists() {
+                name = format!("{}-{}", created_at, n);
+                n += 1;
+            }
+            name
+        }
+    };
+
+    let manifest = SnapshotManifest {
+        version: SNAPSHOT_VERSION,
+        name: name.clone(),
+        user_id: user_id.to_string(),
+        created_at,
+        memory_count: user.memories.len(),
+        checksum: crc32fast::hash(payload.as_bytes()),
+    };
+
+    let mut contents = serde_json::to_string(&manifest)?;
+    contents.push('\n');
+    contents.push_str(&payload);
+
+    let path = snapshot_path(user_id, &name);
+    fs::create_dir_all(snapshot_dir(user_id))?;
+    write_atomic(&path, contents.as_bytes())?;
+
+    info!("Created snapshot '{}' of user '{}' with {} memories at {}", name, user_id, manifest.memory_count, path.display());
+    Ok(manifest)
+}
+
+// Lists the snapshots of a user, oldest first. Only the manifest line
+// is parsed here, the payload is left alone
+pub fn list_snapshots(user_id: &str) -> Vec<SnapshotManifest> {
+    let Ok(entries) = fs::read_dir(snapshot_dir(user_id)) else {
+        return vec![];
+    };
+
+    let mut result: Vec<SnapshotManifest> = vec![];
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != SNAPSHOT_EXTENSION) {
+            continue;
+        }
+
+        match read_manifest(&path) {
+            Ok(manifest) => result.push(manifest),
+            Err(e) => warn!("Skipping unreadable snapshot {}: {e}", path.display())
+        }
+    }
+
+    result.sort_by(|a, b| (a.created_at, &a.name).cmp(&(b.created_at, &b.name)));
+    result
+}
+
+// Only the first line of a snapshot, so listing them doesn't read
+// every payload
+fn read_manifest(path: &Path) -> std::io::Result<SnapshotManifest> {
+    let mut line = String::new();
+    BufReader::new(fs::File::open(path)?).read_line(&mut line)?;
+    if line.trim().is_empty() {
+        return Err(Error::new(ErrorKind::InvalidData, "snapshot has no manifest line"));
+    }
+
+    Ok(serde_json::from_str(line.trim_end())?)
+}
+
+fn read_snapshot_file(path: &Path) -> std::io::Result<(SnapshotManifest, String)> {
+    let contents = fs::read_to_string(path)?;
+    let Some((manifest_line, payload)) = contents.split_once('\n') else {
+        return Err(Error::new(ErrorKind::InvalidData, "snapshot has no manifest line"));
+    };
+
+    let manifest: SnapshotManifest = serde_json::from_str(manifest_line)?;
+    Ok((manifest, payload.to_string()))
+}
+
+// Reads a snapshot back, checks that it is intact and belongs to the
+// user, and replaces the user's memories with it. The restored state is
+// checkpointed right away, so the write-ahead log can't bring back
+// anything written after the snapshot was taken
+pub fn restore_snapshot(store: &mut MemoryStore, user_id: &str, name: &str) -> std::io::Result<SnapshotManifest> {
+    let path = snapshot_path(user_id, name);
+    let (manifest, payload) = read_snapshot_file(&path)?;
+
+    if manifest.version != SNAPSHOT_VERSION {
+        return Err(Error::new(ErrorKind::InvalidData, format!("unsupported snapshot version {}", manifest.version)));
+    }
+
+    let checksum = crc32fast::hash(payload.as_bytes());
+    if checksum != manifest.checksum {
+        error!("Checksum mismatch in {}: expected {:08x}, got {:08x}", path.display(), manifest.checksum, checksum);
+        return Err(Error::new(ErrorKind::InvalidData, "snapshot checksum does not match"));
+    }
+
+    let user: UserMemories = serde_json::from_str(&payload)?;
+    if manifest.user_id != user_id || user.user_id != user_id {
+        return Err(Error::new(ErrorKind::InvalidData, format!("snapshot belongs to user '{}'", user.user_id)));
+    }
+
+    // Loading the user first makes sure their write-ahead log is open,
+    // so the checkpoint below also empties it
+    store.user_mut(user_id);
+    store.users.insert(user_id.to_string(), user);
+    store.checkpoint(user_id)?;
+
+    info!("Restored snapshot '{}' of user '{}' with {} memories", manifest.name, user_id, manifest.memory_count);
+    Ok(manifest)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::custom_types::MemoryMetadata;
+    use crate::store::remove_user_files;
+
+    // A store with two memories of a user no other test uses
+    fn store_with_memories(user_id: &str) -> MemoryStore {
+        remove_user_files(user_id);
+        let _ = fs::remove_dir_all(snapshot_dir(user_id));
+
+        let mut store = MemoryStore::new();
+        let metadata = MemoryMetadata { user_id: user_id.to_string(), ..Default::default() };
+        store.insert_memory(user_id, "first", vec![1.0, 0.0], metadata.clone()).unwrap();
+        store.insert_memory(user_id, "second", vec![0.0, 1.0], metadata).unwrap();
+        store
+    }
+
+    fn clean_up(store: MemoryStore, user_id: &str) {
+        drop(store);
+        remove_user_files(user_id);
+        let _ = fs::remove_dir_all(snapshot_dir(user_id));
+    }
+
+    #[test]
+    fn restore_brings_back_what_was_saved() {
+        let user_id = format!("snapshot-roundtrip-{}", std::process::id());
+        let mut store = store_with_memories(&user_id);
+
+        let manifest = create_snapshot(&mut store, &user_id, Some("before")).unwrap();
+        assert_eq!(manifest.memory_count, 2);
+        assert_eq!(list_snapshots(&user_id).iter().map(|m| m.name.as_str()).collect::<Vec<&str>>(), vec!["before"]);
+
+        let key = store.insert_memory(&user_id, "third", vec![1.0, 1.0], MemoryMetadata { user_id: user_id.clone(), ..Default::default() }).unwrap();
+        assert_eq!(store.user_mut(&user_id).memories.len(), 3);
+
+        restore_snapshot(&mut store, &user_id, "before").unwrap();
+        let user = store.user_mut(&user_id);
+        assert_eq!(user.memories.len(), 2);
+        assert!(!user.memories.contains_key(&key));
+        assert_eq!(user.memories_text.values().filter(|t| *t == "first" || *t == "second").count(), 2);
+
+        // The restored state is what a fresh start sees as well
+        let mut reloaded = MemoryStore::new();
+        assert_eq!(reloaded.user_mut(&user_id).memories.len(), 2);
+
+        clean_up(store, &user_id);
+    }
+
+    #[test]
+    fn a_corrupted_payload_is_refused() {
+        let user_id = format!("snapshot-corrupt-{}", std::process::id());
+        let mut store = store_with_memories(&user_id);
+        create_snapshot(&mut store, &user_id, Some("good")).unwrap();
+
+        let path = snapshot_path(&user_id, "good");
+        let contents = fs::read_to_string(&path).unwrap().replacen("first", "forst", 1);
+        fs::write(&path, contents).unwrap();
+
+        let error = restore_snapshot(&mut store, &user_id, "good").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert_eq!(store.user_mut(&user_id).memories.len(), 2);
+
+        clean_up(store, &user_id);
+    }
+
+    #[test]
+    fn a_snapshot_of_another_user_is_refused() {
+        let user_id = format!("snapshot-owner-{}", std::process::id());
+        let other_id = format!("snapshot-other-{}", std::process::id());
+        let mut store = store_with_memories(&user_id);
+        create_snapshot(&mut store, &user_id, Some("mine")).unwrap();
+
+        // Copied into the snapshots of someone else, checksum and all
+        fs::create_dir_all(snapshot_dir(&other_id)).unwrap();
+        fs::copy(snapshot_path(&user_id, "mine"), snapshot_path(&other_id, "mine")).unwrap();
+
+        let error = restore_snapshot(&mut store, &other_id, "mine").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert!(store.user_mut(&other_id).memories.is_empty());
+
+        clean_up(store, &user_id);
+        remove_user_files(&other_id);
+        let _ = fs::remove_dir_all(snapshot_dir(&other_id));
+    }
+
+    #[test]
+    fn an_existing_label_is_never_overwritten() {
+        let user_id = format!("snapshot-label-{}", std::process::id());
+        let mut store = store_with_memories(&user_id);
+
+        create_snapshot(&mut store, &user_id, Some("kept")).unwrap();
+        store.insert_memory(&user_id, "third", vec![1.0, 1.0], MemoryMetadata { user_id: user_id.clone(), ..Default::default() }).unwrap();
+
+        let error = create_snapshot(&mut store, &user_id, Some("kept")).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(read_manifest(&snapshot_path(&user_id, "kept")).unwrap().memory_count, 2);
+
+        // Unlabelled snapshots of the same second get names of their own
+        let first = create_snapshot(&mut store, &user_id, None).unwrap();
+        let second = create_snapshot(&mut store, &user_id, None).unwrap();
+        assert_ne!(first.name, second.name);
+        assert_eq!(list_snapshots(&user_id).len(), 3);
+
+        clean_up(store, &user_id);
+    }
+}
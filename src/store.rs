@@ -1,27 +1,439 @@
 use std::collections::HashMap;
-use std::usize;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use log::*;
+use serde::Serialize;
 
-use ndarray::{Array1};
 use openai_api_rust::embeddings::{*};
 
-use crate::memory::{cosine_similarity, generate_embeddings};
-use crate::utils::text_to_vec;
+use crate::custom_types::{ ChatSession, MemoryMetadata, MemoryRole, MemoryStore, UserMemories };
+use crate::dedup::{ record_duplicate, DedupOptions, DedupPolicy };
+use crate::export::ExportRecord;
+use crate::importance::Importance;
+use crate::lazy::Lazy;
+use crate::memory::generate_embeddings;
+use crate::rerank::rerank_results;
+use crate::search::{ hybrid_search, SearchOptions, SearchResult };
+use crate::segments::remove_stale_generations;
+use crate::utils::{ now_unix, sanitize_id, text_to_vec, MEMORY_DIR, WAL_CHECKPOINT_EVERY };
+use crate::vectors::{ rescore_from_env, MappedVectors, Precision, Storage, VectorStore };
+use crate::wal::{ apply_entry, entry_fits, read_entries, write_atomic, FsyncPolicy, WalEntry, WriteAheadLog };
 
 
-pub fn add_memory(input: &str, memories: &mut HashMap<String, Vec<f64>>, count: &mut usize, memories_text: &mut HashMap<String, String>) -> () {
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore {
+            users: HashMap::new(),
+            wals: HashMap::new(),
+            fsync: FsyncPolicy::from_env(),
+        }
+    }
+
+    // Returns the memories of a user. The first time we see a user, their
+    // last snapshot is loaded from disk, everything in their write-ahead
+    // log is replayed on top of it, and the log is opened for new entries
+    pub fn user_mut(&mut self, user_id: &str) -> &mut UserMemories {
+        if !self.users.contains_key(user_id) {
+            let mut user = load_user_memories(user_id);
+
+            let wal_path = user_wal_path(user_id);
+            let entries = read_entries(&wal_path);
+            if !entries.is_empty() {
+                info!("Replaying {} write-ahead log entries of user '{}'", entries.len(), user_id);
+            }
+            for entry in entries {
+                apply_entry(&mut user, entry);
+            }
+
+            match WriteAheadLog::open(&wal_path, self.fsync) {
+                Ok(wal) => { self.wals.insert(user_id.to_string(), wal); }
+                Err(e) => error!("Failed to open write-ahead log of user '{}': {e}", user_id)
+            }
+
+            self.users.insert(user_id.to_string(), user);
+        }
+
+        self.users.get_mut(user_id).unwrap()
+    }
+
+    // Read-only access, this never touches the disk
+    pub fn user(&self, user_id: &str) -> Option<&UserMemories> {
+        self.users.get(user_id)
+    }
+
+    // Adds a new memory to a user and returns its key. The change is
+    // written to the write-ahead log first, if that fails the memory
+    // is not added at all
+    pub fn insert_memory(&mut self, user_id: &str, text: &str, vector: Vec<f32>, metadata: MemoryMetadata) -> Option<String> {
+        let user = self.user_mut(user_id);
+
+        // Imported memories can keep their own keys, so the next free
+        // "User Info" number isn't always the same as count
+        let mut count = user.count;
+        while user.memories.contains_key(&format!("User Info{}", count)) {
+            count += 1;
+        }
+
+        let key = format!("User Info{}", count);
+        let entry = WalEntry::Insert {
+            key: key.clone(),
+            vector,
+            text: text.to_string(),
+            metadata,
+            count: count + 1,
+        };
+
+        self.log_and_apply(user_id, entry)?;
+        Some(key)
+    }
+
+    // Same as insert_memory, but keeps the key the memory already has,
+    // replacing whatever was stored under that key before
+    pub fn insert_memory_at(&mut self, user_id: &str, key: &str, text: &str, vector: Vec<f32>, metadata: MemoryMetadata) -> Option<()> {
+        let count = self.user_mut(user_id).count;
+        let entry = WalEntry::Insert {
+            key: key.to_string(),
+            vector,
+            text: text.to_string(),
+            metadata,
+            count,
+        };
+
+        self.log_and_apply(user_id, entry)
+    }
+
+    // Removes a memory of a user, returns false if there was nothing to remove
+    pub fn delete_memory(&mut self, user_id: &str, key: &str) -> bool {
+        if !self.user_mut(user_id).memories.contains_key(key) {
+            return false;
+        }
+
+        self.log_and_apply(user_id, WalEntry::Delete { key: key.to_string() }).is_some()
+    }
+
+    // Replaces the metadata of a memory, the vector and text stay as they are
+    pub fn update_metadata(&mut self, user_id: &str, key: &str, metadata: MemoryMetadata) -> bool {
+        if !self.user_mut(user_id).memories.contains_key(key) {
+            return false;
+        }
+
+        self.log_and_apply(user_id, WalEntry::Metadata { key: key.to_string(), metadata }).is_some()
+    }
+
+    // Moves a memory out of the searchable memories into the archive file
+    // of the user, in the same format as a JSONL export so "/import jsonl"
+    // can bring it back. The archive is written and synced before the
+    // memory is deleted, a crash in between at worst archives it twice
+    pub fn archive_memory(&mut self, user_id: &str, key: &str) -> std::io::Result<bool> {
+        let user = self.user_mut(user_id);
+        let Some(vector) = user.memories.get(key) else {
+            return Ok(false);
+        };
+
+        let record = ExportRecord {
+            id: key.to_string(),
+            text: user.memories_text.get(key).cloned().unwrap_or_default(),
+            metadata: user.metadata.get(key).cloned().unwrap_or_default(),
+            vector,
+        };
+
+        let path = user_archive_path(user_id);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let mut line = serde_json::to_vec(&record).map_err(std::io::Error::other)?;
+        line.push(b'\n');
+        file.write_all(&line)?;
+        file.sync_all()?;
+
+        Ok(self.delete_memory(user_id, key))
+    }
+
+    fn log_and_apply(&mut self, user_id: &str, entry: WalEntry) -> Option<()> {
+        if !entry_fits(self.user_mut(user_id), &entry) {
+            return None;
+        }
+
+        let Some(wal) = self.wals.get_mut(user_id) else {
+            error!("No write-ahead log open for user '{}', refusing to change memories", user_id);
+            return None;
+        };
+
+        if let Err(e) = wal.append(&entry) {
+            error!("Failed to write to the write-ahead log of user '{}': {e}", user_id);
+            return None;
+        }
+        let wal_len = wal.len();
+
+        if !apply_entry(self.user_mut(user_id), entry) {
+            warn!("Change to the memories of user '{}' was logged but not applied", user_id);
+            return None;
+        }
+
+        if wal_len >= WAL_CHECKPOINT_EVERY
+            && let Err(e) = self.checkpoint(user_id) {
+            error!("Failed to checkpoint memories of user '{}': {e}", user_id);
+        }
+
+        Some(())
+    }
+
+    // Writes a full snapshot of one user with an atomic rename, and only
+    // then empties their write-ahead log, so a crash at any point leaves
+    // either the old snapshot plus the log, or the new snapshot behind.
+    // A memory-mapped collection flushes its segments first, and its
+    // memory file only says which records are live
+    pub fn checkpoint(&mut self, user_id: &str) -> std::io::Result<()> {
+        let Some(user) = self.users.get_mut(user_id) else {
+            warn!("Tried to save memories of unknown user '{}'", user_id);
+            return Ok(());
+        };
+
+        let path = user_memories_path(user_id);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let segments_base = user_segments_path(user_id);
+        if user.memories.storage == Storage::Mapped && !user.memories.is_mapped() {
+            user.memories.map_to(&segments_base)?;
+        }
+        user.memories.compact_if_needed()?;
+
+        if user.memories.is_mapped() {
+            user.memories_text.save_to(&user_text_path(user_id))?;
+            user.metadata.save_to(&user_metadata_path(user_id))?;
+        }
+
+        let count = user.count;
+        let json = match user.memories.sync_segments()? {
+            Some(memories) => serde_json::to_string(&MappedUserMemories { user_id, memories, count })?,
+            None => serde_json::to_string(user)?
+        };
+        write_atomic(&path, json.as_bytes())?;
+        info!("Saved {} memories of user '{}' to {}", user.memories.len(), user_id, path.display());
+
+        // Only now nothing points to older segments any more
+        match user.memories.segment_generation() {
+            Some(generation) => {
+                remove_stale_generations(&segments_base, generation);
+                user.memories.remove_stale_catalogs();
+            }
+            None => remove_mapped_files(user_id)
+        }
+
+        if let Some(wal) = self.wals.get_mut(user_id) {
+            wal.truncate()?;
+        }
+
+        Ok(())
+    }
+
+    // Switches the vector precision of a user's collection. The vectors are
+    // re-encoded in memory and a checkpoint writes them out right away
+    pub fn set_precision(&mut self, user_id: &str, precision: Precision, rescore: bool) -> std::io::Result<()> {
+        let user = self.user_mut(user_id);
+        user.memories.set_precision(precision, rescore);
+        info!("Switched memories of user '{}' to {:?} (rescore: {})", user_id, precision, rescore);
+
+        self.checkpoint(user_id)
+    }
+
+    // Moves the vectors of a user into memory-mapped segments or back onto
+    // the heap. The checkpoint does the actual mapping
+    pub fn set_storage(&mut self, user_id: &str, storage: Storage) -> std::io::Result<()> {
+        let user = self.user_mut(user_id);
+        user.memories.storage = storage;
+        if storage == Storage::Memory {
+            user.memories.unmap();
+        }
+        info!("Switched memories of user '{}' to {:?} storage", user_id, storage);
+
+        self.checkpoint(user_id)
+    }
+
+    // Makes sure everything in the write-ahead logs has reached the disk
+    pub fn flush(&mut self) {
+        for (user_id, wal) in self.wals.iter_mut() {
+            if let Err(e) = wal.sync() {
+                error!("Failed to flush write-ahead log of user '{}': {e}", user_id);
+            }
+        }
+    }
+
+    // Called before exiting: flushes every log and writes a fresh
+    // snapshot for every user that has pending log entries
+    pub fn shutdown(&mut self) {
+        info!("Flushing memories before exit...");
+        self.flush();
+
+        let pending: Vec<String> = self.wals.iter()
+            .filter(|(_, wal)| !wal.is_empty())
+            .map(|(user_id, _)| user_id.clone())
+            .collect();
+
+        for user_id in pending {
+            if let Err(e) = self.checkpoint(&user_id) {
+                error!("Failed to checkpoint memories of user '{}': {e}", user_id);
+            }
+        }
+    }
+}
+
+fn user_memories_path(user_id: &str) -> PathBuf {
+    Path::new(MEMORY_DIR).join(format!("{}.json", sanitize_id(user_id)))
+}
+
+pub(crate) fn user_wal_path(user_id: &str) -> PathBuf {
+    Path::new(MEMORY_DIR).join(format!("{}.wal", sanitize_id(user_id)))
+}
+
+fn user_archive_path(user_id: &str) -> PathBuf {
+    Path::new(MEMORY_DIR).join(format!("{}.archive.jsonl", sanitize_id(user_id)))
+}
+
+fn user_segments_path(user_id: &str) -> PathBuf {
+    Path::new(MEMORY_DIR).join(format!("{}.vectors", sanitize_id(user_id)))
+}
+
+fn user_text_path(user_id: &str) -> PathBuf {
+    Path::new(MEMORY_DIR).join(format!("{}.text.json", sanitize_id(user_id)))
+}
+
+fn user_metadata_path(user_id: &str) -> PathBuf {
+    Path::new(MEMORY_DIR).join(format!("{}.meta.json", sanitize_id(user_id)))
+}
+
+// Tests work on the real memory directory with users of their own, and
+// clean up after themselves with this
+#[cfg(test)]
+pub(crate) fn remove_user_files(user_id: &str) {
+    for path in [user_memories_path(user_id), user_wal_path(user_id), user_archive_path(user_id), user_text_path(user_id), user_metadata_path(user_id)] {
+        let _ = fs::remove_file(path);
+    }
+    let _ = fs::remove_dir_all(user_segments_path(user_id));
+}
+
+// The memory file of a user whose vectors are memory-mapped
+#[derive(Serialize)]
+struct MappedUserMemories<'a> {
+    user_id: &'a str,
+    memories: MappedVectors<'a>,
+    count: usize,
+}
+
+// Cleans up after a user whose vectors went back into memory
+fn remove_mapped_files(user_id: &str) {
+    let segments = user_segments_path(user_id);
+    if segments.exists()
+        && let Err(e) = fs::remove_dir_all(&segments) {
+        warn!("Failed to remove {}: {e}", segments.display());
+    }
+
+    for path in [user_text_path(user_id), user_metadata_path(user_id)] {
+        if path.exists()
+            && let Err(e) = fs::remove_file(&path) {
+            warn!("Failed to remove {}: {e}", path.display());
+        }
+    }
+}
+
+// Loads the memories of a user from disk. A missing or broken file
+// is not fatal, we just start with empty memories for that user
+pub fn load_user_memories(user_id: &str) -> UserMemories {
+    let empty = UserMemories {
+        user_id: user_id.to_string(),
+        memories: VectorStore::new(Precision::from_env(), rescore_from_env()).with_storage(Storage::from_env()),
+        ..Default::default()
+    };
+    let path = user_memories_path(user_id);
+
+    let contents = match fs::read_to_string(&path) {
+        Ok(c) => c,
+        Err(e) => {
+            info!("No saved memories for user '{}' ({}), starting empty", user_id, e);
+            return empty;
+        }
+    };
+
+    match serde_json::from_str::<UserMemories>(&contents) {
+        Ok(mut user) if user.user_id == user_id => {
+            // Texts and metadata of a mapped collection are read on first use
+            if user.memories.is_mapped() {
+                user.memories_text = Lazy::from_file(&user_text_path(user_id));
+                user.metadata = Lazy::from_file(&user_metadata_path(user_id));
+            }
+            info!("Loaded {} memories of user '{}'", user.memories.len(), user_id);
+            user
+        }
+        Ok(user) => {
+            error!("Memory file {} belongs to user '{}', not '{}', ignoring it", path.display(), user.user_id, user_id);
+            empty
+        }
+        Err(e) => {
+            error!("Failed to parse memories of user '{}': {e}", user_id);
+            empty
+        }
+    }
+}
+
+// Remembers a message of the conversation, role says who wrote it.
+// Whether a role is remembered at all is up to the caller, see capture.rs.
+// Rating the importance can ask the chat model, so the store is only
+// locked once that is done
+pub fn add_memory(input: &str, role: MemoryRole, session: &ChatSession, store: &Mutex<MemoryStore>) {
+    // Rated once for the whole input, every sentence of it shares it
+    let mut metadata = turn_metadata(input, role, session);
+    metadata.importance = Importance::from_env().rate(input);
+    store_memory(input, metadata, &mut store.lock().unwrap_or_else(|e| e.into_inner()));
+}
+
+// The metadata of a memory taken from a message of the session. The
+// turn is the latest message in the session with that text, if it was
+// already added to the history. The importance is left to the caller
+pub fn turn_metadata(message: &str, role: MemoryRole, session: &ChatSession) -> MemoryMetadata {
+    MemoryMetadata {
+        user_id: session.user_id.clone(),
+        session_id: session.session_id.clone(),
+        created_at: now_unix(),
+        role,
+        turn: session.messages.iter().rposition(|m| m.content == message),
+        ..Default::default()
+    }
+}
+
+// Stores a text as memories of the user named in the metadata, one per
+// embedding, unless it duplicates an existing memory (see dedup.rs)
+pub fn store_memory(input: &str, metadata: MemoryMetadata, store: &mut MemoryStore) {
+    let user_id = metadata.user_id.clone();
+    let role = metadata.role;
+
+    // Saying the exact same thing again does not even need an embedding
+    let dedup = DedupOptions::from_env();
+    if dedup.policy != DedupPolicy::Off
+        && let Some(key) = store.user_mut(&user_id).find_same_text(input, role) {
+        info!("Input is the same as memory '{}', applying {:?}", key, dedup.policy);
+        if !record_duplicate(store, &user_id, &key, &metadata, dedup.policy) {
+            error!("Failed to update duplicate memory '{}'", key);
+        }
+        return;
+    }
+
     let input_vectors: Vec<String> = text_to_vec(input);
     info!("Converted input to vector of {} tokens", input_vectors.len());
 
     let my_embeddings: Option<Embeddings> = generate_embeddings(input_vectors.clone());
-    let Some(emb) = my_embeddings else { 
+    let Some(emb) = my_embeddings else {
         error!("Failed to generate embeddings from input text.");
-        return 
+        return
     };
 
-    let Some(data_vec) = emb.data else { 
+    let Some(data_vec) = emb.data else {
         error!("Embeddings returned, but no data found inside.");
-        return 
+        return
     };
 
     if data_vec.is_empty() {
@@ -29,92 +441,151 @@ pub fn add_memory(input: &str, memories: &mut HashMap<String, Vec<f64>>, count:
         return;
     }
 
+    // The sentences of one input are not duplicates of each other,
+    // so the memories added for it are left out of the near-duplicate check
+    let mut added: Vec<String> = vec![];
+
     for (i, val) in data_vec.iter().enumerate() {
-        let Some(data) = val.embedding.as_ref() else { 
+        let Some(data) = val.embedding.as_ref() else {
             warn!("Missing embedding vector at index {}, skipping.", i);
-            continue 
+            continue
         };
 
-        let key = format!("User Info{}", *count + i);
+        // The API hands out f64 values, but the model only computes f32
+        let vector: Vec<f32> = data.iter().map(|x| *x as f32).collect();
 
-        memories.insert(key.to_string(), data.to_vec());
-        info!("Added memory at key '{}', vector length {}", key, data.len());
+        if dedup.policy != DedupPolicy::Off
+            && let Some((key, similarity)) = store.user_mut(&user_id).find_similar(&vector, role, dedup.threshold, &added) {
+            info!("Embedding {} is {:.4} similar to memory '{}', applying {:?}", i, similarity, key, dedup.policy);
+            if !record_duplicate(store, &user_id, &key, &metadata, dedup.policy) {
+                error!("Failed to update duplicate memory '{}'", key);
+            }
+            continue;
+        }
 
-        memories_text.insert(key.to_string(), input.to_string());
-        info!("Added memory text at key '{}', string length {}", key, input.to_string().len());
+        let Some(key) = store.insert_memory(&user_id, input, vector, metadata.clone()) else {
+            error!("Failed to add memory at index {}", i);
+            continue
+        };
+        info!("Added memory at key '{}', vector length {}, string length {}", key, data.len(), input.len());
+        added.push(key);
     }
 
-    *count += data_vec.len();
-    info!("Updated memory count to {}", *count);
+    info!("Added {} memories for user '{}'", added.len(), user_id);
+}
+
+// Embeds the query and runs a hybrid vector and keyword search over the
+// memories of one user, see search.rs, then reranks the results if the
+// options ask for it. Returns the results best first
+pub fn search_memories(store: &MemoryStore, user_id: &str, query: &str, options: &SearchOptions) -> Option<Vec<SearchResult>> {
+    if !has_memories(store, user_id) {
+        return None;
+    }
+
+    let query_vector = embed_query(query, options);
+    let candidates = search_candidates(store, user_id, query, query_vector.as_deref(), options)?;
+    Some(rank_results(query, candidates, options))
+}
+
+fn has_memories(store: &MemoryStore, user_id: &str) -> bool {
+    store.user(user_id).is_some_and(|user| !user.memories.is_empty())
 }
 
-pub fn retrieve_memory(memories: HashMap<String, Vec<f64>>, memories_text: HashMap<String, String>, query: &str, top_k: usize) -> Option<Vec<String>> {
+// The query only needs an embedding if the vectors count at all.
+// If the embedding fails, the keywords alone still find something
+fn embed_query(query: &str, options: &SearchOptions) -> Option<Vec<f32>> {
+    if options.vector_weight <= 0.0 {
+        return None;
+    }
+
+    let embedded = embed_text(query);
+    if embedded.is_none() {
+        warn!("Failed to embed the query, searching by keywords only");
+    }
+    embedded
+}
+
+// The part of search_memories that needs the store, given the query
+// embedded by embed_query. With a reranker these are the rerank_top_n
+// candidates it picks from
+pub fn search_candidates(store: &MemoryStore, user_id: &str, query: &str, query_vector: Option<&[f32]>, options: &SearchOptions) -> Option<Vec<SearchResult>> {
+    // Only the memories of the requested user are ever looked at,
+    // other users are simply not reachable from here.
+    let user = store.user(user_id)?;
+    if user.memories.is_empty() {
+        return None;
+    }
+
+    if query_vector.is_none() && options.keyword_weight <= 0.0 {
+        return None;
+    }
+
+    // The reranker picks the top_k out of a bigger pool of candidates
+    let pool_size = match options.reranker {
+        Some(_) => options.rerank_top_n.max(options.top_k),
+        None => options.top_k,
+    };
+    let pool_options = options.clone().with_top_k(pool_size);
+    let found = hybrid_search(user, user_id, query, query_vector, &pool_options);
+    Some(found.into_iter()
+        .map(|(id, score)| SearchResult::new(user, id, score, options.with_vectors))
+        .collect())
+}
+
+// The last part of search_memories, which doesn't need the store. A
+// reranker can send a request per candidate, so callers that share the
+// store should let go of it before this
+pub fn rank_results(query: &str, candidates: Vec<SearchResult>, options: &SearchOptions) -> Vec<SearchResult> {
+    let mut results = candidates;
+    if let Some(reranker) = &options.reranker {
+        results = rerank_results(reranker, query, results, options.top_k);
+    }
+
+    // The threshold applies to whatever the results were ranked by last,
+    // so with a reranker it is a rerank score
+    if let Some(min_score) = options.min_score {
+        results.retain(|result| result.final_score() >= min_score);
+    }
+
+    results
+}
+
+pub fn embed_text(query: &str) -> Option<Vec<f32>> {
     // Here we convert our string input to a vector so that it's,
     // in the correct format for the OpanAI Rust API's embedding
     // call.
     let input_vector = text_to_vec(query);
-    
-    // This is an instance of the Embeddings struct that has 
+
+    // This is an instance of the Embeddings struct that has
     // several attributes, one of which is data, the one we need.
     let embeddings = generate_embeddings(input_vector.to_vec())?;
 
-    // This is a vector of struct EmbeddingsData, which has 
+    // This is a vector of struct EmbeddingsData, which has
     // several attributes, one of which is embedding, which is
     // the value we need.
     let embedding_data = embeddings.data?;
 
     // This will store the extracted floating point values,
     // creating a raw vector, the exact one we need.
-    let actual_embedding = embedding_data[0].embedding.as_ref()?;
-
-    // Here we convert our Vec to an Array so we can perform
-    // cosine similarity on it.
-    let actual_embed_as_array: Array1<f64> = Array1::from_vec(actual_embedding.to_vec());
+    let actual_embedding = embedding_data.first()?.embedding.as_ref()?;
 
-    // Here we define keys, to which we can later reference. these are the 
-    // keys of memory_text
-    let keys: Vec<&String> = memories_text.keys().collect();
-
-    // Here we create a Vec of a tuple which corresponds to the
-    // attributes of HashMap, and an extra, the similarity itslef
-    // (index of memory, similarity).
-    let mut sims_and_indexes: Vec<(usize, f64)> = Vec::new();
-
-    // Here we create a Vector that will store the results.
-    // To be more specific, we will put the correct values from memories_text
-    let mut result: Vec<String> = Vec::new();
+    // The stored vectors are f32, so the query is converted as well.
+    Some(actual_embedding.iter().map(|x| *x as f32).collect())
+}
 
-    // Here we loop through memories and calculate cosine similarity, and store
-    // both the index and the similarity value in sims_and_indexes
-    for (i, mem) in memories.iter().enumerate() {
-        let mem_vec_as_array: Array1<f64> = Array1::from_vec(mem.1.clone());
-        let similarity = cosine_similarity(&actual_embed_as_array, &mem_vec_as_array);
-        sims_and_indexes.push((i, similarity));
+// The memories to put into the prompt for a chat message, with their
+// ids and scores so the caller can cite where an answer came from.
+// The store is only locked while the candidates are found, not while
+// the query is embedded or the candidates are reranked
+pub fn retrieve_memory(store: &Mutex<MemoryStore>, user_id: &str, query: &str, options: &SearchOptions) -> Option<Vec<SearchResult>> {
+    if !has_memories(&store.lock().unwrap_or_else(|e| e.into_inner()), user_id) {
+        return None;
     }
 
-    // Here we filter out NaN values, since sort_by() doesn't play nice
-    // with NaN comparisons
-    sims_and_indexes.retain(|x| !x.1.is_nan());
-    sims_and_indexes.sort_by(
-        |a, b| b.1.partial_cmp(&a.1).unwrap()
-    );
-
-    if sims_and_indexes.len() >= top_k {
-        for item in 0..top_k {
-            let index = sims_and_indexes[item].0;
-            let val= memories_text[keys[index]].clone();
-
-            result.push(val);
-        }
-    } else {
-        for item in sims_and_indexes {
-            let index = item.0;
-            let val = memories_text[keys[index]].clone();
+    let query_vector = embed_query(query, options);
+    let candidates = search_candidates(&store.lock().unwrap_or_else(|e| e.into_inner()), user_id, query, query_vector.as_deref(), options)?;
+    let results: Vec<SearchResult> = rank_results(query, candidates, options);
 
-            result.push(val);
-        }
-    }
-
-    if result.is_empty() { None }
-    else { Some(result) }
-}
\ No newline at end of file
+    if results.is_empty() { None }
+    else { Some(results) }
+}
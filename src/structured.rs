@@ -0,0 +1,347 @@
+use log::*;
+use openai_api_rust::{ Message, OpenAI, Role };
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::custom_types::MyChatbot;
+use crate::params::{ read_schema, GenerationParams, ResponseFormat };
+use crate::prompt::{ PromptVars, SystemPrompts };
+use crate::utils::STRUCTURED_RETRIES;
+
+
+fn type_matches(value: &Value, name: &str) -> bool {
+    match name {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "integer" => value.is_i64() || value.is_u64() || value.as_f64().is_some_and(|n| n.fract() == 0.0),
+        "number" => value.is_number(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        _ => true
+    }
+}
+
+// Checks a value against a JSON Schema and returns what is wrong with it,
+// nothing if it fits. Covers type, enum, const, properties, required,
+// additionalProperties, items, anyOf and the length and range limits,
+// which is what answer schemas use. $ref and formats are not followed
+pub fn validate(value: &Value, schema: &Value) -> Vec<String> {
+    let mut errors: Vec<String> = vec![];
+    validate_at(value, schema, "$", &mut errors);
+    errors
+}
+
+fn validate_at(value: &Value, schema: &Value, path: &str, errors: &mut Vec<String>) {
+    // true and {} allow anything, false nothing
+    let Some(schema) = schema.as_object() else {
+        if schema == &Value::Bool(false) {
+            errors.push(format!("{}: is not allowed", path));
+        }
+        return;
+    };
+
+    match &schema.get("type") {
+        Some(Value::String(name)) if !type_matches(value, name) => {
+            errors.push(format!("{}: expected {}", path, name));
+            return;
+        }
+        Some(Value::Array(names)) if !names.iter().filter_map(Value::as_str).any(|name| type_matches(value, name)) => {
+            errors.push(format!("{}: expected one of {}", path, Value::Array(names.clone())));
+            return;
+        }
+        _ => ()
+    }
+
+    if let Some(Value::Array(allowed)) = schema.get("enum")
+        && !allowed.contains(value) {
+        errors.push(format!("{}: must be one of {}", path, Value::Array(allowed.clone())));
+    }
+    if let Some(expected) = schema.get("const")
+        && expected != value {
+        errors.push(format!("{}: must be {}", path, expected));
+    }
+
+    if let Some(Value::Array(options)) = schema.get("anyOf")
+        && !options.iter().any(|option| validate(value, option).is_empty()) {
+        errors.push(format!("{}: matches none of the allowed schemas", path));
+    }
+
+    match value {
+        Value::Object(object) => {
+            if let Some(Value::Array(required)) = schema.get("required") {
+                for name in required.iter().filter_map(Value::as_str) {
+                    if !object.contains_key(name) {
+                        errors.push(format!("{}: missing required property '{}'", path, name));
+                    }
+                }
+            }
+
+            let properties = schema.get("properties").and_then(Value::as_object);
+            for (name, item) in object {
+                let item_path = format!("{}.{}", path, name);
+                match (properties.and_then(|p| p.get(name)), schema.get("additionalProperties")) {
+                    (Some(item_schema), _) => validate_at(item, item_schema, &item_path, errors),
+                    (None, Some(Value::Bool(false))) => errors.push(format!("{}: is not an allowed property", item_path)),
+                    (None, Some(extra)) => validate_at(item, extra, &item_path, errors),
+                    (None, None) => ()
+                }
+            }
+        }
+        Value::Array(items) => {
+            if let Some(min) = schema.get("minItems").and_then(Value::as_u64)
+                && (items.len() as u64) < min {
+                errors.push(format!("{}: needs at least {} items", path, min));
+            }
+            if let Some(max) = schema.get("maxItems").and_then(Value::as_u64)
+                && items.len() as u64 > max {
+                errors.push(format!("{}: allows at most {} items", path, max));
+            }
+            if let Some(item_schema) = schema.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    validate_at(item, item_schema, &format!("{}[{}]", path, i), errors);
+                }
+            }
+        }
+        Value::String(text) => {
+            let length = text.chars().count() as u64;
+            if let Some(min) = schema.get("minLength").and_then(Value::as_u64)
+                && length < min {
+                errors.push(format!("{}: needs at least {} characters", path, min));
+            }
+            if let Some(max) = schema.get("maxLength").and_then(Value::as_u64)
+                && length > max {
+                errors.push(format!("{}: allows at most {} characters", path, max));
+            }
+        }
+        Value::Number(number) => {
+            let n = number.as_f64().unwrap_or_default();
+            if let Some(min) = schema.get("minimum").and_then(Value::as_f64)
+                && n < min {
+                errors.push(format!("{}: must be at least {}", path, min));
+            }
+            if let Some(max) = schema.get("maximum").and_then(Value::as_f64)
+                && n > max {
+                errors.push(format!("{}: must be at most {}", path, max));
+            }
+            if let Some(min) = schema.get("exclusiveMinimum").and_then(Value::as_f64)
+                && n <= min {
+                errors.push(format!("{}: must be more than {}", path, min));
+            }
+            if let Some(max) = schema.get("exclusiveMaximum").and_then(Value::as_f64)
+                && n >= max {
+                errors.push(format!("{}: must be less than {}", path, max));
+            }
+        }
+        _ => ()
+    }
+}
+
+// The JSON object or list in an answer. Models like to wrap it in a
+// code fence or add a sentence around it, so everything from the first
+// opening to the last closing bracket is taken
+fn json_part(answer: &str) -> Option<&str> {
+    let start = answer.find(['{', '['])?;
+    let end = answer.rfind(['}', ']'])?;
+    (end > start).then(|| &answer[start..=end])
+}
+
+// The JSON in an answer. An answer that is JSON as a whole is taken as
+// it is, so a plain string, number or boolean works too. Only otherwise
+// is the object or array looked for in the text around it
+pub fn answer_json(answer: &str) -> Result<Value, String> {
+    if let Ok(value) = serde_json::from_str(answer.trim()) {
+        return Ok(value);
+    }
+
+    let json = json_part(answer).ok_or("the answer holds no JSON")?;
+    serde_json::from_str(json).map_err(|e| format!("the answer is not valid JSON: {e}"))
+}
+
+// The JSON in an answer, checked against the schema and read into T
+fn read_answer<T: DeserializeOwned>(answer: &str, schema: &Value) -> Result<T, Vec<String>> {
+    let value = answer_json(answer).map_err(|e| vec![e])?;
+
+    let errors = validate(&value, schema);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    serde_json::from_value(value).map_err(|e| vec![e.to_string()])
+}
+
+impl MyChatbot {
+    // Asks for an answer in the JsonSchema response format (see params.rs)
+    // and reads it into T. An answer that does not fit the schema is sent
+    // back with what is wrong with it, up to STRUCTURED_RETRIES times.
+    // None if no answer fit
+    pub fn generate_structured<T: DeserializeOwned>(self, messages: &[Message], openai: &OpenAI, params: &GenerationParams, name: &str, schema: &Value) -> Option<T> {
+        let params = params.clone().with_response_format(ResponseFormat::JsonSchema {
+            name: name.to_string(),
+            schema: schema.clone(),
+        });
+
+        let mut conversation: Vec<Message> = messages.to_vec();
+        for attempt in 0..=STRUCTURED_RETRIES {
+            let answer = self.clone().generate_response(&mut conversation, openai, &params).0;
+            if answer.trim().is_empty() {
+                warn!("No answer for structured output '{}'", name);
+                return None;
+            }
+
+            let errors = match read_answer::<T>(&answer, schema) {
+                Ok(value) => return Some(value),
+                Err(errors) => errors
+            };
+            warn!("Answer {} for '{}' does not match the schema: {}", attempt + 1, name, errors.join("; "));
+
+            conversation.push(Message { role: Role::Assistant, content: answer });
+            conversation.push(Message {
+                role: Role::User,
+                content: format!("That answer does not match the schema:\n- {}\nAnswer again with only the corrected JSON.", errors.join("\n- ")),
+            });
+        }
+
+        error!("Gave up on structured output '{}' after {} attempts", name, STRUCTURED_RETRIES + 1);
+        None
+    }
+}
+
+// "/json <schema file> <message>" answers one message as JSON that fits
+// the schema in the file, without adding it to the conversation. The
+// system prompt is only rendered once the command is really "/json"
+pub fn handle_structured_command(line: &str, prompts: &SystemPrompts, vars: &PromptVars, openai: &OpenAI, params: &GenerationParams) -> bool {
+    let Some(args) = line.trim().strip_prefix("/json") else {
+        return false;
+    };
+    if !args.is_empty() && !args.starts_with(' ') {
+        return false;
+    }
+
+    let Some((path, message)) = args.trim().split_once(' ') else {
+        println!("Usage: /json <schema file> <message>");
+        return true;
+    };
+
+    let schema: Value = match read_schema(path) {
+        Ok(schema) => schema,
+        Err(e) => {
+            error!("{e}");
+            return true;
+        }
+    };
+
+    let system_prompt = prompts.render(vars);
+    let mut messages: Vec<Message> = vec![];
+    if !system_prompt.trim().is_empty() {
+        messages.push(Message { role: Role::System, content: system_prompt });
+    }
+    messages.push(Message { role: Role::User, content: message.trim().to_string() });
+
+    match MyChatbot::new().generate_structured::<Value>(&messages, openai, params, "answer", &schema) {
+        Some(value) => println!("{}", serde_json::to_string_pretty(&value).unwrap_or_default()),
+        None => println!("No answer matched the schema")
+    }
+    true
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn person() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string", "minLength": 1, "maxLength": 10 },
+                "age": { "type": "integer", "minimum": 0, "exclusiveMaximum": 150 },
+                "score": { "type": "number", "maximum": 1, "exclusiveMinimum": 0 },
+                "tags": { "type": "array", "items": { "type": "string" }, "minItems": 1, "maxItems": 2 }
+            },
+            "required": ["name", "age"],
+            "additionalProperties": false
+        })
+    }
+
+    #[test]
+    fn a_fitting_value_has_no_errors() {
+        let value = json!({ "name": "Ada", "age": 36, "score": 0.5, "tags": ["maths"] });
+        assert!(validate(&value, &person()).is_empty());
+        assert!(validate(&value, &json!(true)).is_empty());
+        assert!(validate(&value, &json!({})).is_empty());
+        assert_eq!(validate(&value, &json!(false)), vec!["$: is not allowed"]);
+    }
+
+    #[test]
+    fn required_properties_must_be_there() {
+        let errors = validate(&json!({ "name": "Ada" }), &person());
+        assert_eq!(errors, vec!["$: missing required property 'age'"]);
+    }
+
+    #[test]
+    fn no_additional_properties_means_none() {
+        let errors = validate(&json!({ "name": "Ada", "age": 36, "email": "ada@example.com" }), &person());
+        assert_eq!(errors, vec!["$.email: is not an allowed property"]);
+
+        // A schema for additional properties checks them instead
+        let schema = json!({ "type": "object", "additionalProperties": { "type": "integer" } });
+        assert!(validate(&json!({ "a": 1 }), &schema).is_empty());
+        assert_eq!(validate(&json!({ "a": "1" }), &schema), vec!["$.a: expected integer"]);
+    }
+
+    #[test]
+    fn integers_are_numbers_but_not_the_other_way_round() {
+        assert!(validate(&json!(3), &json!({ "type": "number" })).is_empty());
+        assert!(validate(&json!(3.0), &json!({ "type": "integer" })).is_empty());
+        assert_eq!(validate(&json!(3.5), &json!({ "type": "integer" })), vec!["$: expected integer"]);
+        assert_eq!(validate(&json!("3"), &json!({ "type": "number" })), vec!["$: expected number"]);
+        assert!(validate(&json!(null), &json!({ "type": ["integer", "null"] })).is_empty());
+    }
+
+    #[test]
+    fn any_of_needs_one_match() {
+        let schema = json!({ "anyOf": [{ "type": "string" }, { "type": "integer", "minimum": 10 }] });
+        assert!(validate(&json!("ten"), &schema).is_empty());
+        assert!(validate(&json!(12), &schema).is_empty());
+        assert_eq!(validate(&json!(5), &schema), vec!["$: matches none of the allowed schemas"]);
+    }
+
+    #[test]
+    fn limits_are_checked_at_their_edges() {
+        let schema = person();
+        let check = |value: Value| validate(&value, &schema);
+
+        assert!(check(json!({ "name": "A", "age": 0 })).is_empty());
+        assert!(check(json!({ "name": "Abcdefghij", "age": 149 })).is_empty());
+        assert_eq!(check(json!({ "name": "", "age": 0 })), vec!["$.name: needs at least 1 characters"]);
+        assert_eq!(check(json!({ "name": "Abcdefghijk", "age": 0 })), vec!["$.name: allows at most 10 characters"]);
+        assert_eq!(check(json!({ "name": "A", "age": -1 })), vec!["$.age: must be at least 0"]);
+        assert_eq!(check(json!({ "name": "A", "age": 150 })), vec!["$.age: must be less than 150"]);
+
+        assert!(check(json!({ "name": "A", "age": 1, "score": 1 })).is_empty());
+        assert_eq!(check(json!({ "name": "A", "age": 1, "score": 1.5 })), vec!["$.score: must be at most 1"]);
+        assert_eq!(check(json!({ "name": "A", "age": 1, "score": 0 })), vec!["$.score: must be more than 0"]);
+
+        assert_eq!(check(json!({ "name": "A", "age": 1, "tags": [] })), vec!["$.tags: needs at least 1 items"]);
+        assert_eq!(check(json!({ "name": "A", "age": 1, "tags": ["a", "b", "c"] })), vec!["$.tags: allows at most 2 items"]);
+        assert_eq!(check(json!({ "name": "A", "age": 1, "tags": ["a", 2] })), vec!["$.tags[1]: expected string"]);
+    }
+
+    #[test]
+    fn answers_are_read_whole_before_looking_inside() {
+        assert_eq!(answer_json("\"yes\""), Ok(json!("yes")));
+        assert_eq!(answer_json(" 42 \n"), Ok(json!(42)));
+        assert_eq!(answer_json("```json\n{\"a\": [1, 2]}\n```"), Ok(json!({ "a": [1, 2] })));
+        assert_eq!(answer_json("Here you go: [1] and that's all"), Ok(json!([1])));
+        assert!(answer_json("no JSON here").is_err());
+        assert!(answer_json("{ broken }").is_err());
+    }
+
+    #[test]
+    fn enum_and_const() {
+        assert!(validate(&json!("red"), &json!({ "enum": ["red", "green"] })).is_empty());
+        assert_eq!(validate(&json!("blue"), &json!({ "enum": ["red", "green"] })), vec!["$: must be one of [\"red\",\"green\"]"]);
+        assert_eq!(validate(&json!(2), &json!({ "const": 1 })), vec!["$: must be 1"]);
+    }
+}
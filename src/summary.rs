@@ -1,9 +1,38 @@
-/*use openai_api_rust::*;
-use openai_api_rust::chat::*;
+use log::*;
+use openai_api_rust::{ Message, Role };
 
-use crate::custom_types::{ MyChatBody, MyChatbot };
-use crate::utils::{ load_environment, get_openai, CHAT_MODEL };
+use crate::custom_types::MyChatbot;
+use crate::params::GenerationParams;
+use crate::utils::{ get_openai, load_environment };
 
-pub fn generate_summary(input: &str) -> String {
 
-}*/
\ No newline at end of file
+static SUMMARY_PROMPT: &str = "You condense notes about a user into one short paragraph. \
+Keep every concrete fact (names, numbers, dates, preferences) and drop repetition and small talk. \
+Write in the third person about the user and answer with the summary only.";
+
+// Asks the chat model to summarise the given texts into one.
+// Returns None if the model gave no answer
+pub fn generate_summary(texts: &[String]) -> Option<String> {
+    if texts.is_empty() {
+        return None;
+    }
+
+    let notes: String = texts.iter()
+        .map(|text| format!("- {}", text.trim()))
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    let oai = get_openai(&load_environment("URL"), &load_environment("LMS_API_KEY"));
+    let mut messages = vec![
+        Message { role: Role::System, content: SUMMARY_PROMPT.to_string() },
+        Message { role: Role::User, content: format!("Notes:\n{}\n\nSummary:", notes) },
+    ];
+
+    let summary = MyChatbot::new().generate_response(&mut messages, &oai, &GenerationParams::from_env()).0;
+    if summary.trim().is_empty() {
+        warn!("Chat model returned an empty summary for {} notes", texts.len());
+        return None;
+    }
+
+    Some(summary.trim().to_string())
+}
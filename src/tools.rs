@@ -0,0 +1,310 @@
+use std::sync::Mutex;
+
+use log::*;
+use openai_api_rust::{ Message, OpenAI };
+use serde_json::{ json, Value };
+
+use crate::capture::CapturePolicy;
+use crate::custom_types::{ ChatSession, MemoryRole, MemoryStore, MyChatbot };
+use crate::importance::Importance;
+use crate::llm::post_chat_json;
+use crate::params::GenerationParams;
+use crate::search::SearchOptions;
+use crate::store::{ add_memory, retrieve_memory, store_memory, turn_metadata };
+use crate::transcript::InjectedMemory;
+use crate::usage::record_usage;
+use crate::utils::{ load_environment, MAX_TOOL_ROUNDS };
+
+
+// What a tool works with. The store is locked for each call on its own,
+// not for the whole answer, so Ctrl+C can still flush in between
+pub struct ToolContext<'a> {
+    pub store: &'a Mutex<MemoryStore>,
+    pub session: &'a ChatSession,
+    pub search_options: &'a SearchOptions,
+    pub capture: CapturePolicy,
+}
+
+// Runs a tool with the arguments the model gave. The text returned, or
+// the error, is what the model gets to read
+pub type ToolHandler = fn(&Value, &ToolContext) -> Result<String, String>;
+
+// A tool the model can call. parameters is the JSON Schema of its
+// arguments. With capture set, its results are remembered as tool
+// memories if the capture policy allows it, so a tool that finds out
+// something new should set it. Tools that only read or delete memories
+// don't, their results would just copy memories that are already there
+#[derive(Clone)]
+pub struct Tool {
+    pub name: String,
+    pub description: String,
+    pub parameters: Value,
+    pub capture: bool,
+    pub handler: ToolHandler,
+}
+
+// One call of a tool the model asked for. arguments is a JSON string
+#[derive(Clone, Debug, PartialEq)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub arguments: String,
+}
+
+// The tools offered to the model, in the order they are listed to it
+#[derive(Clone, Default)]
+pub struct ToolRegistry {
+    tools: Vec<Tool>,
+}
+
+// Whether the chat loop offers tools to the model, from MEMORY_TOOLS
+pub fn tools_from_env() -> bool {
+    matches!(load_environment("MEMORY_TOOLS").trim().to_lowercase().as_str(), "1" | "true" | "yes" | "on")
+}
+
+impl ToolRegistry {
+    pub fn new() -> Self {
+        ToolRegistry::default()
+    }
+
+    // search_memory, save_memory and forget_memory over the memories
+    // of the user the session belongs to
+    pub fn builtin() -> Self {
+        ToolRegistry::new()
+            .register(Tool {
+                name: "search_memory".to_string(),
+                description: "Search the long-term memory of the user for things they said before".to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "query": { "type": "string", "description": "What to look for" },
+                        "k": { "type": "integer", "description": "How many memories to return" }
+                    },
+                    "required": ["query"]
+                }),
+                // The results are memories already
+                capture: false,
+                handler: search_memory_tool,
+            })
+            .register(Tool {
+                name: "save_memory".to_string(),
+                description: "Save a fact about the user to long-term memory, as a short sentence".to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "text": { "type": "string", "description": "The fact, like \"The user is vegetarian\"" }
+                    },
+                    "required": ["text"]
+                }),
+                // The result is the fact, see save_memory_tool
+                capture: true,
+                handler: save_memory_tool,
+            })
+            .register(Tool {
+                name: "forget_memory".to_string(),
+                description: "Delete a memory by its id, when the user asks to forget it or it is wrong".to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "id": { "type": "string", "description": "The id search_memory returned" }
+                    },
+                    "required": ["id"]
+                }),
+                // Keeping a note of it would bring the memory back
+                capture: false,
+                handler: forget_memory_tool,
+            })
+    }
+
+    // Adds a tool, replacing one of the same name
+    pub fn register(mut self, tool: Tool) -> Self {
+        self.tools.retain(|t| t.name != tool.name);
+        self.tools.push(tool);
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Tool> {
+        self.tools.iter().find(|t| t.name == name)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tools.is_empty()
+    }
+
+    // The tools field of the request
+    pub fn definitions(&self) -> Value {
+        Value::Array(self.tools.iter().map(|tool| json!({
+            "type": "function",
+            "function": {
+                "name": tool.name,
+                "description": tool.description,
+                "parameters": tool.parameters,
+            }
+        })).collect())
+    }
+
+    // Runs one call and returns what the model gets back. Failures are
+    // handed to the model as well, so it can try again or give up
+    pub fn dispatch(&self, call: &ToolCall, ctx: &ToolContext) -> String {
+        let Some(tool) = self.get(&call.name) else {
+            warn!("Model called unknown tool '{}'", call.name);
+            return format!("Error: there is no tool called '{}'", call.name);
+        };
+
+        let arguments: Value = if call.arguments.trim().is_empty() {
+            json!({})
+        } else {
+            match serde_json::from_str(&call.arguments) {
+                Ok(arguments) => arguments,
+                Err(e) => return format!("Error: the arguments are not valid JSON: {e}")
+            }
+        };
+
+        let result = match (tool.handler)(&arguments, ctx) {
+            Ok(result) => result,
+            Err(e) => {
+                warn!("Tool '{}' failed: {e}", call.name);
+                return format!("Error: {e}");
+            }
+        };
+        info!("Tool '{}' called with {}", call.name, arguments);
+
+        if tool.capture && ctx.capture.captures(MemoryRole::Tool) {
+            add_memory(&result, MemoryRole::Tool, ctx.session, ctx.store);
+        }
+        result
+    }
+}
+
+fn string_argument<'a>(arguments: &'a Value, name: &str) -> Result<&'a str, String> {
+    arguments[name].as_str()
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .ok_or_else(|| format!("missing argument '{}'", name))
+}
+
+fn search_memory_tool(arguments: &Value, ctx: &ToolContext) -> Result<String, String> {
+    let query = string_argument(arguments, "query")?;
+    let mut options = ctx.search_options.clone();
+    if let Some(k) = arguments["k"].as_u64() {
+        options.top_k = k.clamp(1, 20) as usize;
+    }
+
+    let results = retrieve_memory(ctx.store, &ctx.session.user_id, query, &options).unwrap_or_default();
+    let memories: Vec<InjectedMemory> = results.iter().map(InjectedMemory::from).collect();
+    serde_json::to_string(&memories).map_err(|e| e.to_string())
+}
+
+// The fact is the result, so when tool results are captured dispatch
+// stores it like any other. Otherwise it is stored here, the model was
+// asked to save it after all. Either way it is a tool memory
+fn save_memory_tool(arguments: &Value, ctx: &ToolContext) -> Result<String, String> {
+    let text = string_argument(arguments, "text")?;
+
+    if !ctx.capture.captures(MemoryRole::Tool) {
+        let mut metadata = turn_metadata(text, MemoryRole::Tool, ctx.session);
+        metadata.importance = Importance::from_env().rate(text);
+        store_memory(text, metadata, &mut ctx.store.lock().unwrap_or_else(|e| e.into_inner()));
+    }
+    Ok(text.to_string())
+}
+
+fn forget_memory_tool(arguments: &Value, ctx: &ToolContext) -> Result<String, String> {
+    let id = string_argument(arguments, "id")?;
+
+    let mut store = ctx.store.lock().unwrap_or_else(|e| e.into_inner());
+    if store.delete_memory(&ctx.session.user_id, id) {
+        Ok(format!("Forgot memory '{}'", id))
+    } else {
+        Err(format!("there is no memory with id '{}'", id))
+    }
+}
+
+// The tool calls in a message of a completion, in the order given
+pub fn parse_tool_calls(message: &Value) -> Vec<ToolCall> {
+    let Some(calls) = message["tool_calls"].as_array() else {
+        return vec![];
+    };
+
+    calls.iter()
+        .filter_map(|call| Some(ToolCall {
+            id: call["id"].as_str().unwrap_or_default().to_string(),
+            name: call["function"]["name"].as_str()?.to_string(),
+            arguments: call["function"]["arguments"].as_str().unwrap_or_default().to_string(),
+        }))
+        .collect()
+}
+
+fn token_count(usage: &Value, name: &str) -> Option<u32> {
+    usage[name].as_u64().map(|n| n as u32)
+}
+
+fn add_tokens(total: Option<u32>, more: Option<u32>) -> Option<u32> {
+    match (total, more) {
+        (None, None) => None,
+        (a, b) => Some(a.unwrap_or(0) + b.unwrap_or(0))
+    }
+}
+
+impl MyChatbot {
+    // Like generate_response, but the model may call the tools first.
+    // Their results are sent back and the model asked again, until it
+    // answers or MAX_TOOL_ROUNDS is used up. The last round takes the
+    // tools away, a model that still calls them gets no answer and a
+    // warning is logged. The tokens are those of every round together
+    pub fn generate_with_tools(self, messages: &[Message], openai: &OpenAI, params: &GenerationParams, tools: &ToolRegistry, ctx: &ToolContext) -> (String, Option<u32>, Option<u32>) {
+        let mut exchanged: Vec<Value> = vec![];
+        let mut prompt_tokens: Option<u32> = None;
+        let mut completion_tokens: Option<u32> = None;
+
+        for round in 0..=MAX_TOOL_ROUNDS {
+            // The last round takes the tools away, there has to be an answer
+            let choice = if round == MAX_TOOL_ROUNDS { "none" } else { "auto" };
+            let body = params.chat_body(messages.to_vec())
+                .with_tools(tools.definitions())
+                .with_tool_choice(choice)
+                .with_raw_messages(exchanged.clone());
+
+            let completion = match post_chat_json(openai, &body) {
+                Ok(completion) => completion,
+                Err(e) => {
+                    error!("Failed to get result for API error: {e}");
+                    break;
+                }
+            };
+
+            let usage = &completion["usage"];
+            record_usage(&params.model, token_count(usage, "prompt_tokens"), token_count(usage, "completion_tokens"));
+            prompt_tokens = add_tokens(prompt_tokens, token_count(usage, "prompt_tokens"));
+            completion_tokens = add_tokens(completion_tokens, token_count(usage, "completion_tokens"));
+
+            let message = &completion["choices"][0]["message"];
+            let calls = parse_tool_calls(message);
+            let answer = message["content"].as_str().unwrap_or_default().to_string();
+            if calls.is_empty() {
+                return (answer, prompt_tokens, completion_tokens);
+            }
+            if round == MAX_TOOL_ROUNDS {
+                warn!("Model still called {} tools after {} rounds, giving up on them", calls.len(), MAX_TOOL_ROUNDS);
+                if answer.trim().is_empty() {
+                    warn!("No answer within the tool round limit");
+                }
+                return (answer, prompt_tokens, completion_tokens);
+            }
+
+            // The assistant message goes back as it came, the tool
+            // results are matched to its calls by id
+            exchanged.push(message.clone());
+            for call in &calls {
+                let result = tools.dispatch(call, ctx);
+                exchanged.push(json!({
+                    "role": "tool",
+                    "tool_call_id": call.id,
+                    "content": result,
+                }));
+            }
+        }
+
+        (String::new(), prompt_tokens, completion_tokens)
+    }
+}
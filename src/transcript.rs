@@ -0,0 +1,210 @@
+use std::fs;
+use std::path::Path;
+
+use openai_api_rust::Role;
+use serde::{ Deserialize, Serialize };
+
+use crate::custom_types::{ ChatSession, MemoryRole };
+use crate::search::SearchResult;
+use crate::usage::Usage;
+use crate::utils::format_unix;
+
+
+// A memory that was put into the prompt of one turn
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct InjectedMemory {
+    pub id: String,
+    pub score: f32,
+    #[serde(default)]
+    pub role: MemoryRole,
+    pub text: String,
+}
+
+// What went into and came out of one answer of the assistant.
+// message is the index of the answer in the session's messages
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TurnRecord {
+    pub message: usize,
+    pub created_at: u64,
+    #[serde(default)]
+    pub memories: Vec<InjectedMemory>,
+    #[serde(default)]
+    pub prompt_tokens: Option<u32>,
+    #[serde(default)]
+    pub completion_tokens: Option<u32>,
+    // Every chat and embedding call made for this turn, by model. The
+    // token counts above are only the answer itself
+    #[serde(default, skip_serializing_if = "Usage::is_empty")]
+    pub usage: Usage,
+}
+
+// A conversation as it is exported, the system prompt apart from the
+// turns and the totals worked out already
+#[derive(Serialize)]
+struct Transcript<'a> {
+    session_id: &'a str,
+    user_id: &'a str,
+    title: String,
+    created_at: u64,
+    updated_at: u64,
+    system_prompt: Option<&'a str>,
+    summary: Option<&'a str>,
+    prompt_tokens: u32,
+    completion_tokens: u32,
+    messages: Vec<TranscriptMessage<'a>>,
+}
+
+#[derive(Serialize)]
+struct TranscriptMessage<'a> {
+    role: &'static str,
+    content: &'a str,
+    #[serde(flatten)]
+    turn: Option<&'a TurnRecord>,
+}
+
+impl From<&SearchResult> for InjectedMemory {
+    fn from(result: &SearchResult) -> Self {
+        InjectedMemory {
+            id: result.id.clone(),
+            score: result.final_score(),
+            role: result.metadata.role,
+            text: result.text.clone(),
+        }
+    }
+}
+
+fn role_name(role: &Role) -> &'static str {
+    match role {
+        Role::System => "system",
+        Role::Assistant => "assistant",
+        Role::User => "user",
+    }
+}
+
+impl ChatSession {
+    // Records the memories and token usage of the answer that was just
+    // added as the last message
+    pub fn record_turn(&mut self, memories: &[SearchResult], prompt_tokens: Option<u32>, completion_tokens: Option<u32>, usage: Usage, created_at: u64) {
+        self.turns.push(TurnRecord {
+            message: self.messages.len().saturating_sub(1),
+            created_at,
+            memories: memories.iter().map(InjectedMemory::from).collect(),
+            prompt_tokens,
+            completion_tokens,
+            usage,
+        });
+    }
+
+    fn turn_of(&self, message: usize) -> Option<&TurnRecord> {
+        self.turns.iter().rev().find(|turn| turn.message == message)
+    }
+}
+
+fn transcript(session: &ChatSession) -> Transcript<'_> {
+    // The system prompt is the first message of every session
+    let (system_prompt, start) = match session.messages.first() {
+        Some(first) if matches!(first.role, Role::System) => (Some(first.content.as_str()), 1),
+        _ => (None, 0)
+    };
+
+    let messages: Vec<TranscriptMessage> = session.messages.iter()
+        .enumerate()
+        .skip(start)
+        .map(|(i, message)| TranscriptMessage {
+            role: role_name(&message.role),
+            content: &message.content,
+            turn: session.turn_of(i),
+        })
+        .collect();
+
+    Transcript {
+        session_id: &session.session_id,
+        user_id: &session.user_id,
+        title: session.display_title(),
+        created_at: session.created_at,
+        updated_at: session.updated_at,
+        system_prompt: system_prompt.filter(|p| !p.trim().is_empty()),
+        summary: session.summary.as_deref(),
+        prompt_tokens: session.turns.iter().filter_map(|t| t.prompt_tokens).sum(),
+        completion_tokens: session.turns.iter().filter_map(|t| t.completion_tokens).sum(),
+        messages,
+    }
+}
+
+pub fn transcript_json(session: &ChatSession) -> serde_json::Result<String> {
+    serde_json::to_string_pretty(&transcript(session))
+}
+
+// Quotes every line, so the system prompt can't break out into the
+// headings of the transcript
+fn quoted(text: &str) -> String {
+    text.trim().lines().map(|line| format!("> {}", line)).collect::<Vec<String>>().join("\n")
+}
+
+fn usage_line(prompt_tokens: Option<u32>, completion_tokens: Option<u32>) -> Option<String> {
+    match (prompt_tokens, completion_tokens) {
+        (None, None) => None,
+        (p, c) => Some(format!("_Tokens: {} prompt, {} completion_", p.unwrap_or(0), c.unwrap_or(0)))
+    }
+}
+
+pub fn transcript_markdown(session: &ChatSession) -> String {
+    let t = transcript(session);
+    let mut out = format!("# {}\n\n", t.title);
+
+    out.push_str(&format!("- Conversation: `{}`\n", t.session_id));
+    out.push_str(&format!("- User: {}\n", t.user_id));
+    out.push_str(&format!("- Started: {}\n", format_unix(t.created_at)));
+    out.push_str(&format!("- Last updated: {}\n", format_unix(t.updated_at)));
+    out.push_str(&format!("- Tokens: {} prompt, {} completion\n", t.prompt_tokens, t.completion_tokens));
+
+    if let Some(prompt) = t.system_prompt {
+        out.push_str(&format!("\n## System prompt\n\n{}\n", quoted(prompt)));
+    }
+    if let Some(summary) = t.summary {
+        out.push_str(&format!("\n## Summary\n\n{}\n", summary.trim()));
+    }
+
+    out.push_str("\n## Transcript\n");
+    for message in &t.messages {
+        let heading = match message.role {
+            "user" => "User",
+            "assistant" => "Assistant",
+            _ => "System",
+        };
+        out.push_str(&format!("\n### {}\n\n{}\n", heading, message.content.trim()));
+
+        let Some(turn) = message.turn else {
+            continue;
+        };
+        if !turn.memories.is_empty() {
+            out.push_str("\nMemories used:\n\n");
+            for memory in &turn.memories {
+                out.push_str(&format!("- `{}` ({:.4}, {:?}): {}\n", memory.id, memory.score, memory.role, memory.text.trim()));
+            }
+        }
+        if let Some(line) = usage_line(turn.prompt_tokens, turn.completion_tokens) {
+            out.push_str(&format!("\n{}\n", line));
+        }
+        if !turn.usage.is_empty() {
+            out.push_str(&format!("\n_All calls of this turn: {} tokens_\n", turn.usage.total_tokens()));
+        }
+    }
+
+    out
+}
+
+// Writes the transcript of a session as Markdown ("md") or JSON ("json")
+pub fn export_transcript(session: &ChatSession, format: &str, path: &Path) -> std::io::Result<()> {
+    let contents = match format {
+        "md" | "markdown" => transcript_markdown(session),
+        "json" => transcript_json(session)?,
+        other => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("unknown transcript format '{}'", other)))
+    };
+
+    if let Some(parent) = path.parent()
+        && !parent.as_os_str().is_empty() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, contents)
+}
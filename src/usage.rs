@@ -0,0 +1,270 @@
+use std::collections::{ BTreeMap, HashMap };
+use std::fs;
+use std::path::Path;
+use std::sync::{ Mutex, OnceLock };
+
+use log::*;
+use serde::{ Deserialize, Serialize };
+
+use crate::custom_types::ChatSession;
+use crate::utils::{ format_unix, load_environment, now_unix, USAGE_FILE };
+use crate::wal::write_atomic;
+
+
+// Tokens used with one model. Embedding calls only have prompt tokens
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ModelUsage {
+    pub calls: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+// Token usage by model, for a turn, a session or everything so far
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Usage {
+    pub models: BTreeMap<String, ModelUsage>,
+}
+
+// What a model costs per million tokens, read from the JSON file named
+// in MODEL_PRICES: {"model name": {"prompt": 0.15, "completion": 0.6}}
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+pub struct ModelPrice {
+    #[serde(default)]
+    pub prompt: f64,
+    #[serde(default)]
+    pub completion: f64,
+}
+
+// Limits on tokens and cost, for one session and for everything so far.
+// With stop set, no more messages are sent once a limit is reached,
+// otherwise there is only a warning
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Budget {
+    pub session_tokens: Option<u64>,
+    pub session_cost: Option<f64>,
+    pub total_tokens: Option<u64>,
+    pub total_cost: Option<f64>,
+    pub stop: bool,
+}
+
+// Everything used since the usage file was started, and what was used
+// since the current turn began. There is only one of these per process,
+// every chat and embedding call reports to it (see record_usage)
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct UsageLedger {
+    since: u64,
+    total: Usage,
+    #[serde(skip)]
+    turn: Usage,
+}
+
+static LEDGER: OnceLock<Mutex<UsageLedger>> = OnceLock::new();
+static PRICES: OnceLock<HashMap<String, ModelPrice>> = OnceLock::new();
+
+impl Usage {
+    pub fn add(&mut self, model: &str, prompt_tokens: u64, completion_tokens: u64) {
+        let entry = self.models.entry(model.to_string()).or_default();
+        entry.calls += 1;
+        entry.prompt_tokens += prompt_tokens;
+        entry.completion_tokens += completion_tokens;
+    }
+
+    pub fn merge(&mut self, other: &Usage) {
+        for (model, usage) in &other.models {
+            let entry = self.models.entry(model.clone()).or_default();
+            entry.calls += usage.calls;
+            entry.prompt_tokens += usage.prompt_tokens;
+            entry.completion_tokens += usage.completion_tokens;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.models.is_empty()
+    }
+
+    pub fn total_tokens(&self) -> u64 {
+        self.models.values().map(|u| u.prompt_tokens + u.completion_tokens).sum()
+    }
+
+    // The cost of everything in here, None if no model has a price.
+    // Models without a price count as free
+    pub fn cost(&self, prices: &HashMap<String, ModelPrice>) -> Option<f64> {
+        let mut priced = false;
+        let mut cost = 0.0;
+
+        for (model, usage) in &self.models {
+            if let Some(price) = prices.get(model) {
+                priced = true;
+                cost += (usage.prompt_tokens as f64 * price.prompt + usage.completion_tokens as f64 * price.completion) / 1_000_000.0;
+            }
+        }
+
+        priced.then_some(cost)
+    }
+}
+
+fn ledger() -> &'static Mutex<UsageLedger> {
+    LEDGER.get_or_init(|| {
+        let ledger = fs::read_to_string(USAGE_FILE)
+            .ok()
+            .and_then(|contents| serde_json::from_str::<UsageLedger>(&contents)
+                .inspect_err(|e| error!("Failed to read {}, starting over: {e}", USAGE_FILE))
+                .ok())
+            .unwrap_or_else(|| UsageLedger { since: now_unix(), ..Default::default() });
+        Mutex::new(ledger)
+    })
+}
+
+// Reports the tokens of one call. Servers that send no usage count as 0
+pub fn record_usage(model: &str, prompt_tokens: Option<u32>, completion_tokens: Option<u32>) {
+    let prompt = prompt_tokens.unwrap_or(0) as u64;
+    let completion = completion_tokens.unwrap_or(0) as u64;
+
+    let mut ledger = ledger().lock().unwrap_or_else(|e| e.into_inner());
+    ledger.total.add(model, prompt, completion);
+    ledger.turn.add(model, prompt, completion);
+}
+
+// Everything used since the last call, and starts counting the next turn.
+// The totals are saved to the usage file at the same time
+pub fn take_turn_usage() -> Usage {
+    let mut ledger = ledger().lock().unwrap_or_else(|e| e.into_inner());
+    let turn = std::mem::take(&mut ledger.turn);
+    if let Err(e) = save_ledger(&ledger) {
+        error!("Failed to save token usage to {}: {e}", USAGE_FILE);
+    }
+    turn
+}
+
+pub fn total_usage() -> Usage {
+    ledger().lock().unwrap_or_else(|e| e.into_inner()).total.clone()
+}
+
+pub fn save_usage() {
+    let ledger = ledger().lock().unwrap_or_else(|e| e.into_inner());
+    if let Err(e) = save_ledger(&ledger) {
+        error!("Failed to save token usage to {}: {e}", USAGE_FILE);
+    }
+}
+
+fn save_ledger(ledger: &UsageLedger) -> std::io::Result<()> {
+    if let Some(parent) = Path::new(USAGE_FILE).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    write_atomic(Path::new(USAGE_FILE), &serde_json::to_vec_pretty(ledger)?)
+}
+
+// The price table from MODEL_PRICES, read once. Empty without one
+pub fn prices() -> &'static HashMap<String, ModelPrice> {
+    PRICES.get_or_init(|| {
+        let path = load_environment("MODEL_PRICES");
+        if path.trim().is_empty() {
+            return HashMap::new();
+        }
+
+        match fs::read_to_string(path.trim()).map(|c| serde_json::from_str::<HashMap<String, ModelPrice>>(&c)) {
+            Ok(Ok(prices)) => prices,
+            Ok(Err(e)) => {
+                error!("MODEL_PRICES file {} is not valid: {e}", path.trim());
+                HashMap::new()
+            }
+            Err(e) => {
+                error!("Failed to read MODEL_PRICES file {}: {e}", path.trim());
+                HashMap::new()
+            }
+        }
+    })
+}
+
+impl ChatSession {
+    // Everything the turns of this session used
+    pub fn usage(&self) -> Usage {
+        let mut usage = Usage::default();
+        for turn in &self.turns {
+            usage.merge(&turn.usage);
+        }
+        usage
+    }
+}
+
+fn env_number<T: std::str::FromStr>(name: &str) -> Option<T> {
+    let value = load_environment(name);
+    if value.trim().is_empty() {
+        return None;
+    }
+    value.trim().parse::<T>()
+        .inspect_err(|_| warn!("{} '{}' is not a number, ignoring it", name, value))
+        .ok()
+}
+
+impl Budget {
+    // Reads BUDGET_SESSION_TOKENS, BUDGET_SESSION_COST, BUDGET_TOTAL_TOKENS,
+    // BUDGET_TOTAL_COST and BUDGET_ACTION ("warn" or "stop")
+    pub fn from_env() -> Self {
+        Budget {
+            session_tokens: env_number("BUDGET_SESSION_TOKENS"),
+            session_cost: env_number("BUDGET_SESSION_COST"),
+            total_tokens: env_number("BUDGET_TOTAL_TOKENS"),
+            total_cost: env_number("BUDGET_TOTAL_COST"),
+            stop: load_environment("BUDGET_ACTION").trim().eq_ignore_ascii_case("stop"),
+        }
+    }
+
+    // Every limit the session or the totals have reached, as text
+    pub fn exceeded(&self, session: &Usage, total: &Usage) -> Vec<String> {
+        let prices = prices();
+        let mut reached: Vec<String> = vec![];
+
+        if let Some(limit) = self.session_tokens
+            && session.total_tokens() >= limit {
+            reached.push(format!("session tokens {} of {}", session.total_tokens(), limit));
+        }
+        if let Some(limit) = self.total_tokens
+            && total.total_tokens() >= limit {
+            reached.push(format!("total tokens {} of {}", total.total_tokens(), limit));
+        }
+        if let Some(limit) = self.session_cost
+            && let Some(cost) = session.cost(prices)
+            && cost >= limit {
+            reached.push(format!("session cost {:.6} of {:.6}", cost, limit));
+        }
+        if let Some(limit) = self.total_cost
+            && let Some(cost) = total.cost(prices)
+            && cost >= limit {
+            reached.push(format!("total cost {:.6} of {:.6}", cost, limit));
+        }
+
+        reached
+    }
+}
+
+fn print_usage(label: &str, usage: &Usage) {
+    let cost = usage.cost(prices()).map_or(String::new(), |c| format!(", cost {:.6}", c));
+    println!("{}: {} tokens{}", label, usage.total_tokens(), cost);
+    for (model, model_usage) in &usage.models {
+        println!("\t{}\t{} calls\t{} prompt\t{} completion", model, model_usage.calls, model_usage.prompt_tokens, model_usage.completion_tokens);
+    }
+}
+
+// "/stats": the usage of the last turn, the current session and
+// everything so far, and which budget limits are reached
+pub fn handle_usage_command(line: &str, session: Option<&ChatSession>, budget: &Budget) -> bool {
+    if line.trim() != "/stats" {
+        return false;
+    }
+
+    let session_usage = session.map(|s| s.usage()).unwrap_or_default();
+    if let Some(last) = session.and_then(|s| s.turns.last()) {
+        print_usage("Last turn", &last.usage);
+    }
+    print_usage("This session", &session_usage);
+
+    let since = ledger().lock().unwrap_or_else(|e| e.into_inner()).since;
+    let total = total_usage();
+    print_usage(&format!("Since {}", format_unix(since)), &total);
+
+    for limit in budget.exceeded(&session_usage, &total) {
+        println!("Budget reached: {}", limit);
+    }
+    true
+}
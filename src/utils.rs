@@ -1,6 +1,7 @@
 use std::{env, fs, io::{ErrorKind, Read}, vec};
 use log::*;
 use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 //use regex::*;
 use openai_api_rust::{Auth, OpenAI};
 
@@ -10,6 +11,62 @@ use crate::custom_types::MyLogger;
 static LOGGER: MyLogger = MyLogger;
 pub static EMBED_MODEL: &str = "text-embedding-all-minilm-l6-v2-embedding";
 pub static CHAT_MODEL: &str = "meta-llama-3.1-8b-instruct@q4_k_m";
+pub static MEMORY_DIR: &str = "data/memories";
+pub static SNAPSHOT_DIR: &str = "data/snapshots";
+pub static EXPORT_DIR: &str = "data/exports";
+pub static CONVERSATION_DIR: &str = "data/conversations";
+pub static USAGE_FILE: &str = "data/usage.json";
+pub static SYSPROMPT_FILE: &str = "data/system_pormpt.txt";
+pub static PROMPT_DIR: &str = "data/prompts";
+pub static MEMORY_TOP_K: usize = 3;
+pub static WAL_CHECKPOINT_EVERY: usize = 500;
+pub static INT8_CALIBRATION_ROWS: usize = 64;
+pub static RESCORE_OVERSAMPLE: usize = 4;
+// Below this many rows a full scan stays on the calling thread
+pub static PARALLEL_SCAN_MIN_ROWS: usize = 4096;
+// Size of one memory-mapped vector segment file
+pub static SEGMENT_BYTES: usize = 64 * 1024 * 1024;
+pub static INDEX_TRAIN_SAMPLE: usize = 10_000;
+pub static KMEANS_ITERATIONS: usize = 25;
+pub static PQ_SUB_VECTORS: usize = 8;
+pub static PQ_CODEBOOK_SIZE: usize = 256;
+pub static DEFAULT_NPROBE: usize = 8;
+pub static IVF_MAX_LIST_FACTOR: f32 = 4.0;
+pub static BM25_K1: f32 = 1.2;
+pub static BM25_B: f32 = 0.75;
+// How much the embeddings and the keywords count in a hybrid search
+pub static HYBRID_VECTOR_WEIGHT: f32 = 0.7;
+pub static HYBRID_KEYWORD_WEIGHT: f32 = 0.3;
+// How many more candidates than needed each side of a hybrid search brings
+pub static HYBRID_OVERSAMPLE: usize = 4;
+// The usual constant of reciprocal rank fusion, it keeps the first few
+// ranks from drowning out everything else
+pub static RRF_K: f32 = 60.0;
+// The lambda a bare "mmr" search option uses: 1 is pure relevance,
+// 0 only cares about results being different from each other
+pub static MMR_DEFAULT_LAMBDA: f32 = 0.5;
+// How many candidates a reranker gets to look at
+pub static RERANK_TOP_N: usize = 10;
+// How much recency and importance count next to relevance, and after how
+// many hours a memory is only half as recent as a brand new one
+pub static RECENCY_WEIGHT: f32 = 0.2;
+pub static IMPORTANCE_WEIGHT: f32 = 0.1;
+pub static RECENCY_HALF_LIFE_HOURS: f32 = 168.0;
+// The importance of a memory nobody rated, and where the heuristic starts
+pub static DEFAULT_IMPORTANCE: f32 = 0.3;
+// Memories younger than this are never consolidated, and a summary is
+// only written for groups of similar enough memories (see consolidate.rs)
+pub static CONSOLIDATE_MIN_AGE_HOURS: f32 = 168.0;
+pub static CONSOLIDATE_SIMILARITY: f32 = 0.75;
+pub static CONSOLIDATE_MIN_CLUSTER: usize = 3;
+pub static CONSOLIDATE_MAX_CLUSTER: usize = 20;
+// How close a new memory's vector has to be to an existing one to count
+// as the same memory said again
+pub static DEDUP_THRESHOLD: f32 = 0.97;
+// How many times the model may call tools before it has to answer
+pub static MAX_TOOL_ROUNDS: usize = 5;
+// How often an answer that does not fit its JSON Schema is sent back
+pub static STRUCTURED_RETRIES: usize = 2;
 
 fn read_abbreviations() -> Vec<String> {
     let mut result: Vec<String> = vec![];
@@ -39,9 +96,24 @@ fn read_abbreviations() -> Vec<String> {
     return result;
 }
 
+// The tokeniser shared by the sentence splitter and the keyword search
+pub fn split_words(input: &str) -> std::str::SplitWhitespace<'_> {
+    input.split_whitespace()
+}
+
+// The words of a text as the keyword search sees them: lowercase, with
+// punctuation stripped from both ends. Punctuation inside a word stays,
+// so ids like "ERR-1042" or "v2.3" are kept in one piece
+pub fn search_terms(input: &str) -> Vec<String> {
+    split_words(input)
+        .map(|word| word.trim_matches(|c: char| !c.is_alphanumeric()).to_lowercase())
+        .filter(|term| !term.is_empty())
+        .collect()
+}
+
 fn split_to_sentences(input: &str) -> Vec<String> {
     let abbreviations = read_abbreviations();
-    let spaced: Vec<(usize, &str)> = input.split_whitespace().enumerate().collect();
+    let spaced: Vec<(usize, &str)> = split_words(input).enumerate().collect();
     let len = spaced.len();
 
     let mut result: Vec<String> = vec![];
@@ -98,7 +170,7 @@ pub fn load_environment(what: &str) -> String {
 
 pub fn load_sysprompt() -> Result<String, std::io::Error> {
     let mut contents: String = String::new();
-    let path: &str = "data/system_pormpt.txt";
+    let path: &str = SYSPROMPT_FILE;
 
     let mut f: fs::File = fs::File::open(path)
         .inspect(|_| info!("Successfully read system prompt"))
@@ -167,4 +239,87 @@ pub fn get_openai(url: &str, a_key: &str) -> OpenAI {
 pub fn text_to_vec(input: &str) -> Vec<String>{
     let sentences = split_to_sentences(input);
     sentences
-}
\ No newline at end of file
+}
+
+// Seconds since the unix epoch, used to timestamp memories
+pub fn now_unix() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// A unix timestamp as "2024-05-01 13:45:00 UTC", for people to read.
+// The date is worked out from the day count (Howard Hinnant's
+// civil_from_days), so no date library is needed
+pub fn format_unix(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rest = secs % 86400;
+
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, rest / 3600, rest % 3600 / 60, rest % 60)
+}
+
+// Milliseconds since the unix epoch, precise enough to tell
+// apart two sessions started right after each other
+pub fn now_unix_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0)
+}
+
+// User ids end up in file names. Letters, digits and '-' are kept as
+// they are, every other byte is written as '_' and its two hex digits,
+// so "a.b" becomes "a_2eb" and "a_b" "a_5fb" and no two ids share a file.
+// The empty id becomes a lone "_", which no other id can turn into
+pub fn sanitize_id(input: &str) -> String {
+    let mut cleaned = String::with_capacity(input.len());
+    for byte in input.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'-' {
+            cleaned.push(byte as char);
+        } else {
+            cleaned.push_str(&format!("_{:02x}", byte));
+        }
+    }
+
+    if cleaned.is_empty() { "_".to_string() }
+    else { cleaned }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_id_keeps_plain_ids() {
+        assert_eq!(sanitize_id("alice"), "alice");
+        assert_eq!(sanitize_id("user-42"), "user-42");
+    }
+
+    #[test]
+    fn sanitize_id_never_maps_two_ids_to_one_name() {
+        let ids = ["", " ", "  ", "default", "_", "a.b", "a_b", "a_2eb", "a b", " a", "a", "é", "_c3_a9", "../x"];
+        let mut names: Vec<String> = ids.iter().map(|id| sanitize_id(id)).collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), ids.len());
+    }
+
+    #[test]
+    fn sanitize_id_only_writes_safe_characters() {
+        for id in ["../../etc/passwd", "a/b\\c", "C:", "x\0y"] {
+            assert!(sanitize_id(id).chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+        }
+    }
+}
@@ -0,0 +1,1195 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{ Path, PathBuf };
+use std::sync::OnceLock;
+
+use half::f16;
+use log::*;
+use rayon::prelude::*;
+use serde::{ Deserialize, Serialize, Serializer };
+
+use crate::memory::cosine_similarity_slices;
+use crate::ivf::IvfFlatIndex;
+use crate::lazy::Lazy;
+use crate::pq::IvfPqIndex;
+use crate::scan::{ dot, norm, TopK };
+use crate::segments::{ SegmentManifest, SegmentStore };
+use crate::utils::{ load_environment, DEFAULT_NPROBE, INT8_CALIBRATION_ROWS, PARALLEL_SCAN_MIN_ROWS, RESCORE_OVERSAMPLE };
+
+
+// The indexes a collection can use on top of the full scan.
+// Untagged, so an index is recognised by its fields: IvfPq has to come
+// first, because every IVF-PQ index also has all the fields of an IVF-Flat one
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum VectorIndex {
+    IvfPq(IvfPqIndex),
+    IvfFlat(IvfFlatIndex),
+}
+
+impl VectorIndex {
+    pub fn is_empty(&self) -> bool {
+        match self {
+            VectorIndex::IvfPq(index) => index.is_empty(),
+            VectorIndex::IvfFlat(index) => index.is_empty(),
+        }
+    }
+
+    fn add(&mut self, key: &str, vector: &[f32]) {
+        match self {
+            VectorIndex::IvfPq(index) => index.add(key, vector),
+            VectorIndex::IvfFlat(index) => index.add(key, vector),
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        match self {
+            VectorIndex::IvfPq(index) => { index.remove(key); }
+            VectorIndex::IvfFlat(index) => { index.remove(key); }
+        }
+    }
+
+    fn set_nprobe(&mut self, nprobe: usize) {
+        match self {
+            VectorIndex::IvfPq(index) => index.nprobe = nprobe.clamp(1, index.centroids.len().max(1)),
+            VectorIndex::IvfFlat(index) => index.nprobe = nprobe.clamp(1, index.centroids.len().max(1)),
+        }
+    }
+
+    // The keys worth scoring exactly: for IVF-PQ the n best by approximate
+    // distance, for IVF-Flat everything in the probed lists
+    fn candidates(&self, query: &[f32], n: usize, nprobe: Option<usize>) -> Vec<String> {
+        match self {
+            VectorIndex::IvfPq(index) => index.search(query, n, nprobe).into_iter().map(|(key, _)| key).collect(),
+            VectorIndex::IvfFlat(index) => index.candidates(query, nprobe),
+        }
+    }
+}
+
+
+// How the vectors of a collection are kept in memory.
+// F32 is what the embedding model gives us, F16 halves that,
+// Int8 stores one byte per value using a per-dimension calibration
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Precision {
+    #[default]
+    F32,
+    F16,
+    Int8,
+}
+
+impl Precision {
+    pub fn bytes_per_value(self) -> usize {
+        match self {
+            Precision::F32 => 4,
+            Precision::F16 => 2,
+            Precision::Int8 => 1,
+        }
+    }
+
+    pub fn parse(input: &str) -> Option<Self> {
+        match input.trim().to_lowercase().as_str() {
+            "f32" => Some(Precision::F32),
+            "f16" => Some(Precision::F16),
+            "int8" | "i8" => Some(Precision::Int8),
+            _ => None
+        }
+    }
+
+    // The precision new collections start with, from VECTOR_PRECISION
+    pub fn from_env() -> Self {
+        let value = load_environment("VECTOR_PRECISION");
+        if value.trim().is_empty() {
+            return Precision::default();
+        }
+
+        Precision::parse(&value).unwrap_or_else(|| {
+            warn!("Unknown VECTOR_PRECISION '{}', using f32", value);
+            Precision::default()
+        })
+    }
+}
+
+// Where the vector rows of a collection live. Memory keeps them in one
+// buffer on the heap, Mapped in memory-mapped segment files on disk
+// (see segments.rs), for collections bigger than the RAM
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Storage {
+    #[default]
+    Memory,
+    Mapped,
+}
+
+impl Storage {
+    pub fn parse(input: &str) -> Option<Self> {
+        match input.trim().to_lowercase().as_str() {
+            "memory" | "heap" => Some(Storage::Memory),
+            "mmap" | "mapped" => Some(Storage::Mapped),
+            _ => None
+        }
+    }
+
+    // The storage new collections start with, from VECTOR_STORAGE
+    pub fn from_env() -> Self {
+        let value = load_environment("VECTOR_STORAGE");
+        if value.trim().is_empty() {
+            return Storage::default();
+        }
+
+        Storage::parse(&value).unwrap_or_else(|| {
+            warn!("Unknown VECTOR_STORAGE '{}', keeping vectors in memory", value);
+            Storage::default()
+        })
+    }
+}
+
+// Whether new collections keep full precision vectors for rescoring,
+// from VECTOR_RESCORE
+pub fn rescore_from_env() -> bool {
+    matches!(load_environment("VECTOR_RESCORE").trim().to_lowercase().as_str(), "1" | "true" | "yes")
+}
+
+// Per-dimension range used by int8 quantisation: a value x of dimension i
+// is stored as round((x - min[i]) / scale[i]), which always fits in a byte
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Int8Calibration {
+    pub min: Vec<f32>,
+    pub scale: Vec<f32>,
+    pub calibrated_rows: usize,
+}
+
+impl Int8Calibration {
+    fn from_rows(rows: &[Vec<f32>], dim: usize) -> Self {
+        let mut min = vec![f32::INFINITY; dim];
+        let mut max = vec![f32::NEG_INFINITY; dim];
+
+        for row in rows {
+            for (i, x) in row.iter().enumerate() {
+                min[i] = min[i].min(*x);
+                max[i] = max[i].max(*x);
+            }
+        }
+
+        // A dimension that never changes still needs a non-zero scale
+        let scale: Vec<f32> = min.iter().zip(max.iter())
+            .map(|(lo, hi)| if hi > lo { (hi - lo) / 255.0 } else { 1.0 })
+            .collect();
+        let min: Vec<f32> = min.into_iter().map(|lo| if lo.is_finite() { lo } else { 0.0 }).collect();
+
+        Int8Calibration { min, scale, calibrated_rows: rows.len() }
+    }
+
+    // Values outside the calibrated range are clamped to it
+    fn encode(&self, vector: &[f32], out: &mut Vec<u8>) {
+        for (i, x) in vector.iter().enumerate() {
+            let code = ((x - self.min[i]) / self.scale[i]).round().clamp(0.0, 255.0);
+            out.push(code as u8);
+        }
+    }
+
+    fn decode(&self, codes: &[u8], out: &mut [f32]) {
+        for (i, code) in codes.iter().enumerate() {
+            out[i] = self.min[i] + self.scale[i] * (*code as f32);
+        }
+    }
+}
+
+// Which key every row belongs to and, in a memory-mapped collection,
+// which segment record holds the row. Both always go together
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct RowTable {
+    keys: Vec<String>,
+    #[serde(default)]
+    slots: Vec<usize>,
+}
+
+// The files a memory-mapped collection keeps its row table, rescore
+// originals and index in, next to its segments. Every checkpoint writes
+// them under a new catalog number before the memory file is pointed at
+// them, so a crash in between still finds the ones it pointed at before
+fn catalog_path(dir: &Path, catalog: u64, part: &str) -> PathBuf {
+    dir.join(format!("{}-{:06}.json", part, catalog))
+}
+
+// What actually gets written to disk, the key to row lookup table is
+// rebuilt on first use. A memory-mapped collection only writes how many
+// rows it has and where its segment and catalog files are, everything
+// else is read from those when first needed.
+// Older memory files stored every vector as a list of f64 values keyed
+// by memory key, those are still understood
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredVectors {
+    Current {
+        precision: Precision,
+        encoding: Precision,
+        dim: usize,
+        keys: Vec<String>,
+        data: Vec<u8>,
+        calibration: Option<Int8Calibration>,
+        rescore: bool,
+        originals: HashMap<String, Vec<f32>>,
+        #[serde(default)]
+        index: Option<Box<VectorIndex>>,
+        #[serde(default)]
+        storage: Storage,
+    },
+    Mapped {
+        precision: Precision,
+        encoding: Precision,
+        dim: usize,
+        len: usize,
+        catalog: u64,
+        segments: SegmentManifest,
+        calibration: Option<Int8Calibration>,
+        rescore: bool,
+    },
+    Legacy(HashMap<String, Vec<f64>>),
+}
+
+// The self-contained form of a collection, with every row inline no
+// matter where they live. Snapshots and exports always get this one
+#[derive(Serialize)]
+struct PortableVectors<'a> {
+    precision: Precision,
+    encoding: Precision,
+    dim: usize,
+    keys: &'a [String],
+    data: Cow<'a, [u8]>,
+    calibration: &'a Option<Int8Calibration>,
+    rescore: bool,
+    originals: &'a HashMap<String, Vec<f32>>,
+    index: &'a Option<VectorIndex>,
+    storage: Storage,
+}
+
+// What the memory file of a memory-mapped collection holds
+#[derive(Serialize)]
+pub struct MappedVectors<'a> {
+    precision: Precision,
+    encoding: Precision,
+    dim: usize,
+    len: usize,
+    catalog: u64,
+    segments: SegmentManifest,
+    calibration: &'a Option<Int8Calibration>,
+    rescore: bool,
+}
+
+// All the vectors of one collection in one row-major byte buffer.
+// precision is what the collection should use, encoding is what the
+// buffer holds right now: an int8 collection keeps f32 rows until it
+// has seen enough vectors to calibrate on.
+// With rescore on, the full precision vectors are kept as well, and the
+// best compressed matches are scored again against them.
+// An optional index narrows a search down to a few candidates, which are
+// then scored exactly like in a full scan.
+// The norm of every stored row is kept next to the buffer, so a search
+// only has to compute one dot product per row.
+// With Mapped storage, data and norms stay empty: the rows and their
+// norms are records in the segment files, and the slots of the row table
+// say which record holds which row. The row table, originals and index
+// of a mapped collection are read from its catalog files on first use,
+// until then mapped_len says how many rows there are
+#[derive(Debug, Default, Deserialize)]
+#[serde(try_from = "StoredVectors")]
+pub struct VectorStore {
+    pub precision: Precision,
+    pub encoding: Precision,
+    pub dim: usize,
+    pub data: Vec<u8>,
+    pub calibration: Option<Int8Calibration>,
+    pub rescore: bool,
+    pub storage: Storage,
+    table: Lazy<RowTable>,
+    originals: Lazy<HashMap<String, Vec<f32>>>,
+    index: Lazy<Option<VectorIndex>>,
+    rows: OnceLock<HashMap<String, usize>>,
+    norms: Vec<f32>,
+    segments: Option<SegmentStore>,
+    mapped_len: usize,
+    catalog: u64,
+}
+
+impl Serialize for VectorStore {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let data: Cow<[u8]> = match &self.segments {
+            Some(_) => Cow::Owned((0..self.len()).flat_map(|row| self.row_data(row).iter().copied()).collect()),
+            None => Cow::Borrowed(&self.data)
+        };
+
+        PortableVectors {
+            precision: self.precision,
+            encoding: self.encoding,
+            dim: self.dim,
+            keys: &self.table.keys,
+            data,
+            calibration: &self.calibration,
+            rescore: self.rescore,
+            originals: &self.originals,
+            index: &self.index,
+            storage: self.storage,
+        }.serialize(serializer)
+    }
+}
+
+// The query in the form the rows are scored against. For int8 rows the
+// per-dimension calibration is folded into the query once, so a row's
+// codes can be dotted with it directly:
+// q . (min + scale * c) = q . min + (q * scale) . c
+struct PreparedQuery {
+    values: Vec<f32>,
+    offset: f32,
+    norm: f32,
+}
+
+impl TryFrom<StoredVectors> for VectorStore {
+    type Error = String;
+
+    fn try_from(stored: StoredVectors) -> Result<Self, Self::Error> {
+        match stored {
+            StoredVectors::Current { precision, encoding, dim, keys, data, calibration, rescore, originals, index, storage } => {
+                let mut store = VectorStore {
+                    precision, encoding, dim, data, calibration, rescore, storage,
+                    table: Lazy::from(RowTable { keys, slots: vec![] }),
+                    originals: Lazy::from(originals),
+                    index: Lazy::from(index.map(|i| *i)),
+                    ..Default::default()
+                };
+                store.rebuild_norms();
+                Ok(store)
+            }
+            StoredVectors::Mapped { precision, encoding, dim, len, catalog, segments, calibration, rescore } => {
+                if segments.row_bytes != dim * encoding.bytes_per_value() {
+                    return Err(format!("vector segments in {} don't match the collection", segments.dir.display()));
+                }
+                if len > segments.len {
+                    return Err(format!("vector segments in {} are missing records", segments.dir.display()));
+                }
+
+                // The row table is only usable if it has a record for
+                // every row, and all of them are in the segments
+                let records = segments.len;
+                let table = Lazy::<RowTable>::from_file(&catalog_path(&segments.dir, catalog, "rows"))
+                    .with_check(move |table| {
+                        if table.keys.len() != len || table.slots.len() != len {
+                            return Err(format!("it has {} keys and {} slots for {} rows", table.keys.len(), table.slots.len(), len));
+                        }
+                        if table.slots.iter().any(|slot| *slot >= records) {
+                            return Err("it points past the last vector record".to_string());
+                        }
+                        Ok(())
+                    });
+                let originals = Lazy::from_file(&catalog_path(&segments.dir, catalog, "originals"));
+                let index = Lazy::from_file(&catalog_path(&segments.dir, catalog, "index"));
+
+                let segments = SegmentStore::open(&segments).map_err(|e| e.to_string())?;
+                Ok(VectorStore {
+                    precision, encoding, dim, calibration, rescore, table, originals, index, catalog,
+                    storage: Storage::Mapped,
+                    segments: Some(segments),
+                    mapped_len: len,
+                    ..Default::default()
+                })
+            }
+            StoredVectors::Legacy(memories) => {
+                let mut store = VectorStore::new(Precision::F32, false);
+                for (key, vector) in memories {
+                    let vector: Vec<f32> = vector.iter().map(|x| *x as f32).collect();
+                    store.insert(&key, &vector);
+                }
+                Ok(store)
+            }
+        }
+    }
+}
+
+impl VectorStore {
+    pub fn new(precision: Precision, rescore: bool) -> Self {
+        let encoding = match precision {
+            Precision::Int8 => Precision::F32,
+            other => other
+        };
+        VectorStore { precision, encoding, rescore, ..Default::default() }
+    }
+
+    pub fn with_storage(mut self, storage: Storage) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        self.segments.is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        match self.table.pending_source() {
+            Some(_) => self.mapped_len,
+            None => self.table.keys.len()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.rows().contains_key(key)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.table.keys.iter()
+    }
+
+    // The row of every key, built from the row table on first use
+    fn rows(&self) -> &HashMap<String, usize> {
+        self.rows.get_or_init(|| self.table.keys.iter().enumerate().map(|(i, k)| (k.clone(), i)).collect())
+    }
+
+    fn rows_mut(&mut self) -> &mut HashMap<String, usize> {
+        self.rows();
+        self.rows.get_mut().unwrap()
+    }
+
+    fn row_bytes(&self) -> usize {
+        self.dim * self.encoding.bytes_per_value()
+    }
+
+    fn encode_into(&self, vector: &[f32], out: &mut Vec<u8>) {
+        match self.encoding {
+            Precision::F32 => {
+                for x in vector { out.extend_from_slice(&x.to_le_bytes()); }
+            }
+            Precision::F16 => {
+                for x in vector { out.extend_from_slice(&f16::from_f32(*x).to_le_bytes()); }
+            }
+            Precision::Int8 => match &self.calibration {
+                Some(calibration) => calibration.encode(vector, out),
+                None => error!("Int8 encoding without calibration, this is a bug")
+            }
+        }
+    }
+
+    // The encoded bytes of one row, wherever they are stored
+    fn row_data(&self, row: usize) -> &[u8] {
+        match &self.segments {
+            Some(segments) => segments.row(self.table.slots[row]),
+            None => &self.data[row * self.row_bytes()..(row + 1) * self.row_bytes()]
+        }
+    }
+
+    fn stored_norm(&self, row: usize) -> f32 {
+        match &self.segments {
+            Some(segments) => segments.norm(self.table.slots[row]),
+            None => self.norms[row]
+        }
+    }
+
+    // Decodes one row into out, which must be dim long
+    fn decode_row(&self, row: usize, out: &mut [f32]) {
+        self.decode_bytes(self.row_data(row), out);
+    }
+
+    fn decode_bytes(&self, bytes: &[u8], out: &mut [f32]) {
+        match self.encoding {
+            Precision::F32 => {
+                for (i, chunk) in bytes.chunks_exact(4).enumerate() {
+                    out[i] = f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+                }
+            }
+            Precision::F16 => {
+                for (i, chunk) in bytes.chunks_exact(2).enumerate() {
+                    out[i] = f16::from_le_bytes([chunk[0], chunk[1]]).to_f32();
+                }
+            }
+            Precision::Int8 => {
+                if let Some(calibration) = &self.calibration {
+                    calibration.decode(bytes, out);
+                }
+            }
+        }
+    }
+
+    // The length of a row as it is stored, so what a search compares against
+    fn encoded_norm(&self, bytes: &[u8]) -> f32 {
+        let mut out = vec![0.0; self.dim];
+        self.decode_bytes(bytes, &mut out);
+        norm(&out)
+    }
+
+    fn rebuild_norms(&mut self) {
+        if self.is_mapped() {
+            return;
+        }
+        self.norms = (0..self.len()).map(|row| self.encoded_norm(self.row_data(row))).collect();
+    }
+
+    // Returns a vector in full precision if we kept it, otherwise the
+    // decoded version of what is stored
+    pub fn get(&self, key: &str) -> Option<Vec<f32>> {
+        if self.rescore
+            && let Some(original) = self.originals.get(key) {
+            return Some(original.clone());
+        }
+
+        let row = *self.rows().get(key)?;
+        let mut out = vec![0.0; self.dim];
+        self.decode_row(row, &mut out);
+        Some(out)
+    }
+
+    // Adds or replaces the vector stored under key. Returns false if the
+    // vector doesn't have the same length as the ones already stored
+    pub fn insert(&mut self, key: &str, vector: &[f32]) -> bool {
+        // An empty collection takes the length of the first vector,
+        // an int8 calibration made for another length is useless then
+        if self.is_empty() && vector.len() != self.dim {
+            self.dim = vector.len();
+            if self.encoding == Precision::Int8 {
+                self.encoding = Precision::F32;
+                self.calibration = None;
+            }
+        }
+        if vector.len() != self.dim {
+            error!("Vector for '{}' has length {}, but the collection uses {}", key, vector.len(), self.dim);
+            return false;
+        }
+
+        // Segments are made for one row length, an empty collection that
+        // just changed its length needs a new generation
+        if let Some(segments) = &self.segments
+            && segments.row_bytes != self.row_bytes() {
+            let base = segments.dir.parent().map(|p| p.to_path_buf()).unwrap_or_default();
+            self.segments = None;
+            self.table.slots.clear();
+            if let Err(e) = self.map_to(&base) {
+                error!("Failed to start new vector segments in {}: {e}", base.display());
+                return false;
+            }
+        }
+
+        let mut encoded: Vec<u8> = Vec::with_capacity(self.row_bytes());
+        self.encode_into(vector, &mut encoded);
+        let row_norm = self.encoded_norm(&encoded);
+        let existing = self.rows().get(key).copied();
+
+        // Mapped rows are never overwritten, a replaced vector
+        // just gets a new record
+        if let Some(segments) = &mut self.segments {
+            let slot = match segments.append(row_norm, &encoded) {
+                Ok(slot) => slot,
+                Err(e) => {
+                    error!("Failed to write vector for '{}' to its segment: {e}", key);
+                    return false;
+                }
+            };
+            match existing {
+                Some(row) => self.table.slots[row] = slot,
+                None => self.table.slots.push(slot)
+            }
+        } else {
+            match existing {
+                Some(row) => {
+                    let row_bytes = self.row_bytes();
+                    self.data[row * row_bytes..(row + 1) * row_bytes].copy_from_slice(&encoded);
+                    self.norms[row] = row_norm;
+                }
+                None => {
+                    self.data.extend_from_slice(&encoded);
+                    self.norms.push(row_norm);
+                }
+            }
+        }
+
+        if existing.is_none() {
+            let row = self.table.keys.len();
+            self.rows_mut().insert(key.to_string(), row);
+            self.table.keys.push(key.to_string());
+        }
+
+        if self.rescore {
+            self.originals.insert(key.to_string(), vector.to_vec());
+        }
+        if let Some(index) = &mut *self.index {
+            index.add(key, vector);
+        }
+
+        self.calibrate_if_needed();
+        self.rebalance_if_needed();
+        true
+    }
+
+    // Removes a vector by moving the last row into its place
+    pub fn remove(&mut self, key: &str) -> bool {
+        let Some(row) = self.rows_mut().remove(key) else {
+            return false;
+        };
+
+        let row_bytes = self.row_bytes();
+        let last = self.table.keys.len() - 1;
+        if row != last {
+            if !self.is_mapped() {
+                self.data.copy_within(last * row_bytes..(last + 1) * row_bytes, row * row_bytes);
+            }
+            let moved = self.table.keys[last].clone();
+            self.rows_mut().insert(moved.clone(), row);
+            self.table.keys[row] = moved;
+        }
+
+        self.table.keys.pop();
+        if self.is_mapped() {
+            // The record stays in its segment until the next compaction
+            self.table.slots.swap_remove(row);
+        } else {
+            self.data.truncate(last * row_bytes);
+            self.norms.swap_remove(row);
+        }
+        if self.rescore {
+            self.originals.remove(key);
+        }
+        if let Some(index) = &mut *self.index {
+            index.remove(key);
+        }
+        true
+    }
+
+    // Every vector in full precision if kept, decoded otherwise
+    fn all_vectors(&self) -> Vec<Vec<f32>> {
+        self.table.keys.iter()
+            .filter_map(|key| self.get(key))
+            .collect()
+    }
+
+    // Encodes every vector again with the given encoding. The rows are
+    // built on the heap first, a mapped collection then gets them written
+    // into a new generation of segments.
+    // An int8 calibration is only made from rows that are not int8 yet,
+    // calibrating on decoded int8 rows would just repeat their rounding
+    fn reencode(&mut self, encoding: Precision) {
+        let vectors = self.all_vectors();
+
+        if encoding == Precision::Int8 && (self.encoding != Precision::Int8 || self.calibration.is_none()) {
+            self.calibration = Some(Int8Calibration::from_rows(&vectors, self.dim));
+        }
+        self.encoding = encoding;
+
+        let mut data: Vec<u8> = Vec::with_capacity(vectors.len() * self.row_bytes());
+        for vector in &vectors {
+            self.encode_into(vector, &mut data);
+        }
+
+        let segments_base = self.segments.take().and_then(|s| s.dir.parent().map(|p| p.to_path_buf()));
+        self.table.slots.clear();
+        self.data = data;
+        self.rebuild_norms();
+
+        if let Some(base) = segments_base
+            && let Err(e) = self.map_to(&base) {
+            error!("Failed to write re-encoded vectors to {}, keeping them in memory: {e}", base.display());
+        }
+    }
+
+    // Copies every row, in row order, into a new generation of segments
+    // below base, and switches the collection over to it
+    pub fn map_to(&mut self, base: &Path) -> std::io::Result<()> {
+        let mut segments = SegmentStore::create(base, self.row_bytes())?;
+        for row in 0..self.len() {
+            segments.append(self.stored_norm(row), self.row_data(row))?;
+        }
+
+        info!("Mapped {} vectors to {}", self.len(), segments.dir.display());
+        self.table.slots = (0..self.len()).collect();
+        self.segments = Some(segments);
+        self.data = vec![];
+        self.norms = vec![];
+        Ok(())
+    }
+
+    // Moves the rows of a mapped collection back onto the heap
+    pub fn unmap(&mut self) {
+        if !self.is_mapped() {
+            return;
+        }
+
+        let data: Vec<u8> = (0..self.len()).flat_map(|row| self.row_data(row).iter().copied()).collect();
+        let norms: Vec<f32> = (0..self.len()).map(|row| self.stored_norm(row)).collect();
+        self.segments = None;
+        self.table.slots = vec![];
+        self.data = data;
+        self.norms = norms;
+    }
+
+    // Segments only ever grow, replaced and removed vectors leave dead
+    // records behind. Once those outnumber the live ones, the live rows
+    // are copied into a fresh generation
+    pub fn compact_if_needed(&mut self) -> std::io::Result<()> {
+        let Some(segments) = &self.segments else {
+            return Ok(());
+        };
+
+        let dead = segments.len - self.len();
+        if dead == 0 || dead <= self.len() {
+            return Ok(());
+        }
+
+        info!("Compacting vector segments, {} of {} records are dead", dead, segments.len);
+        let base = segments.dir.parent().map(|p| p.to_path_buf()).unwrap_or_default();
+        self.map_to(&base)
+    }
+
+    // Writes mapped rows back to the disk, then the catalog files under
+    // the next catalog number, and describes where they all are.
+    // None if the collection is not mapped
+    pub fn sync_segments(&mut self) -> std::io::Result<Option<MappedVectors<'_>>> {
+        let Some(segments) = &mut self.segments else {
+            return Ok(None);
+        };
+        segments.flush()?;
+        let manifest = segments.manifest();
+
+        let catalog = self.catalog + 1;
+        self.table.save_to(&catalog_path(&manifest.dir, catalog, "rows"))?;
+        self.originals.save_to(&catalog_path(&manifest.dir, catalog, "originals"))?;
+        self.index.save_to(&catalog_path(&manifest.dir, catalog, "index"))?;
+        self.catalog = catalog;
+
+        Ok(Some(MappedVectors {
+            precision: self.precision,
+            encoding: self.encoding,
+            dim: self.dim,
+            len: self.len(),
+            catalog,
+            segments: manifest,
+            calibration: &self.calibration,
+            rescore: self.rescore,
+        }))
+    }
+
+    // Deletes the catalog files of earlier checkpoints, once the memory
+    // file points to the current ones
+    pub fn remove_stale_catalogs(&self) {
+        let Some(segments) = &self.segments else {
+            return;
+        };
+        let Ok(entries) = fs::read_dir(&segments.dir) else {
+            return;
+        };
+
+        let current = format!("-{:06}.json", self.catalog);
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !name.ends_with(".json") || name.ends_with(&current) {
+                continue;
+            }
+            if let Err(e) = fs::remove_file(entry.path()) {
+                warn!("Failed to remove old catalog file {}: {e}", entry.path().display());
+            }
+        }
+    }
+
+    pub fn segment_generation(&self) -> Option<u64> {
+        self.segments.as_ref().map(|s| s.generation)
+    }
+
+    // An int8 collection is calibrated once, when it has
+    // INT8_CALIBRATION_ROWS vectors that are still in full precision.
+    // Later values outside the calibrated range are clamped to it
+    fn calibrate_if_needed(&mut self) {
+        if self.precision != Precision::Int8 || self.encoding == Precision::Int8 {
+            return;
+        }
+
+        if self.len() >= INT8_CALIBRATION_ROWS {
+            info!("Calibrating int8 quantisation on {} vectors", self.len());
+            self.reencode(Precision::Int8);
+        }
+    }
+
+    // Switches the collection to another precision. Going to a higher
+    // precision can't bring back what was lost, unless rescoring kept
+    // the original vectors around
+    pub fn set_precision(&mut self, precision: Precision, rescore: bool) {
+        if rescore && !self.rescore {
+            // From now on the originals are kept, for the vectors we already
+            // have the decoded ones are the best we can do
+            let decoded: Vec<(String, Vec<f32>)> = self.table.keys.iter()
+                .filter_map(|key| self.get(key).map(|v| (key.clone(), v)))
+                .collect();
+            self.originals.extend(decoded);
+        }
+
+        self.precision = precision;
+        let encoding = match precision {
+            Precision::Int8 if self.len() < INT8_CALIBRATION_ROWS => Precision::F32,
+            other => other
+        };
+        self.reencode(encoding);
+
+        self.rescore = rescore;
+        if !rescore {
+            self.originals.clear();
+        }
+    }
+
+    // Roughly the square root of the collection size, a common
+    // starting point for the number of IVF lists
+    fn default_nlist(&self) -> usize {
+        ((self.len() as f64).sqrt().ceil() as usize).max(1)
+    }
+
+    // Trains an IVF-PQ index on the stored vectors and adds all of them to it
+    pub fn build_pq_index(&mut self, nlist: Option<usize>, sub_vectors: usize, codebook_size: usize) -> Result<(), String> {
+        let keys: Vec<String> = self.table.keys.clone();
+        let vectors: Vec<Vec<f32>> = self.all_vectors();
+        let nlist = nlist.unwrap_or_else(|| self.default_nlist());
+
+        let mut index = IvfPqIndex::train(&vectors, nlist, sub_vectors, codebook_size, DEFAULT_NPROBE)?;
+        for (key, vector) in keys.iter().zip(vectors.iter()) {
+            index.add(key, vector);
+        }
+
+        info!("Built IVF-PQ index with {} lists over {} vectors", index.centroids.len(), index.len());
+        *self.index = Some(VectorIndex::IvfPq(index));
+        Ok(())
+    }
+
+    // Trains an IVF-Flat index on the stored vectors and adds all of them to it
+    pub fn build_ivf_index(&mut self, nlist: Option<usize>) -> Result<(), String> {
+        let keys: Vec<String> = self.table.keys.clone();
+        let vectors: Vec<Vec<f32>> = self.all_vectors();
+        let nlist = nlist.unwrap_or_else(|| self.default_nlist());
+
+        // A retrained index keeps the nprobe it was tuned to
+        let nprobe = match &*self.index {
+            Some(VectorIndex::IvfFlat(old)) => old.nprobe,
+            _ => DEFAULT_NPROBE
+        };
+
+        let mut index = IvfFlatIndex::train(&vectors, nlist, nprobe)?;
+        for (key, vector) in keys.iter().zip(vectors.iter()) {
+            index.add(key, vector);
+        }
+
+        info!("Built IVF-Flat index with {} lists over {} vectors", index.centroids.len(), index.len());
+        *self.index = Some(VectorIndex::IvfFlat(index));
+        Ok(())
+    }
+
+    // An IVF-Flat index is cheap to train, so it is retrained on its own
+    // once the collection outgrew it. An IVF-PQ index has to be rebuilt by hand
+    fn rebalance_if_needed(&mut self) {
+        let due = matches!(&*self.index, Some(VectorIndex::IvfFlat(index)) if index.needs_rebalance());
+        if !due {
+            return;
+        }
+
+        info!("Collection outgrew its IVF-Flat index, retraining it");
+        if let Err(e) = self.build_ivf_index(None) {
+            error!("Failed to retrain IVF-Flat index: {e}");
+        }
+    }
+
+    pub fn drop_index(&mut self) {
+        *self.index = None;
+    }
+
+    // Changes how many lists an index search looks at by default
+    pub fn set_nprobe(&mut self, nprobe: usize) -> bool {
+        match &mut *self.index {
+            Some(index) => {
+                index.set_nprobe(nprobe);
+                true
+            }
+            None => false
+        }
+    }
+
+    fn prepare_query(&self, query: &[f32]) -> PreparedQuery {
+        let norm = norm(query);
+        match (&self.encoding, &self.calibration) {
+            (Precision::Int8, Some(calibration)) => PreparedQuery {
+                values: query.iter().zip(calibration.scale.iter()).map(|(q, s)| q * s).collect(),
+                offset: dot(query, &calibration.min),
+                norm,
+            },
+            _ => PreparedQuery { values: query.to_vec(), offset: 0.0, norm }
+        }
+    }
+
+    // Cosine similarity of one row to the query. f32 rows are read in
+    // place when the buffer happens to be aligned for it, everything else
+    // goes through the scratch buffer first
+    fn score_row(&self, query: &PreparedQuery, row: usize, scratch: &mut [f32]) -> f32 {
+        let row_norm = self.stored_norm(row);
+        if row_norm == 0.0 || query.norm == 0.0 {
+            return 0.0;
+        }
+
+        let bytes = self.row_data(row);
+        let dot_product = match self.encoding {
+            Precision::F32 => {
+                // Safe because every bit pattern is a valid f32
+                let (head, values, tail) = unsafe { bytes.align_to::<f32>() };
+                if cfg!(target_endian = "little") && head.is_empty() && tail.is_empty() {
+                    dot(&query.values, values)
+                } else {
+                    self.decode_row(row, scratch);
+                    dot(&query.values, scratch)
+                }
+            }
+            Precision::F16 => {
+                self.decode_row(row, scratch);
+                dot(&query.values, scratch)
+            }
+            Precision::Int8 => {
+                for (out, code) in scratch.iter_mut().zip(bytes.iter()) {
+                    *out = *code as f32;
+                }
+                query.offset + dot(&query.values, scratch)
+            }
+        };
+
+        dot_product / (query.norm * row_norm)
+    }
+
+    // Scores the given rows (all of them if None) and keeps the k best,
+    // skipping keys the filter rejects. Big scans are split across all
+    // cores, each thread keeping its own top k, and those get merged
+    fn top_rows(&self, query: &PreparedQuery, rows: Option<&[usize]>, k: usize, filter: &(impl Fn(&str) -> bool + Sync)) -> Vec<(usize, f32)> {
+        let count = rows.map_or(self.len(), |r| r.len());
+        let row_at = |i: usize| rows.map_or(i, |r| r[i]);
+
+        let scan = |(mut top, mut scratch): (TopK, Vec<f32>), i: usize| {
+            let row = row_at(i);
+            if filter(&self.table.keys[row]) {
+                top.push(row, self.score_row(query, row, &mut scratch));
+            }
+            (top, scratch)
+        };
+
+        let top = if count >= PARALLEL_SCAN_MIN_ROWS {
+            (0..count).into_par_iter()
+                .fold(|| (TopK::new(k), vec![0.0; self.dim]), scan)
+                .map(|(top, _)| top)
+                .reduce(|| TopK::new(k), TopK::merge)
+        } else {
+            (0..count).fold((TopK::new(k), vec![0.0; self.dim]), scan).0
+        };
+
+        top.into_sorted()
+    }
+
+    pub fn search(&self, query: &[f32], top_k: usize, filter: impl Fn(&str) -> bool + Sync) -> Vec<(String, f32)> {
+        self.search_with_nprobe(query, top_k, None, filter)
+    }
+
+    // Scores the stored vectors against the query straight from the
+    // compressed buffer, and returns the top_k (key, similarity) pairs.
+    // Keys the filter rejects are left out.
+    // With an index, only the candidates it finds in the nprobe closest
+    // lists are scored, otherwise every vector is.
+    // With rescoring on, a few more candidates than needed are taken and
+    // scored again against their original vectors
+    pub fn search_with_nprobe(&self, query: &[f32], top_k: usize, nprobe: Option<usize>, filter: impl Fn(&str) -> bool + Sync) -> Vec<(String, f32)> {
+        if query.len() != self.dim || top_k == 0 {
+            return vec![];
+        }
+
+        let use_originals = self.rescore && !self.originals.is_empty() && self.encoding != Precision::F32;
+        let candidates = if use_originals { top_k * RESCORE_OVERSAMPLE } else { top_k };
+
+        let prepared = self.prepare_query(query);
+        let mut scored: Vec<(usize, f32)> = match &*self.index {
+            Some(index) if !index.is_empty() => {
+                let found = index.candidates(query, top_k * RESCORE_OVERSAMPLE, nprobe);
+                let rows: Vec<usize> = found.iter().filter_map(|key| self.rows().get(key).copied()).collect();
+                self.top_rows(&prepared, Some(&rows), candidates, &filter)
+            }
+            _ => self.top_rows(&prepared, None, candidates, &filter)
+        };
+
+        if use_originals {
+            for (row, similarity) in scored.iter_mut() {
+                if let Some(original) = self.originals.get(&self.table.keys[*row]) {
+                    *similarity = cosine_similarity_slices(query, original);
+                }
+            }
+            scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+            scored.truncate(top_k);
+        }
+
+        scored.into_iter()
+            .map(|(row, similarity)| (self.table.keys[row].clone(), similarity))
+            .collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Deterministic vectors spread over [-1, 1] in every dimension
+    fn vectors(count: usize, dim: usize) -> Vec<Vec<f32>> {
+        (0..count)
+            .map(|i| (0..dim).map(|j| (((i * 31 + j * 17) % 101) as f32 / 50.0) - 1.0).collect())
+            .collect()
+    }
+
+    fn filled(precision: Precision, rescore: bool, data: &[Vec<f32>]) -> VectorStore {
+        let mut store = VectorStore::new(precision, rescore);
+        for (i, v) in data.iter().enumerate() {
+            assert!(store.insert(&format!("k{}", i), v));
+        }
+        store
+    }
+
+    #[test]
+    fn int8_round_trip_stays_within_half_a_step() {
+        let data = vectors(INT8_CALIBRATION_ROWS, 8);
+        let calibration = Int8Calibration::from_rows(&data, 8);
+
+        for vector in &data {
+            let mut codes: Vec<u8> = vec![];
+            calibration.encode(vector, &mut codes);
+            let mut decoded = vec![0.0; 8];
+            calibration.decode(&codes, &mut decoded);
+            for (i, (x, y)) in vector.iter().zip(decoded.iter()).enumerate() {
+                assert!((x - y).abs() <= calibration.scale[i] / 2.0 + 1e-6, "{} decoded as {}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn int8_values_outside_the_range_are_clamped() {
+        let calibration = Int8Calibration::from_rows(&[vec![0.0, 5.0], vec![1.0, 5.0]], 2);
+        let mut codes: Vec<u8> = vec![];
+        calibration.encode(&[2.0, 5.0], &mut codes);
+        calibration.encode(&[-1.0, 5.0], &mut codes);
+        assert_eq!(codes, vec![255, 0, 0, 0]);
+    }
+
+    #[test]
+    fn int8_is_calibrated_once_at_the_calibration_row_count() {
+        let data = vectors(INT8_CALIBRATION_ROWS * 2, 4);
+        let mut store = filled(Precision::Int8, false, &data[..INT8_CALIBRATION_ROWS - 1]);
+        assert_eq!(store.encoding, Precision::F32);
+        assert!(store.calibration.is_none());
+
+        store.insert("last", &data[INT8_CALIBRATION_ROWS - 1]);
+        assert_eq!(store.encoding, Precision::Int8);
+        let calibration = store.calibration.clone().unwrap();
+        assert_eq!(calibration.calibrated_rows, INT8_CALIBRATION_ROWS);
+
+        // Later vectors, even ones far outside the range, don't recalibrate
+        for (i, v) in data[INT8_CALIBRATION_ROWS..].iter().enumerate() {
+            store.insert(&format!("more{}", i), v);
+        }
+        store.insert("outlier", &[10.0, -10.0, 10.0, -10.0]);
+        let after = store.calibration.as_ref().unwrap();
+        assert_eq!(after.calibrated_rows, INT8_CALIBRATION_ROWS);
+        assert_eq!(after.min, calibration.min);
+        assert_eq!(after.scale, calibration.scale);
+    }
+
+    #[test]
+    fn rescore_keeps_the_originals() {
+        let data = vectors(INT8_CALIBRATION_ROWS, 4);
+        let store = filled(Precision::Int8, true, &data);
+        assert_eq!(store.encoding, Precision::Int8);
+        assert_eq!(store.originals.len(), data.len());
+        for (i, v) in data.iter().enumerate() {
+            assert_eq!(store.get(&format!("k{}", i)).unwrap(), *v);
+        }
+
+        // Rescored similarities are the exact ones
+        let results = store.search(&data[3], 5, |_| true);
+        assert_eq!(results[0].0, "k3");
+        for (key, similarity) in &results {
+            let original = &store.originals[key];
+            assert_eq!(*similarity, cosine_similarity_slices(&data[3], original));
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("vectors-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    // What loading the memory file of a mapped collection gives back
+    fn reload(store: &mut VectorStore) -> VectorStore {
+        let json = serde_json::to_string(&store.sync_segments().unwrap().unwrap()).unwrap();
+        store.remove_stale_catalogs();
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn a_mapped_collection_reads_its_catalog_on_first_use() {
+        let dir = temp_dir("lazy");
+        let data = vectors(10, 4);
+        let mut store = filled(Precision::F32, true, &data);
+        store.map_to(&dir).unwrap();
+
+        let mut loaded = reload(&mut store);
+        assert!(loaded.table.pending_source().is_some());
+        assert!(loaded.originals.pending_source().is_some());
+        assert!(loaded.index.pending_source().is_some());
+        assert_eq!(loaded.len(), 10);
+
+        assert_eq!(loaded.search(&data[4], 1, |_| true)[0].0, "k4");
+        assert!(loaded.table.is_loaded());
+        assert_eq!(loaded.get("k7").unwrap(), data[7]);
+
+        // A checkpoint copies what was never read, and the next load
+        // finds it under the new catalog number
+        loaded.build_ivf_index(Some(2)).unwrap();
+        let again = reload(&mut loaded);
+        assert_eq!(again.len(), 10);
+        assert_eq!(again.get("k2").unwrap(), data[2]);
+        assert!(again.index.is_some());
+
+        let catalogs = fs::read_dir(&loaded.segments.as_ref().unwrap().dir).unwrap()
+            .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().ends_with(".json"))
+            .count();
+        assert_eq!(catalogs, 3);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_row_table_that_does_not_fit_is_ignored() {
+        let dir = temp_dir("check");
+        let mut store = filled(Precision::F32, false, &vectors(3, 4));
+        store.map_to(&dir).unwrap();
+        let json = serde_json::to_string(&store.sync_segments().unwrap().unwrap()).unwrap();
+
+        let rows = catalog_path(&store.segments.as_ref().unwrap().dir, store.catalog, "rows");
+        let broken = RowTable { keys: vec!["k0".to_string(), "k1".to_string()], slots: vec![0, 1] };
+        fs::write(&rows, serde_json::to_string(&broken).unwrap()).unwrap();
+
+        let loaded: VectorStore = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.len(), 3);
+        assert!(!loaded.contains_key("k0"));
+        assert!(loaded.is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn without_rescore_nothing_extra_is_kept() {
+        let data = vectors(INT8_CALIBRATION_ROWS, 4);
+        let mut store = filled(Precision::Int8, false, &data);
+        assert!(store.originals.is_empty());
+
+        // Turning rescore on keeps what can still be decoded, turning it
+        // off again drops it
+        store.set_precision(Precision::Int8, true);
+        assert_eq!(store.originals.len(), data.len());
+        store.set_precision(Precision::Int8, false);
+        assert!(store.originals.is_empty());
+    }
+}
@@ -0,0 +1,386 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use log::*;
+use serde::{ Deserialize, Serialize };
+
+use crate::custom_types::{ MemoryMetadata, UserMemories };
+use crate::utils::load_environment;
+
+
+// One line of the write-ahead log. Every change to the memories of a
+// user is written here first, and only applied after it is on disk
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum WalEntry {
+    Insert {
+        key: String,
+        vector: Vec<f32>,
+        text: String,
+        metadata: MemoryMetadata,
+        count: usize,
+    },
+    Delete {
+        key: String,
+    },
+    Metadata {
+        key: String,
+        metadata: MemoryMetadata,
+    },
+}
+
+// How often the log is fsync-ed to the disk.
+// Always is the safest, Never leaves it to the operating system,
+// EveryN is somewhere in between and loses at most N entries on a crash
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FsyncPolicy {
+    #[default]
+    Always,
+    EveryN(usize),
+    Never,
+}
+
+impl FsyncPolicy {
+    // Reads WAL_FSYNC from the environment, which can be "always",
+    // "never" or a number of entries between two fsyncs
+    pub fn from_env() -> Self {
+        let value = load_environment("WAL_FSYNC");
+
+        match value.trim().to_lowercase().as_str() {
+            "" | "always" => FsyncPolicy::Always,
+            "never" => FsyncPolicy::Never,
+            other => match other.parse::<usize>() {
+                Ok(0) | Ok(1) => FsyncPolicy::Always,
+                Ok(n) => FsyncPolicy::EveryN(n),
+                Err(_) => {
+                    warn!("Unknown WAL_FSYNC value '{}', falling back to always", other);
+                    FsyncPolicy::Always
+                }
+            }
+        }
+    }
+}
+
+// An append-only log file for one user, next to their snapshot file
+pub struct WriteAheadLog {
+    path: PathBuf,
+    file: File,
+    policy: FsyncPolicy,
+    unsynced: usize,
+    entries: usize,
+}
+
+impl WriteAheadLog {
+    pub fn open(path: &Path, policy: FsyncPolicy) -> std::io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let entries = read_entries(path).len();
+
+        Ok(WriteAheadLog { path: path.to_path_buf(), file, policy, unsynced: 0, entries })
+    }
+
+    // Writes one entry as a single JSON line, and fsyncs it
+    // depending on the policy
+    pub fn append(&mut self, entry: &WalEntry) -> std::io::Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+
+        self.unsynced += 1;
+        self.entries += 1;
+
+        match self.policy {
+            FsyncPolicy::Always => self.sync()?,
+            FsyncPolicy::EveryN(n) if self.unsynced >= n => self.sync()?,
+            _ => ()
+        }
+
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        self.file.sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    // Number of entries written since the last checkpoint
+    pub fn len(&self) -> usize {
+        self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+
+    // Empties the log, this is only safe once a snapshot containing
+    // every entry has been renamed into place
+    pub fn truncate(&mut self) -> std::io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        self.unsynced = 0;
+        self.entries = 0;
+        info!("Truncated write-ahead log {}", self.path.display());
+        Ok(())
+    }
+}
+
+// Reads every complete entry of a log file. A crash can leave a half
+// written last line behind, so reading stops at the first broken line
+pub fn read_entries(path: &Path) -> Vec<WalEntry> {
+    let Ok(file) = File::open(path) else {
+        return vec![];
+    };
+
+    let mut result: Vec<WalEntry> = vec![];
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let Ok(line) = line else { break };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match serde_json::from_str::<WalEntry>(&line) {
+            Ok(entry) => result.push(entry),
+            Err(e) => {
+                warn!("Stopped replaying {} at line {}: {e}", path.display(), i + 1);
+                break;
+            }
+        }
+    }
+
+    result
+}
+
+// Whether an entry can be applied to the memories of a user: inserts
+// and metadata have to belong to that user, and an inserted vector has
+// to have the length of the collection unless it is still empty. Checked
+// before an entry is written, so the log never holds one that would fail
+pub fn entry_fits(user: &UserMemories, entry: &WalEntry) -> bool {
+    match entry {
+        WalEntry::Insert { key, vector, metadata, .. } => {
+            if metadata.user_id != user.user_id {
+                warn!("Memory {} belongs to user '{}', not to user '{}'", key, metadata.user_id, user.user_id);
+                return false;
+            }
+            if !user.memories.is_empty() && vector.len() != user.memories.dim {
+                error!("Vector for '{}' has length {}, but the collection uses {}", key, vector.len(), user.memories.dim);
+                return false;
+            }
+            true
+        }
+        WalEntry::Metadata { key, metadata } => {
+            if metadata.user_id != user.user_id {
+                warn!("Metadata of {} belongs to user '{}', not to user '{}'", key, metadata.user_id, user.user_id);
+                return false;
+            }
+            true
+        }
+        WalEntry::Delete { .. } => true
+    }
+}
+
+// Applies one log entry to the memories of a user. Applying the same
+// entry twice gives the same result, so replaying a log on top of a
+// snapshot that already contains some of it is harmless. Returns false
+// if the entry was not applied (see entry_fits)
+pub fn apply_entry(user: &mut UserMemories, entry: WalEntry) -> bool {
+    if !entry_fits(user, &entry) {
+        return false;
+    }
+
+    match entry {
+        WalEntry::Insert { key, vector, text, metadata, count } => {
+            if !user.memories.insert(&key, &vector) {
+                return false;
+            }
+            if let Some(keywords) = user.keywords_mut() {
+                keywords.add(&key, &text);
+            }
+            if let Some(hashes) = user.text_hashes_mut() {
+                hashes.add(&key, &text);
+            }
+            user.memories_text.insert(key.clone(), text);
+            user.metadata.insert(key, metadata);
+            user.count = user.count.max(count);
+        }
+        WalEntry::Delete { key } => {
+            user.memories.remove(&key);
+            if let Some(keywords) = user.keywords_mut() {
+                keywords.remove(&key);
+            }
+            if let Some(hashes) = user.text_hashes_mut() {
+                hashes.remove(&key);
+            }
+            user.memories_text.remove(&key);
+            user.metadata.remove(&key);
+        }
+        WalEntry::Metadata { key, metadata } => {
+            if !user.memories.contains_key(&key) {
+                return false;
+            }
+            user.metadata.insert(key, metadata);
+        }
+    }
+    true
+}
+
+// Writes a file so that it is either completely there or not changed
+// at all: write to a temporary file, fsync it, rename it over the old
+// one and fsync the directory so the rename itself is durable
+pub fn write_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+
+    {
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(contents)?;
+        tmp.sync_all()?;
+    }
+
+    fs::rename(&tmp_path, path)?;
+
+    if let Some(parent) = path.parent()
+        && let Ok(dir) = File::open(parent) {
+        let _ = dir.sync_all();
+    }
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::custom_types::MemoryStore;
+    use crate::store::user_wal_path;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("wal-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join(name)
+    }
+
+    fn user(user_id: &str) -> UserMemories {
+        UserMemories { user_id: user_id.to_string(), ..Default::default() }
+    }
+
+    fn insert(key: &str, user_id: &str, vector: Vec<f32>) -> WalEntry {
+        WalEntry::Insert {
+            key: key.to_string(),
+            vector,
+            text: format!("text of {}", key),
+            metadata: MemoryMetadata { user_id: user_id.to_string(), ..Default::default() },
+            count: 1,
+        }
+    }
+
+    #[test]
+    fn replay_stops_at_a_torn_last_line() {
+        let path = temp_path("torn.wal");
+        let _ = fs::remove_file(&path);
+        {
+            let mut wal = WriteAheadLog::open(&path, FsyncPolicy::Always).unwrap();
+            wal.append(&insert("a", "u", vec![1.0, 0.0])).unwrap();
+            wal.append(&insert("b", "u", vec![0.0, 1.0])).unwrap();
+        }
+
+        // A crash in the middle of the third write
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"op":"insert","key":"c","vec"#).unwrap();
+        drop(file);
+
+        let entries = read_entries(&path);
+        assert_eq!(entries.len(), 2);
+
+        let mut memories = user("u");
+        for entry in entries {
+            assert!(apply_entry(&mut memories, entry));
+        }
+        assert_eq!(memories.memories.len(), 2);
+        assert!(memories.memories.contains_key("b"));
+        assert!(!memories.memories.contains_key("c"));
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn a_missing_log_has_no_entries() {
+        assert!(read_entries(&temp_path("missing.wal")).is_empty());
+    }
+
+    #[test]
+    fn entries_that_do_not_fit_are_not_applied() {
+        let mut memories = user("u");
+        assert!(apply_entry(&mut memories, insert("a", "u", vec![1.0, 0.0])));
+
+        // Another user, and another vector length
+        let foreign = insert("b", "someone else", vec![0.0, 1.0]);
+        let too_long = insert("c", "u", vec![0.0, 1.0, 0.0]);
+        assert!(!entry_fits(&memories, &foreign));
+        assert!(!entry_fits(&memories, &too_long));
+        assert!(!apply_entry(&mut memories, foreign));
+        assert!(!apply_entry(&mut memories, too_long));
+
+        let foreign_metadata = WalEntry::Metadata {
+            key: "a".to_string(),
+            metadata: MemoryMetadata { user_id: "someone else".to_string(), session_id: "s".to_string(), ..Default::default() },
+        };
+        assert!(!apply_entry(&mut memories, foreign_metadata));
+
+        assert_eq!(memories.memories.len(), 1);
+        assert!(memories.memories_text.get("b").is_none());
+        assert!(memories.memories_text.get("c").is_none());
+        assert_eq!(memories.metadata.get("a").map(|m| m.user_id.as_str()), Some("u"));
+    }
+
+    #[test]
+    fn applying_an_entry_twice_changes_nothing() {
+        let mut memories = user("u");
+        assert!(apply_entry(&mut memories, insert("a", "u", vec![1.0, 0.0])));
+        assert!(apply_entry(&mut memories, insert("a", "u", vec![1.0, 0.0])));
+        assert_eq!(memories.memories.len(), 1);
+
+        assert!(apply_entry(&mut memories, WalEntry::Delete { key: "a".to_string() }));
+        assert!(apply_entry(&mut memories, WalEntry::Delete { key: "a".to_string() }));
+        assert!(memories.memories.is_empty());
+    }
+
+    #[test]
+    fn checkpoint_empties_the_log() {
+        let user_id = format!("wal-checkpoint-test-{}", std::process::id());
+        let wal_path = user_wal_path(&user_id);
+
+        let mut store = MemoryStore::new();
+        let metadata = MemoryMetadata { user_id: user_id.clone(), ..Default::default() };
+        store.insert_memory(&user_id, "first", vec![1.0, 0.0], metadata.clone()).unwrap();
+        store.insert_memory(&user_id, "second", vec![0.0, 1.0], metadata).unwrap();
+        assert_eq!(read_entries(&wal_path).len(), 2);
+
+        store.checkpoint(&user_id).unwrap();
+        assert!(read_entries(&wal_path).is_empty());
+        assert!(store.wals[&user_id].is_empty());
+
+        // Everything is in the snapshot now
+        let mut reloaded = MemoryStore::new();
+        assert_eq!(reloaded.user_mut(&user_id).memories.len(), 2);
+
+        drop(reloaded);
+        drop(store);
+        crate::store::remove_user_files(&user_id);
+    }
+
+    #[test]
+    fn write_atomic_replaces_the_whole_file() {
+        let path = temp_path("atomic.json");
+        write_atomic(&path, b"old contents that are longer").unwrap();
+        write_atomic(&path, b"new").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert!(!path.with_extension("tmp").exists());
+        let _ = fs::remove_file(&path);
+    }
+}